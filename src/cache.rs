@@ -0,0 +1,60 @@
+use lru::LruCache;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+pub const CACHE_SHARDS: usize = 16;
+
+/// LRU cache split by key hash into shards, each behind its own lock, so
+/// readers of different keys do not wait on each other.
+pub struct ShardedCache<K: Hash + Eq, V: Clone> {
+    shards: Vec<Mutex<LruCache<K, V>>>,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedCache<K, V> {
+    /// Holds about `capacity` entries in all, at least one per shard.
+    pub fn new(capacity: usize) -> ShardedCache<K, V> {
+        let per_shard =
+            NonZeroUsize::new(capacity.div_ceil(CACHE_SHARDS)).unwrap_or(NonZeroUsize::MIN);
+        ShardedCache {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(LruCache::new(per_shard)))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LruCache<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % CACHE_SHARDS]
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().unwrap().get(key).cloned()
+    }
+
+    pub fn put(&self, key: K, value: V) {
+        self.shard(&key).lock().unwrap().put(key, value);
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.shard(key).lock().unwrap().contains(key)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().unwrap().pop(key)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,605 @@
+use crate::compaction::{run_compactions, CompactionScheduler};
+use crate::error::{Error, Result};
+use crate::internal_key::{Memtable, MAX_SEQ};
+use crate::log::*;
+use crate::lsm_forest::{
+    close_memtables, fill, insert_entries, make_immutable, max_seq, memtable_usage, range_at,
+    read_at, retire_segment, spawn_flush_worker, FlushState, LogSerial, WalSegment, WalWriter,
+};
+use crate::manifest::{lock_dir, sync_dir};
+use crate::memtable::{BTreeMemTable, MemTable};
+use crate::options::{Options, WriteOptions};
+use crate::snapshot::SnapshotList;
+use crate::table_manager::simple_table_manager::SimpleTableManager;
+use crate::table_manager::TableManager;
+use crate::write_batch::WriteBatch;
+use bincode::{Decode, Encode};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, MutexGuard, RwLock};
+use std::thread::JoinHandle;
+
+// the column families of a directory are listed in its FAMILIES file, a
+// bincode `FamilyList` replaced whole through a rename whenever one is
+// created or dropped. each family keeps its tables in a directory named
+// after its id, and ids are never reused, so entries the shared wal still
+// holds for a dropped family are skipped on recovery.
+//
+// a memtable that fills up starts a new wal segment, and the memtables of
+// every other family are made immutable with it, so each segment is retired
+// once the memtables logged in it are flushed.
+
+/// Id of the column family every `DB` has, which `WriteBatch::put` writes to.
+pub const DEFAULT_FAMILY: u32 = 0;
+pub const DEFAULT_FAMILY_NAME: &str = "default";
+pub const FAMILIES_NAME: &str = "FAMILIES";
+
+#[derive(Encode, Decode, Debug, Clone)]
+struct FamilyList {
+    // (name, id)
+    families: Vec<(String, u32)>,
+    next_id: u32,
+}
+
+/// One tree of a `DB` with its own options, memtable and table manager.
+/// It is read through directly and written through the `DB`.
+pub struct ColumnFamily<
+    K: LogSerial,
+    V: LogSerial,
+    TM: TableManager<K, V> = SimpleTableManager<K, V>,
+    MT: MemTable<K, V> = BTreeMemTable<K, V>,
+> {
+    pub(crate) name: String,
+    pub(crate) id: u32,
+    pub(crate) path: PathBuf,
+    pub(crate) options: Options,
+    // the db inserts with it locked for reading
+    pub(crate) memtable: RwLock<MT>,
+    pub(crate) table_manager: Arc<RwLock<TM>>,
+    // sequence number of the last write of the db, shared by its families
+    last_seq: Arc<AtomicU64>,
+    snapshots: Arc<SnapshotList>,
+    flush: Arc<FlushState<MT>>,
+    flush_thread: Option<JoinHandle<()>>,
+    compactions: Arc<CompactionScheduler>,
+    // dropped from the db, its directory is deleted along with it
+    dropped: AtomicBool,
+    phantom: std::marker::PhantomData<(K, V)>,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>>
+    ColumnFamily<K, V, TM, MT>
+{
+    /// Opens the tables of family `id` of the db in `dir`, with nothing in
+    /// its memtable yet.
+    fn open(
+        dir: &Path,
+        name: String,
+        id: u32,
+        options: Options,
+        last_seq: &Arc<AtomicU64>,
+        snapshots: &Arc<SnapshotList>,
+    ) -> Result<ColumnFamily<K, V, TM, MT>> {
+        let path = family_path(dir, id);
+        fs::create_dir_all(&path)?;
+        let table_manager = Arc::new(RwLock::new(TM::new(&path, &options)?));
+        let flush = Arc::new(FlushState::new(VecDeque::new()));
+        let compactions = Arc::new(CompactionScheduler::new(
+            Arc::clone(&table_manager),
+            Arc::clone(snapshots),
+            options.compaction_threads,
+        ));
+        let flush_thread =
+            spawn_flush_worker(&flush, &table_manager, snapshots, &compactions, &options);
+
+        Ok(ColumnFamily {
+            name,
+            id,
+            path,
+            options,
+            memtable: RwLock::new(MT::new()),
+            table_manager,
+            last_seq: Arc::clone(last_seq),
+            snapshots: Arc::clone(snapshots),
+            flush,
+            flush_thread: Some(flush_thread),
+            compactions,
+            dropped: AtomicBool::new(false),
+            phantom: std::marker::PhantomData,
+        })
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let visible_seq = self.last_seq.load(Ordering::SeqCst);
+        read_at(
+            &self.memtable,
+            &self.flush,
+            &self.table_manager,
+            key,
+            MAX_SEQ,
+            visible_seq,
+        )
+    }
+
+    /// Ordered iterator over the live entries with keys in `range`
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        let visible_seq = self.last_seq.load(Ordering::SeqCst);
+        range_at(
+            &self.memtable,
+            &self.flush,
+            &self.table_manager,
+            range,
+            visible_seq,
+        )
+    }
+
+    /// Ordered iterator over every live entry
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        self.range(..)
+    }
+
+    /// Encoded size of the memtable and the immutable memtables not flushed yet.
+    pub fn memtable_usage(&self) -> usize {
+        memtable_usage(&self.memtable, &self.flush)
+    }
+
+    /// Blocks until every immutable memtable is in an sstable.
+    pub fn wait_for_flush(&self) -> Result<()> {
+        self.flush.wait_for_flush()
+    }
+
+    /// Runs every compaction that is due and waits for those in the background.
+    pub fn compact(&self) -> Result<()> {
+        self.compactions.wait_idle()?;
+        run_compactions::<K, V, TM>(&self.table_manager, &self.snapshots, || {})?;
+        self.compactions.wait_idle()
+    }
+
+    /// The recovered entries of this family not in its tables yet, if any.
+    fn unflushed(&self, families: &mut BTreeMap<u32, Memtable<K, V>>) -> Option<MT> {
+        let flushed = self.table_manager.read().unwrap().last_sequence();
+        let entries: Memtable<K, V> = families
+            .remove(&self.id)?
+            .into_iter()
+            .filter(|(key, _)| key.seq > flushed)
+            .collect();
+        if entries.is_empty() {
+            return None;
+        }
+
+        let memtable = fill::<K, V, MT>(entries);
+        if let Some(manager) = &self.options.write_buffer_manager {
+            manager.reserve(memtable.size());
+        }
+        Some(memtable)
+    }
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> Drop
+    for ColumnFamily<K, V, TM, MT>
+{
+    fn drop(&mut self) {
+        close_memtables(
+            &mut self.memtable,
+            &self.flush,
+            self.flush_thread.take(),
+            &self.options,
+        );
+        if *self.dropped.get_mut() {
+            // nothing flushes into its tables any more
+            let _ = self.compactions.wait_idle();
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+struct Families<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> {
+    by_name: BTreeMap<String, Arc<ColumnFamily<K, V, TM, MT>>>,
+    next_id: u32,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>>
+    Families<K, V, TM, MT>
+{
+    fn by_id(&self, id: u32) -> Option<&Arc<ColumnFamily<K, V, TM, MT>>> {
+        self.by_name.values().find(|family| family.id == id)
+    }
+
+    fn list(&self) -> FamilyList {
+        FamilyList {
+            families: self
+                .by_name
+                .values()
+                .map(|family| (family.name.clone(), family.id))
+                .collect(),
+            next_id: self.next_id,
+        }
+    }
+}
+
+/// Column families in one directory, each an LSM Tree with its own options,
+/// memtable and table manager, sharing one WAL so a `WriteBatch` can update
+/// several of them atomically.
+///
+/// The WAL follows the sync and recovery modes of the db's options, which
+/// are also what families are opened with unless given their own.
+pub struct DB<
+    K: LogSerial,
+    V: LogSerial,
+    TM: TableManager<K, V> = SimpleTableManager<K, V>,
+    MT: MemTable<K, V> = BTreeMemTable<K, V>,
+> {
+    pub(crate) path: PathBuf,
+    wal: WalWriter<(u32, K, Option<V>)>,
+    // sequence number of the last write, shared with the wal and the families
+    last_seq: Arc<AtomicU64>,
+    snapshots: Arc<SnapshotList>,
+    families: RwLock<Families<K, V, TM, MT>>,
+    recovery_report: RecoveryReport,
+    // held while the db is open
+    _lock: File,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> DB<K, V, TM, MT> {
+    /// Opens the db in `p`, creating it with only the default column family
+    /// if needed. Every family is opened with `options`.
+    pub fn open<P: AsRef<Path>>(p: P, options: Options) -> Result<Arc<DB<K, V, TM, MT>>> {
+        Self::open_with_families(p, options, HashMap::new())
+    }
+
+    /// `open` with options of their own for the families named in
+    /// `family_options`.
+    pub fn open_with_families<P: AsRef<Path>>(
+        p: P,
+        options: Options,
+        family_options: HashMap<String, Options>,
+    ) -> Result<Arc<DB<K, V, TM, MT>>> {
+        let p = p.as_ref();
+        options.validate()?;
+        for options in family_options.values() {
+            options.validate()?;
+        }
+        fs::create_dir_all(p)?;
+        let lock = lock_dir(p)?;
+
+        let list = match read_families(p)? {
+            Some(list) => list,
+            None => {
+                let list = FamilyList {
+                    families: vec![(DEFAULT_FAMILY_NAME.to_string(), DEFAULT_FAMILY)],
+                    next_id: DEFAULT_FAMILY + 1,
+                };
+                write_families(p, &list)?;
+                list
+            }
+        };
+        remove_dropped_families(p, &list)?;
+
+        let last_seq = Arc::new(AtomicU64::new(0));
+        let snapshots = Arc::new(SnapshotList::new());
+        let mut by_name = BTreeMap::new();
+        for (name, id) in list.families {
+            let options = family_options.get(&name).unwrap_or(&options).clone();
+            let family = ColumnFamily::<K, V, TM, MT>::open(
+                p,
+                name.clone(),
+                id,
+                options,
+                &last_seq,
+                &snapshots,
+            )?;
+            by_name.insert(name, Arc::new(family));
+        }
+        let mut seq = by_name
+            .values()
+            .map(|family| family.table_manager.read().unwrap().last_sequence())
+            .max()
+            .unwrap_or(0);
+
+        let recovered: RecoveredWal<BTreeMap<u32, Memtable<K, V>>> =
+            recover_wal(p, |log| log.recovery_families_with(options.recovery_mode))?;
+        for (path, mut entries) in recovered.full {
+            seq = seq.max(entries.values().map(max_seq).max().unwrap_or(0));
+            let memtables: Vec<_> = by_name
+                .values()
+                .filter_map(|family| Some((family, family.unflushed(&mut entries)?)))
+                .collect();
+            if memtables.is_empty() {
+                retire_segment(&path, None)?;
+                continue;
+            }
+            let segment = Arc::new(WalSegment::new(path, memtables.len()));
+            for (family, memtable) in memtables {
+                make_immutable(&family.flush, memtable, &segment, &family.options);
+            }
+        }
+
+        let mut entries = recovered.current;
+        seq = seq.max(entries.values().map(max_seq).max().unwrap_or(0));
+        for family in by_name.values() {
+            if let Some(memtable) = family.unflushed(&mut entries) {
+                *family.memtable.write().unwrap() = memtable;
+            }
+        }
+        last_seq.store(seq, Ordering::SeqCst);
+        let wal = WalWriter::new(
+            p.to_path_buf(),
+            recovered.log,
+            recovered.number,
+            Arc::clone(&last_seq),
+            options.sync_mode,
+        );
+
+        Ok(Arc::new(DB {
+            path: p.to_path_buf(),
+            wal,
+            last_seq,
+            snapshots,
+            families: RwLock::new(Families {
+                by_name,
+                next_id: list.next_id,
+            }),
+            recovery_report: recovered.report,
+            _lock: lock,
+        }))
+    }
+
+    /// WAL corruption that `options.recovery_mode` got past when the db was
+    /// opened, empty if none.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<Arc<ColumnFamily<K, V, TM, MT>>> {
+        self.families.read().unwrap().by_name.get(name).cloned()
+    }
+
+    pub fn default_family(&self) -> Arc<ColumnFamily<K, V, TM, MT>> {
+        self.column_family(DEFAULT_FAMILY_NAME).unwrap()
+    }
+
+    /// Names of the column families, in order.
+    pub fn column_families(&self) -> Vec<String> {
+        self.families
+            .read()
+            .unwrap()
+            .by_name
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds an empty column family called `name`, tuned by `options`.
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: Options,
+    ) -> Result<Arc<ColumnFamily<K, V, TM, MT>>> {
+        options.validate()?;
+        let mut families = self.families.write().unwrap();
+        if families.by_name.contains_key(name) {
+            return Err(Error::InvalidArgument(format!(
+                "column family {} already exists",
+                name
+            )));
+        }
+
+        let id = families.next_id;
+        let family = ColumnFamily::open(
+            &self.path,
+            name.to_string(),
+            id,
+            options,
+            &self.last_seq,
+            &self.snapshots,
+        )?;
+        let mut list = families.list();
+        list.families.push((name.to_string(), id));
+        list.next_id = id + 1;
+        if let Err(e) = write_families(&self.path, &list) {
+            family.dropped.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        let family = Arc::new(family);
+        families.next_id = id + 1;
+        families
+            .by_name
+            .insert(name.to_string(), Arc::clone(&family));
+        Ok(family)
+    }
+
+    /// Removes the column family called `name` with everything in it. Its
+    /// directory is deleted once the last handle to it is dropped.
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_FAMILY_NAME {
+            return Err(Error::InvalidArgument(
+                "the default column family cannot be dropped".to_string(),
+            ));
+        }
+        let mut families = self.families.write().unwrap();
+        let Some(family) = families.by_name.get(name).cloned() else {
+            return Err(Error::InvalidArgument(format!("no column family {}", name)));
+        };
+
+        let mut list = families.list();
+        list.families.retain(|(other, _)| other != name);
+        write_families(&self.path, &list)?;
+        families.by_name.remove(name);
+        family.dropped.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn put(&self, family: &ColumnFamily<K, V, TM, MT>, key: K, value: V) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf(family, key, value);
+        self.write(batch)
+    }
+
+    pub fn remove(&self, family: &ColumnFamily<K, V, TM, MT>, key: &K) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.remove_cf(family, key);
+        self.write(batch)
+    }
+
+    /// Applies every put and remove in `batch`, to whichever column families
+    /// they name, or none of them if the process crashes partway.
+    pub fn write(&self, batch: WriteBatch<K, V>) -> Result<()> {
+        self.write_with(batch, &WriteOptions::default())
+    }
+
+    pub fn write_with(&self, batch: WriteBatch<K, V>, options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut ids: Vec<u32> = batch.entries.iter().map(|(family, _, _)| *family).collect();
+        ids.sort();
+        ids.dedup();
+        let touched = {
+            let families = self.families.read().unwrap();
+            ids.into_iter()
+                .map(|id| {
+                    families.by_id(id).cloned().ok_or_else(|| {
+                        Error::InvalidArgument(format!("no column family with id {}", id))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        self.wal.write(
+            batch.entries,
+            options.sync,
+            |seq, entries| LogRecord::Families(LogFamilyBatch::new(seq, entries)),
+            |wal, records| self.insert(wal, records),
+            |wal_lock| self.rotate_memtables(wal_lock, &self.families.read().unwrap()),
+        )?;
+
+        // backpressure
+        for family in touched {
+            family.flush.wait_for_room(family.options.max_immutables)?;
+            family.compactions.check()?;
+        }
+        Ok(())
+    }
+
+    /// Inserts the records of a group into the memtables of their families,
+    /// telling whether one of them should be flushed.
+    fn insert(&self, wal: &Log, records: Vec<LogRecord<K, V>>) -> bool {
+        let mut entries: BTreeMap<u32, Memtable<K, V>> = BTreeMap::new();
+        for (family, key, value) in records
+            .into_iter()
+            .flat_map(|record| record.into_family_entries())
+        {
+            entries.entry(family).or_default().insert(key, value);
+        }
+
+        // families are not created or dropped while the group is inserted,
+        // and entries of one dropped since they were queued are skipped
+        let families = self.families.read().unwrap();
+        let mut full = false;
+        for (id, entries) in entries {
+            if let Some(family) = families.by_id(id) {
+                full |=
+                    insert_entries::<K, V, TM, MT>(&family.memtable, entries, &family.options, wal);
+            }
+        }
+        full
+    }
+
+    /// Hands the memtable of every family with writes to its flush thread,
+    /// and starts a new wal segment.
+    fn rotate_memtables(
+        &self,
+        wal_lock: &mut MutexGuard<Log>,
+        families: &Families<K, V, TM, MT>,
+    ) -> Result<()> {
+        // memtables are only written to with the wal locked, so none of them
+        // fills up meanwhile
+        let written: Vec<&Arc<ColumnFamily<K, V, TM, MT>>> = families
+            .by_name
+            .values()
+            .filter(|family| !family.memtable.read().unwrap().is_empty())
+            .collect();
+        if written.is_empty() {
+            return Ok(());
+        }
+
+        let segment = self.wal.rotate(wal_lock, written.len())?;
+        for family in written {
+            let memtable = std::mem::replace(&mut *family.memtable.write().unwrap(), MT::new());
+            make_immutable(&family.flush, memtable, &segment, &family.options);
+        }
+        Ok(())
+    }
+
+    /// Writes the memtables of every column family to sstables.
+    pub fn flush_memtables(&self) -> Result<()> {
+        let families: Vec<_> = {
+            let mut wal_lock = self.wal.lock();
+            let families = self.families.read().unwrap();
+            self.rotate_memtables(&mut wal_lock, &families)?;
+            families.by_name.values().cloned().collect()
+        };
+
+        for family in families {
+            family.wait_for_flush()?;
+        }
+        Ok(())
+    }
+
+    /// Forces every write so far to disk.
+    pub fn sync_wal(&self) -> Result<()> {
+        self.wal.sync()
+    }
+}
+
+/// Directory of column family `id` in the db in `dir`.
+pub fn family_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("cf_{:04}", id))
+}
+
+fn read_families(dir: &Path) -> Result<Option<FamilyList>> {
+    let path = dir.join(FAMILIES_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let buf = fs::read(path)?;
+    let (list, _) = bincode::decode_from_slice(&buf, bincode::config::standard())?;
+    Ok(Some(list))
+}
+
+fn write_families(dir: &Path, list: &FamilyList) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", FAMILIES_NAME));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bincode::encode_to_vec(list, bincode::config::standard())?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(FAMILIES_NAME))?;
+    sync_dir(dir)
+}
+
+/// Deletes the directories of families dropped before a crash, or of ones
+/// whose creation never made it into the list.
+fn remove_dropped_families(dir: &Path, list: &FamilyList) -> Result<()> {
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let id = name
+            .strip_prefix("cf_")
+            .and_then(|id| id.parse::<u32>().ok());
+        if let Some(id) = id {
+            if !list.families.iter().any(|(_, other)| *other == id) {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
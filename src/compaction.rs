@@ -0,0 +1,382 @@
+use crate::error::{Error, Result};
+use crate::internal_key::InternalKey;
+use crate::lsm_forest::LogSerial;
+use crate::manifest::{Manifest, VersionEdit};
+use crate::merge_iterator::{EntryIterator, MergeIterator};
+use crate::options::Options;
+use crate::snapshot::SnapshotList;
+use crate::sstable::{Table, TableBuilder, TableMeta};
+use crate::table_manager::TableManager;
+use std::fs;
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+/// What a compaction read, wrote and threw away.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub input_entries: u64,
+    pub output_entries: u64,
+    // versions hidden by a newer entry for the same key from every snapshot
+    pub dropped_versions: u64,
+    // tombstones with nothing left below them to shadow
+    pub dropped_tombstones: u64,
+}
+
+impl CompactionStats {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.input_bytes.saturating_sub(self.output_bytes)
+    }
+
+    pub fn add(&mut self, other: &CompactionStats) {
+        self.input_bytes += other.input_bytes;
+        self.output_bytes += other.output_bytes;
+        self.input_entries += other.input_entries;
+        self.output_entries += other.output_entries;
+        self.dropped_versions += other.dropped_versions;
+        self.dropped_tombstones += other.dropped_tombstones;
+    }
+}
+
+/// One compaction picked by a table manager. Its inputs stay live, and are
+/// not picked again, until the table manager finishes it.
+#[derive(Debug, Clone)]
+pub struct CompactionJob<K: LogSerial> {
+    // newest first
+    pub inputs: Vec<PathBuf>,
+    // level the inputs are taken from, as the table manager numbers them
+    pub level: usize,
+    // manifest level the outputs are written to
+    pub output_level: u32,
+    pub bottommost: bool,
+    pub target_file_size: u64,
+    // largest key taken from `level`
+    pub last_key: Option<InternalKey<K>>,
+}
+
+/// Merges `inputs`, ordered newest first, into new tables at paths handed
+/// out by `new_table_path`.
+///
+/// Inputs are read one block at a time and a new output table is started
+/// once the current one reaches `target_file_size` bytes, so memory use does
+/// not depend on how much data is compacted. Every version of a key goes to
+/// the same output table.
+///
+/// A version is dropped once a newer one is visible to every snapshot, that
+/// is, the newer one is at or before `smallest_snapshot`. When the output is
+/// `bottommost`, no older data for these keys exists anywhere else and
+/// tombstones every snapshot sees are dropped too.
+///
+/// An input that cannot be read fails the compaction. The outputs written
+/// so far are deleted and the inputs are left as they are.
+pub fn compact_tables<K: LogSerial, V: LogSerial>(
+    inputs: &[PathBuf],
+    target_file_size: u64,
+    bottommost: bool,
+    smallest_snapshot: u64,
+    options: &Options,
+    mut new_table_path: impl FnMut() -> PathBuf,
+) -> Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)> {
+    let mut stats = CompactionStats::default();
+    let mut sources: Vec<EntryIterator<InternalKey<K>, V>> = Vec::new();
+    for path in inputs.iter() {
+        let table = Table::<InternalKey<K>, V>::open(path)?;
+        stats.input_bytes += fs::metadata(path)?.len();
+        stats.input_entries += table.footer.entry_count;
+        sources.push(Box::new(table.iter(Bound::Unbounded, Bound::Unbounded)));
+    }
+
+    let mut merged = 0;
+    let mut hidden = 0;
+    let mut current_key: Option<K> = None;
+    // sequence number of the previous version of the current key
+    let mut last_seq: Option<u64> = None;
+    let mut entries = MergeIterator::new(sources)
+        .inspect(|_| merged += 1)
+        .filter(|entry| {
+            let Ok((key, value)) = entry else {
+                return true;
+            };
+            if current_key.as_ref() != Some(&key.key) {
+                current_key = Some(key.key.clone());
+                last_seq = None;
+            }
+
+            let dropped = if last_seq.is_some_and(|seq| seq <= smallest_snapshot) {
+                hidden += 1;
+                true
+            } else if bottommost && value.is_none() && key.seq <= smallest_snapshot {
+                stats.dropped_tombstones += 1;
+                true
+            } else {
+                false
+            };
+            last_seq = Some(key.seq);
+            !dropped
+        })
+        .peekable();
+
+    let mut started = Vec::new();
+    let outputs = write_outputs(
+        &mut entries,
+        target_file_size,
+        options,
+        &mut new_table_path,
+        &mut started,
+    );
+    drop(entries);
+    let outputs = match outputs {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            // not in the manifest yet, so nothing reads them
+            for path in started {
+                let _ = fs::remove_file(path);
+            }
+            return Err(e);
+        }
+    };
+
+    stats.output_bytes = outputs.iter().map(|output| output.size).sum();
+    stats.output_entries = merged - hidden - stats.dropped_tombstones;
+    stats.dropped_versions = stats.input_entries - merged + hidden;
+
+    Ok((outputs, stats))
+}
+
+/// Writes `entries` to tables of about `target_file_size` bytes, adding the
+/// path of each table to `started` before writing it.
+fn write_outputs<K: LogSerial, V: LogSerial>(
+    entries: &mut Peekable<impl Iterator<Item = Result<(InternalKey<K>, Option<V>)>>>,
+    target_file_size: u64,
+    options: &Options,
+    new_table_path: &mut impl FnMut() -> PathBuf,
+    started: &mut Vec<PathBuf>,
+) -> Result<Vec<TableMeta<InternalKey<K>>>> {
+    let mut outputs = Vec::new();
+    while entries.peek().is_some() {
+        let path = new_table_path();
+        started.push(path.clone());
+        let mut builder = TableBuilder::<InternalKey<K>, V>::new(&path, options)?;
+        let mut last_key: Option<K> = None;
+        while let Some(entry) = entries.peek() {
+            // only start a new table between two user keys
+            if let Ok((key, _)) = entry {
+                if builder.size() >= target_file_size && last_key.as_ref() != Some(&key.key) {
+                    break;
+                }
+            }
+            let (key, value) = entries.next().unwrap()?;
+            last_key = Some(key.key.clone());
+            builder.add(key, value)?;
+        }
+        builder.finish()?;
+        outputs.extend(TableMeta::load::<V>(&path)?);
+    }
+
+    Ok(outputs)
+}
+
+/// Swaps `inputs` for `outputs` in the manifest, then deletes the inputs.
+pub fn install_compaction<K: LogSerial>(
+    manifest: &mut Manifest,
+    level: u32,
+    outputs: &[TableMeta<K>],
+    inputs: &[PathBuf],
+) -> Result<()> {
+    let mut edit = VersionEdit::default();
+    for output in outputs.iter() {
+        edit.add(level, &output.path);
+    }
+    for input in inputs.iter() {
+        edit.remove(input);
+    }
+    manifest.apply(edit)?;
+
+    // inputs are only deleted once the manifest no longer lists them
+    for input in inputs.iter() {
+        fs::remove_file(input)?;
+    }
+
+    Ok(())
+}
+
+/// Runs every compaction `tm` picks until there is none left, keeping every
+/// version a snapshot at or after `smallest_snapshot` needs.
+pub fn compact_all<K: LogSerial, V: LogSerial, TM: TableManager<K, V>>(
+    tm: &mut TM,
+    smallest_snapshot: u64,
+) -> Result<()> {
+    let options = tm.options().clone();
+    while let Some(job) = tm.pick_compaction()? {
+        let result = compact_tables::<K, V>(
+            &job.inputs,
+            job.target_file_size,
+            job.bottommost,
+            smallest_snapshot,
+            &options,
+            || tm.new_table_path(job.output_level),
+        );
+        tm.finish_compaction(job, result)?;
+    }
+
+    Ok(())
+}
+
+/// Runs every compaction `tm` picks until there is none left, calling
+/// `installed` after each. `tm` is only locked to pick a job, to hand out
+/// output paths and to install the result, not while tables are merged.
+pub fn run_compactions<K: LogSerial, V: LogSerial, TM: TableManager<K, V>>(
+    tm: &RwLock<TM>,
+    snapshots: &SnapshotList,
+    mut installed: impl FnMut(),
+) -> Result<()> {
+    let options = tm.read().unwrap().options().clone();
+    loop {
+        let job = match tm.write().unwrap().pick_compaction()? {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+
+        // snapshots taken later are newer than every input, so they need
+        // no version this drops
+        let output = compact_tables::<K, V>(
+            &job.inputs,
+            job.target_file_size,
+            job.bottommost,
+            snapshots.smallest(),
+            &options,
+            || tm.write().unwrap().new_table_path(job.output_level),
+        );
+        tm.write().unwrap().finish_compaction(job, output)?;
+        installed();
+    }
+}
+
+struct SchedulerQueue {
+    // bumped whenever the table set changes, so there may be new work
+    generation: u64,
+    // workers between waking up and finding nothing left to pick
+    running: usize,
+    error: Option<Error>,
+    shutdown: bool,
+}
+
+struct SchedulerState {
+    queue: Mutex<SchedulerQueue>,
+    changed: Condvar,
+}
+
+/// Pool of threads that run the compactions a table manager picks.
+///
+/// The table manager is only locked to pick a job, to hand out output paths
+/// and to install the result, never while tables are merged, so flushes and
+/// reads go on against the old table set until the swap.
+pub struct CompactionScheduler {
+    state: Arc<SchedulerState>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl CompactionScheduler {
+    pub fn new<K: LogSerial, V: LogSerial, TM: TableManager<K, V>>(
+        tm: Arc<RwLock<TM>>,
+        snapshots: Arc<SnapshotList>,
+        threads: usize,
+    ) -> CompactionScheduler {
+        let state = Arc::new(SchedulerState {
+            queue: Mutex::new(SchedulerQueue {
+                // workers start behind so they check for work left from before
+                generation: 1,
+                running: 0,
+                error: None,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let workers = (0..threads)
+            .map(|_| {
+                let state = Arc::clone(&state);
+                let tm = Arc::clone(&tm);
+                let snapshots = Arc::clone(&snapshots);
+                thread::spawn(move || compaction_worker::<K, V, TM>(state, tm, snapshots))
+            })
+            .collect();
+
+        CompactionScheduler { state, workers }
+    }
+
+    /// Wakes the workers after the table set changed.
+    pub fn notify(&self) {
+        self.state.queue.lock().unwrap().generation += 1;
+        self.state.changed.notify_all();
+    }
+
+    /// Blocks until no worker is running a compaction.
+    pub fn wait_idle(&self) -> Result<()> {
+        let mut queue = self.state.queue.lock().unwrap();
+        while queue.running > 0 && queue.error.is_none() {
+            queue = self.state.changed.wait(queue).unwrap();
+        }
+        scheduler_result(&queue)
+    }
+
+    /// Error that stopped a worker, if any.
+    pub fn check(&self) -> Result<()> {
+        scheduler_result(&self.state.queue.lock().unwrap())
+    }
+}
+
+impl Drop for CompactionScheduler {
+    fn drop(&mut self) {
+        self.state.queue.lock().unwrap().shutdown = true;
+        self.state.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn scheduler_result(queue: &SchedulerQueue) -> Result<()> {
+    match &queue.error {
+        Some(e) => Err(e.with_context("background compaction failed")),
+        None => Ok(()),
+    }
+}
+
+fn compaction_worker<K: LogSerial, V: LogSerial, TM: TableManager<K, V>>(
+    state: Arc<SchedulerState>,
+    tm: Arc<RwLock<TM>>,
+    snapshots: Arc<SnapshotList>,
+) {
+    let mut seen = 0;
+    loop {
+        {
+            let mut queue = state.queue.lock().unwrap();
+            while queue.generation == seen && !queue.shutdown {
+                queue = state.changed.wait(queue).unwrap();
+            }
+            if queue.shutdown || queue.error.is_some() {
+                return;
+            }
+            seen = queue.generation;
+            queue.running += 1;
+        }
+
+        let result = run_compactions::<K, V, TM>(&tm, &snapshots, || {
+            // the new tables may give idle workers something to do
+            state.queue.lock().unwrap().generation += 1;
+            state.changed.notify_all();
+        });
+
+        let mut queue = state.queue.lock().unwrap();
+        queue.running -= 1;
+        if let Err(e) = result {
+            queue.error = Some(e);
+        }
+        state.changed.notify_all();
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong in an LSM Tree.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // data on disk failed a checksum or could not be decoded
+    Corruption(String),
+    // options or arguments out of range
+    InvalidArgument(String),
+    // held by someone else, such as a directory another tree has open
+    Busy(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Same kind of error with `context` in front, for reporting one failure
+    /// to everyone who waited on it.
+    pub fn with_context(&self, context: &str) -> Error {
+        match self {
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), format!("{}: {}", context, e))),
+            Error::Corruption(message) => Error::Corruption(format!("{}: {}", context, message)),
+            Error::InvalidArgument(message) => {
+                Error::InvalidArgument(format!("{}: {}", context, message))
+            }
+            Error::Busy(message) => Error::Busy(format!("{}: {}", context, message)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Corruption(message) => write!(f, "corruption: {}", message),
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Error::Busy(message) => write!(f, "busy: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::error::DecodeError> for Error {
+    fn from(e: bincode::error::DecodeError) -> Error {
+        match e {
+            bincode::error::DecodeError::Io { inner, .. } => Error::Io(inner),
+            e => Error::Corruption(e.to_string()),
+        }
+    }
+}
+
+impl From<bincode::error::EncodeError> for Error {
+    fn from(e: bincode::error::EncodeError) -> Error {
+        match e {
+            bincode::error::EncodeError::Io { inner, .. } => Error::Io(inner),
+            e => Error::InvalidArgument(e.to_string()),
+        }
+    }
+}
+
+// fixed size fields read from a buffer that is too short
+impl From<std::array::TryFromSliceError> for Error {
+    fn from(e: std::array::TryFromSliceError) -> Error {
+        Error::Corruption(e.to_string())
+    }
+}
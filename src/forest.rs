@@ -0,0 +1,673 @@
+use crate::error::{Error, Result};
+use crate::lsm_forest::{LSMTree, LogSerial};
+use crate::manifest::{lock_dir, sync_dir};
+use crate::memtable::{BTreeMemTable, MemTable};
+use crate::merge_iterator::{EntryIterator, MergeIterator};
+use crate::options::Options;
+use crate::table_manager::simple_table_manager::SimpleTableManager;
+use crate::table_manager::TableManager;
+use crate::write_batch::WriteBatch;
+use bincode::{Decode, Encode};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+// the shards of a forest are listed in its SHARDS file, a bincode
+// `ShardList` replaced whole through a rename whenever a shard is split.
+// each shard is an LSM Tree in a directory named after its id, and ids are
+// never reused. a split copies the shard into two new trees before the list
+// names them, so directories the list does not name are left over from a
+// split that never finished, or from the shard it replaced, and are deleted
+// on open. a split copies a snapshot of the shard while writes go on and
+// note their keys, then holds writes back only to copy what those keys hold
+// now. once the halves take its place the shard is retired: writes that
+// reach it route again, and its directory is deleted when nothing uses the
+// tree any more.
+//
+// keys are routed by where they fall among the shards, by the crc32 of their
+// encoding when hashed, which stays the same across builds unlike `Hash`.
+
+pub const SHARDS_NAME: &str = "SHARDS";
+
+// entries copied to the halves of a split shard per write
+const SPLIT_BATCH_SIZE: usize = 1024;
+
+/// How a `Forest` spreads keys over its shards.
+#[derive(Debug, Clone)]
+pub enum Partitioning<K: LogSerial> {
+    /// This many shards, each taking an equal part of the key hashes.
+    Hash(u32),
+    /// A shard below the first boundary and one from each boundary up to
+    /// the next, so boundaries have to be increasing.
+    Range(Vec<K>),
+}
+
+/// Where a key falls among the shards, the key itself or its hash.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Route<K: LogSerial> {
+    Hash(u32),
+    Key(K),
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+struct ShardList<K: LogSerial> {
+    hashed: bool,
+    // (id, route the shard starts at), in route order. the first shard has
+    // no start and takes everything below the second
+    shards: Vec<(u64, Option<Route<K>>)>,
+    next_id: u64,
+}
+
+struct Shard<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> {
+    id: u64,
+    start: Option<Route<K>>,
+    tree: Arc<LSMTree<K, V, TM, MT>>,
+    // read by every write to the shard
+    state: Arc<RwLock<ShardState<K>>>,
+}
+
+struct ShardState<K: LogSerial> {
+    // set once the halves of a split take the shard's place
+    retired: bool,
+    // keys written since a split took the snapshot it copies
+    written: Option<Mutex<Vec<K>>>,
+}
+
+impl<K: LogSerial> Default for ShardState<K> {
+    fn default() -> Self {
+        ShardState {
+            retired: false,
+            written: None,
+        }
+    }
+}
+
+struct Shards<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> {
+    // in route order
+    shards: Vec<Shard<K, V, TM, MT>>,
+    next_id: u64,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> Shards<K, V, TM, MT> {
+    /// Index of the shard `route` falls in.
+    fn find(&self, route: &Route<K>) -> usize {
+        self.shards
+            .partition_point(|shard| shard.start.as_ref().is_none_or(|start| start <= route))
+            - 1
+    }
+
+    fn position(&self, id: u64) -> usize {
+        self.shards.iter().position(|shard| shard.id == id).unwrap()
+    }
+
+    /// Route the shard after shard `i` starts at, if there is one.
+    fn end(&self, i: usize) -> Option<&Route<K>> {
+        self.shards
+            .get(i + 1)
+            .and_then(|shard| shard.start.as_ref())
+    }
+
+    fn list(&self, hashed: bool) -> ShardList<K> {
+        ShardList {
+            hashed,
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| (shard.id, shard.start.clone()))
+                .collect(),
+            next_id: self.next_id,
+        }
+    }
+}
+
+/// Key space split across LSM Trees by key hash or by key range. Each shard
+/// has its own directory, WAL and table manager, so writes to different
+/// shards do not wait on each other.
+///
+/// A split copies a snapshot of the shard, so writes to it only wait while
+/// the ones made during the copy are caught up.
+pub struct Forest<
+    K: LogSerial,
+    V: LogSerial,
+    TM: TableManager<K, V> = SimpleTableManager<K, V>,
+    MT: MemTable<K, V> = BTreeMemTable<K, V>,
+> {
+    pub(crate) path: PathBuf,
+    pub(crate) options: Options,
+    hashed: bool,
+    // locked for reading by every read and write, a split has it to itself
+    // only to take ids and to put the halves in place
+    shards: RwLock<Shards<K, V, TM, MT>>,
+    // held by a split from start to end
+    splitting: Mutex<()>,
+    // split shards still in use, their directories deleted once they are not
+    #[allow(clippy::type_complexity)]
+    retired: Mutex<Vec<Arc<LSMTree<K, V, TM, MT>>>>,
+    // held while the forest is open
+    _lock: File,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> Forest<K, V, TM, MT> {
+    /// Opens the forest in `p`, creating it with the shards `partitioning`
+    /// gives if needed. Each shard is an LSM Tree opened with `options`.
+    pub fn open<P: AsRef<Path>>(
+        p: P,
+        partitioning: Partitioning<K>,
+        options: Options,
+    ) -> Result<Arc<Forest<K, V, TM, MT>>> {
+        let p = p.as_ref();
+        options.validate()?;
+        fs::create_dir_all(p)?;
+        let lock = lock_dir(p)?;
+
+        let list = match read_shards(p)? {
+            Some(list) => list,
+            None => {
+                let list = new_shard_list(partitioning)?;
+                write_shards(p, &list)?;
+                list
+            }
+        };
+        remove_unlisted_shards(p, &list)?;
+
+        let mut shards = Vec::with_capacity(list.shards.len());
+        for (id, start) in list.shards {
+            let tree = LSMTree::open(shard_path(p, id), options.clone())?;
+            shards.push(Shard {
+                id,
+                start,
+                tree,
+                state: Arc::default(),
+            });
+        }
+
+        Ok(Arc::new(Forest {
+            path: p.to_path_buf(),
+            options,
+            hashed: list.hashed,
+            shards: RwLock::new(Shards {
+                shards,
+                next_id: list.next_id,
+            }),
+            splitting: Mutex::new(()),
+            retired: Mutex::new(Vec::new()),
+            _lock: lock,
+        }))
+    }
+
+    fn route(&self, key: &K) -> Route<K> {
+        if self.hashed {
+            Route::Hash(key_hash(key))
+        } else {
+            Route::Key(key.clone())
+        }
+    }
+
+    /// The shards, in the order of the keys or hashes they take.
+    #[cfg(test)]
+    pub(crate) fn shards(&self) -> Vec<Arc<LSMTree<K, V, TM, MT>>> {
+        let shards = self.shards.read().unwrap();
+        shards
+            .shards
+            .iter()
+            .map(|shard| Arc::clone(&shard.tree))
+            .collect()
+    }
+
+    /// Runs `f` on the tree of the shard `key` falls in, noting the key if
+    /// the shard is being split.
+    fn write_shard<T>(
+        &self,
+        key: &K,
+        f: impl FnOnce(&LSMTree<K, V, TM, MT>) -> Result<T>,
+    ) -> Result<T> {
+        let route = self.route(key);
+        loop {
+            let (tree, state) = {
+                let shards = self.shards.read().unwrap();
+                let shard = &shards.shards[shards.find(&route)];
+                (Arc::clone(&shard.tree), Arc::clone(&shard.state))
+            };
+            // a shard retired while this waited has been replaced
+            let state = state.read().unwrap();
+            if state.retired {
+                continue;
+            }
+            if let Some(written) = &state.written {
+                written.lock().unwrap().push(key.clone());
+            }
+            return f(&tree);
+        }
+    }
+
+    /// Runs `f` on the tree of every shard not retired by a split.
+    fn each_shard(&self, f: impl Fn(&LSMTree<K, V, TM, MT>) -> Result<()>) -> Result<()> {
+        let trees: Vec<_> = {
+            let shards = self.shards.read().unwrap();
+            shards
+                .shards
+                .iter()
+                .map(|shard| (Arc::clone(&shard.tree), Arc::clone(&shard.state)))
+                .collect()
+        };
+        for (tree, state) in trees {
+            if !state.read().unwrap().retired {
+                f(&tree)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let shards = self.shards.read().unwrap();
+        shards.shards[shards.find(&self.route(key))].tree.get(key)
+    }
+
+    pub fn put(&self, key: K, value: V) -> Result<()> {
+        self.write_shard(&key.clone(), |tree| tree.put(key, value))
+    }
+
+    pub fn remove(&self, key: &K) -> Result<()> {
+        self.write_shard(key, |tree| tree.remove(key))
+    }
+
+    /// Ordered iterator over the live entries with keys in `range`, merged
+    /// from every shard that may hold some.
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        let start: Bound<K> = range.start_bound().cloned();
+        let end: Bound<K> = range.end_bound().cloned();
+
+        let shards = self.shards.read().unwrap();
+        let mut sources: Vec<EntryIterator<K, V>> = Vec::new();
+        for (i, shard) in shards.shards.iter().enumerate() {
+            if !self.hashed && !overlaps(shard.start.as_ref(), shards.end(i), &start, &end) {
+                continue;
+            }
+            let entries = shard.tree.range((start.clone(), end.clone()))?;
+            sources.push(Box::new(
+                entries.map(|entry| entry.map(|(key, value)| (key, Some(value)))),
+            ));
+        }
+
+        // shards hold different keys, so nothing is shadowed
+        Ok(MergeIterator::new(sources).filter_map(|entry| {
+            entry
+                .map(|(key, value)| value.map(|value| (key, value)))
+                .transpose()
+        }))
+    }
+
+    /// Ordered iterator over every live entry
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        self.range(..)
+    }
+
+    /// Writes the memtables of every shard to sstables.
+    pub fn flush_memtables(&self) -> Result<()> {
+        self.each_shard(|tree| tree.flush_memtable())
+    }
+
+    /// Runs the compactions that are due in every shard.
+    pub fn compact(&self) -> Result<()> {
+        self.each_shard(|tree| tree.compact())
+    }
+
+    /// Splits the shard holding `key` in two, at its middle key or hash.
+    /// Returns false if it is too small to split.
+    pub fn split_shard(&self, key: &K) -> Result<bool> {
+        let _splitting = self.splitting.lock().unwrap();
+        let id = {
+            let shards = self.shards.read().unwrap();
+            shards.shards[shards.find(&self.route(key))].id
+        };
+        self.split(id)
+    }
+
+    /// Splits every shard whose directory takes more than `max_bytes` on
+    /// disk, returning how many were. The halves are not split again until
+    /// the next call.
+    pub fn split_large_shards(&self, max_bytes: u64) -> Result<usize> {
+        let _splitting = self.splitting.lock().unwrap();
+        let ids: Vec<(u64, PathBuf)> = {
+            let shards = self.shards.read().unwrap();
+            shards
+                .shards
+                .iter()
+                .map(|shard| (shard.id, shard.tree.path.clone()))
+                .collect()
+        };
+        let mut split = 0;
+        for (id, path) in ids {
+            if dir_size(&path)? > max_bytes && self.split(id)? {
+                split += 1;
+            }
+        }
+        Ok(split)
+    }
+
+    /// Copies shard `id` into two new trees, one below its middle and one
+    /// from it, and puts them in its place.
+    fn split(&self, id: u64) -> Result<bool> {
+        let (tree, state, start, end) = {
+            let shards = self.shards.read().unwrap();
+            let i = shards.position(id);
+            let shard = &shards.shards[i];
+            (
+                Arc::clone(&shard.tree),
+                Arc::clone(&shard.state),
+                shard.start.clone(),
+                shards.end(i).cloned(),
+            )
+        };
+
+        // writes that finish after this note their keys, so none is missed
+        // by both the snapshot and the catch up
+        state.write().unwrap().written = Some(Mutex::new(Vec::new()));
+        let result = self.split_from_snapshot(id, &tree, &state, start, end);
+        state.write().unwrap().written = None;
+        if !result? {
+            return Ok(false);
+        }
+
+        self.retired.lock().unwrap().push(tree);
+        self.remove_retired()?;
+        Ok(true)
+    }
+
+    /// Copies a snapshot of `tree`, the tree of shard `id`, into two new
+    /// shards, then holds writes to it back to copy the keys written since
+    /// and to put the new shards in its place.
+    fn split_from_snapshot(
+        &self,
+        id: u64,
+        tree: &LSMTree<K, V, TM, MT>,
+        state: &RwLock<ShardState<K>>,
+        start: Option<Route<K>>,
+        end: Option<Route<K>>,
+    ) -> Result<bool> {
+        let snapshot = tree.snapshot();
+        let middle = if self.hashed {
+            let start = match &start {
+                Some(Route::Hash(start)) => *start as u64,
+                _ => 0,
+            };
+            let end = match &end {
+                Some(Route::Hash(end)) => *end as u64,
+                _ => 1 << 32,
+            };
+            if end - start < 2 {
+                return Ok(false);
+            }
+            Route::Hash(((start + end) / 2) as u32)
+        } else {
+            // the median key, so each half gets as many
+            let mut len = 0;
+            for entry in snapshot.iter()? {
+                entry?;
+                len += 1;
+            }
+            if len < 2 {
+                return Ok(false);
+            }
+            match snapshot.iter()?.nth(len / 2) {
+                Some(entry) => Route::Key(entry?.0),
+                None => return Ok(false),
+            }
+        };
+
+        // taken before anything is written, so a failed split never leaves
+        // a directory a later one reuses
+        let ids = {
+            let mut shards = self.shards.write().unwrap();
+            shards.next_id += 2;
+            [shards.next_id - 2, shards.next_id - 1]
+        };
+        let copied = self.copy_halves(ids, snapshot.iter()?, &middle);
+        drop(snapshot);
+
+        // writes to the shard wait from here until it is retired
+        let mut state = state.write().unwrap();
+        let halves = copied.and_then(|halves| {
+            let mut written = state.written.take().unwrap().into_inner().unwrap();
+            written.sort();
+            written.dedup();
+            let entries = written
+                .into_iter()
+                .map(|key| tree.get(&key).map(|value| (key, value)));
+            self.write_halves(&halves, entries, &middle)?;
+            Ok(halves)
+        });
+        let halves = match halves {
+            Ok(halves) => halves,
+            Err(e) => {
+                for id in ids {
+                    let _ = fs::remove_dir_all(shard_path(&self.path, id));
+                }
+                return Err(e);
+            }
+        };
+
+        {
+            let mut shards = self.shards.write().unwrap();
+            let i = shards.position(id);
+            let mut list = shards.list(self.hashed);
+            list.shards.splice(
+                i..=i,
+                [(ids[0], start.clone()), (ids[1], Some(middle.clone()))],
+            );
+            write_shards(&self.path, &list)?;
+
+            let [lower, upper] = halves;
+            shards.shards.splice(
+                i..=i,
+                [
+                    Shard {
+                        id: ids[0],
+                        start,
+                        tree: lower,
+                        state: Arc::default(),
+                    },
+                    Shard {
+                        id: ids[1],
+                        start: Some(middle),
+                        tree: upper,
+                        state: Arc::default(),
+                    },
+                ],
+            );
+        }
+        state.retired = true;
+        Ok(true)
+    }
+
+    /// Deletes the directories of the split shards nothing uses any more.
+    fn remove_retired(&self) -> Result<()> {
+        let mut retired = self.retired.lock().unwrap();
+        for tree in std::mem::take(&mut *retired) {
+            match Arc::try_unwrap(tree) {
+                Ok(tree) => {
+                    let path = tree.path.clone();
+                    drop(tree);
+                    fs::remove_dir_all(path)?;
+                }
+                Err(tree) => retired.push(tree),
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens shards `ids` and writes `entries` below `middle` to the first
+    /// and the rest to the second.
+    #[allow(clippy::type_complexity)]
+    fn copy_halves(
+        &self,
+        ids: [u64; 2],
+        entries: impl Iterator<Item = Result<(K, V)>>,
+        middle: &Route<K>,
+    ) -> Result<[Arc<LSMTree<K, V, TM, MT>>; 2]> {
+        let lower = LSMTree::open(shard_path(&self.path, ids[0]), self.options.clone())?;
+        let upper = LSMTree::open(shard_path(&self.path, ids[1]), self.options.clone())?;
+        let halves = [lower, upper];
+        let entries = entries.map(|entry| entry.map(|(key, value)| (key, Some(value))));
+        self.write_halves(&halves, entries, middle)?;
+        Ok(halves)
+    }
+
+    /// Writes `entries`, `None` for a removed key, below `middle` to the
+    /// first half and the rest to the second, then flushes both.
+    fn write_halves(
+        &self,
+        halves: &[Arc<LSMTree<K, V, TM, MT>>; 2],
+        entries: impl Iterator<Item = Result<(K, Option<V>)>>,
+        middle: &Route<K>,
+    ) -> Result<()> {
+        let mut batches = [WriteBatch::new(), WriteBatch::new()];
+        for entry in entries {
+            let (key, value) = entry?;
+            let half = (self.route(&key) >= *middle) as usize;
+            match value {
+                Some(value) => batches[half].put(key, value),
+                None => batches[half].remove(&key),
+            };
+            if batches[half].len() >= SPLIT_BATCH_SIZE {
+                halves[half].write(std::mem::take(&mut batches[half]))?;
+            }
+        }
+        for (tree, batch) in halves.iter().zip(batches) {
+            tree.write(batch)?;
+            tree.flush_memtable()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> Drop
+    for Forest<K, V, TM, MT>
+{
+    fn drop(&mut self) {
+        // anything left is deleted on the next open
+        let _ = self.remove_retired();
+    }
+}
+
+/// Directory of shard `id` in the forest in `dir`.
+pub fn shard_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("shard_{:04}", id))
+}
+
+fn key_hash<K: LogSerial>(key: &K) -> u32 {
+    let encoded = bincode::encode_to_vec(key, bincode::config::standard()).unwrap();
+    crc32fast::hash(&encoded)
+}
+
+/// Whether a shard from `start` up to `end` may hold keys between two bounds.
+fn overlaps<K: LogSerial>(
+    start: Option<&Route<K>>,
+    end: Option<&Route<K>>,
+    range_start: &Bound<K>,
+    range_end: &Bound<K>,
+) -> bool {
+    let starts_before_end = match (start, range_end) {
+        (Some(Route::Key(start)), Bound::Included(range_end)) => start <= range_end,
+        (Some(Route::Key(start)), Bound::Excluded(range_end)) => start < range_end,
+        _ => true,
+    };
+    let ends_after_start = match (end, range_start) {
+        (Some(Route::Key(end)), Bound::Included(range_start) | Bound::Excluded(range_start)) => {
+            range_start < end
+        }
+        _ => true,
+    };
+    starts_before_end && ends_after_start
+}
+
+fn new_shard_list<K: LogSerial>(partitioning: Partitioning<K>) -> Result<ShardList<K>> {
+    let (hashed, starts) = match partitioning {
+        Partitioning::Hash(0) => {
+            return Err(Error::InvalidArgument(
+                "a forest needs at least one shard".to_string(),
+            ));
+        }
+        Partitioning::Hash(shards) => {
+            let width = (1u64 << 32) / shards as u64;
+            let starts = (1..shards)
+                .map(|i| Route::Hash((i as u64 * width) as u32))
+                .collect::<Vec<_>>();
+            (true, starts)
+        }
+        Partitioning::Range(boundaries) => {
+            if boundaries.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(Error::InvalidArgument(
+                    "range boundaries have to be increasing".to_string(),
+                ));
+            }
+            (false, boundaries.into_iter().map(Route::Key).collect())
+        }
+    };
+
+    let shards: Vec<(u64, Option<Route<K>>)> = std::iter::once(None)
+        .chain(starts.into_iter().map(Some))
+        .enumerate()
+        .map(|(id, start)| (id as u64, start))
+        .collect();
+    Ok(ShardList {
+        hashed,
+        next_id: shards.len() as u64,
+        shards,
+    })
+}
+
+fn read_shards<K: LogSerial>(dir: &Path) -> Result<Option<ShardList<K>>> {
+    let path = dir.join(SHARDS_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let buf = fs::read(path)?;
+    let (list, _) = bincode::decode_from_slice(&buf, bincode::config::standard())?;
+    Ok(Some(list))
+}
+
+fn write_shards<K: LogSerial>(dir: &Path, list: &ShardList<K>) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", SHARDS_NAME));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bincode::encode_to_vec(list, bincode::config::standard())?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(SHARDS_NAME))?;
+    sync_dir(dir)
+}
+
+/// Deletes shard directories the list does not name.
+fn remove_unlisted_shards<K: LogSerial>(dir: &Path, list: &ShardList<K>) -> Result<()> {
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let id = name
+            .strip_prefix("shard_")
+            .and_then(|id| id.parse::<u64>().ok());
+        if let Some(id) = id {
+            if !list.shards.iter().any(|(other, _)| *other == id) {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Bytes of the files directly in `dir`.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for file in fs::read_dir(dir)? {
+        let metadata = file?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
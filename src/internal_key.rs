@@ -0,0 +1,120 @@
+use crate::error::Result;
+use crate::lsm_forest::LogSerial;
+use bincode::enc::write::SizeWriter;
+use bincode::{Decode, Encode};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+
+/// Sequence number that sees every write.
+pub const MAX_SEQ: u64 = u64::MAX;
+
+/// A user key stamped with the sequence number of the write that made it.
+///
+/// Versions of one key sort newest first, so the first entry at or after
+/// `InternalKey::new(key, seq)` is the newest version visible at `seq`.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct InternalKey<K: LogSerial> {
+    pub key: K,
+    pub seq: u64,
+}
+
+/// Memtable holding every version of a key that a live snapshot may need.
+pub type Memtable<K, V> = BTreeMap<InternalKey<K>, Option<V>>;
+
+impl<K: LogSerial> InternalKey<K> {
+    pub fn new(key: K, seq: u64) -> InternalKey<K> {
+        InternalKey { key, seq }
+    }
+}
+
+impl<K: LogSerial> Ord for InternalKey<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .cmp(&other.key)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<K: LogSerial> PartialOrd for InternalKey<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// only the user key is hashed, so a bloom filter built from internal keys
+// can be checked without knowing which version is in the table
+impl<K: LogSerial> Hash for InternalKey<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+/// Internal key bounds covering every version of the user keys in `start..end`.
+pub fn internal_range<K: LogSerial>(
+    start: &Bound<K>,
+    end: &Bound<K>,
+) -> (Bound<InternalKey<K>>, Bound<InternalKey<K>>) {
+    let start = match start {
+        Bound::Included(key) => Bound::Included(InternalKey::new(key.clone(), MAX_SEQ)),
+        Bound::Excluded(key) => Bound::Excluded(InternalKey::new(key.clone(), 0)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let end = match end {
+        Bound::Included(key) => Bound::Included(InternalKey::new(key.clone(), 0)),
+        Bound::Excluded(key) => Bound::Excluded(InternalKey::new(key.clone(), MAX_SEQ)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+/// Size of an entry once encoded, which is what memtables are measured in.
+pub fn entry_size<K: LogSerial, V: LogSerial>(key: &InternalKey<K>, value: &Option<V>) -> usize {
+    let mut writer = SizeWriter::default();
+    // counting bytes cannot fail
+    let _ = bincode::encode_into_writer((key, value), &mut writer, bincode::config::standard());
+    writer.bytes_written
+}
+
+/// Approximate encoded size of `memtable`, the sum of its entries.
+#[cfg(test)]
+pub fn memtable_size<K: LogSerial, V: LogSerial>(memtable: &Memtable<K, V>) -> usize {
+    memtable
+        .iter()
+        .map(|(key, value)| entry_size(key, value))
+        .sum()
+}
+
+/// Newest version of `key` visible at `seq`, `Some(None)` for a tombstone.
+pub fn memtable_get<K: LogSerial, V: LogSerial>(
+    memtable: &Memtable<K, V>,
+    key: &K,
+    seq: u64,
+) -> Option<Option<V>> {
+    memtable
+        .range(InternalKey::new(key.clone(), seq)..)
+        .next()
+        .filter(|(found, _)| found.key == *key)
+        .map(|(_, value)| value.clone())
+}
+
+/// Keeps the newest version of each key visible at `seq` from entries in
+/// internal key order.
+pub fn visible<K: LogSerial, V: LogSerial>(
+    entries: impl Iterator<Item = Result<(InternalKey<K>, Option<V>)>>,
+    seq: u64,
+) -> impl Iterator<Item = Result<(K, Option<V>)>> {
+    let mut last_key: Option<K> = None;
+    entries.filter_map(move |entry| {
+        let (internal_key, value) = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        if internal_key.seq > seq || last_key.as_ref() == Some(&internal_key.key) {
+            return None;
+        }
+        last_key = Some(internal_key.key.clone());
+        Some(Ok((internal_key.key, value)))
+    })
+}
@@ -1,15 +1,60 @@
 #![feature(trait_alias)]
 #![allow(unused_imports)]
+mod cache;
+mod column_family;
+mod compaction;
+mod error;
+mod forest;
+mod internal_key;
 mod log;
 mod lsm_forest;
+mod manifest;
+mod memtable;
+mod merge_iterator;
+mod options;
+mod snapshot;
+mod sstable;
 mod table_manager;
+mod write_batch;
+mod write_buffer_manager;
+
+pub use crate::column_family::{ColumnFamily, DB};
+pub use crate::compaction::CompactionStats;
+pub use crate::error::{Error, Result};
+pub use crate::forest::{Forest, Partitioning};
+pub use crate::internal_key::{InternalKey, Memtable};
+pub use crate::log::{replay_segments, RecoveryReport};
+pub use crate::lsm_forest::{LSMTree, LogSerial};
+pub use crate::memtable::{BTreeMemTable, MemTable, SkipListMemTable, VectorMemTable};
+pub use crate::options::{Options, ReadOptions, SyncMode, WalRecoveryMode, WriteOptions};
+pub use crate::snapshot::Snapshot;
+pub use crate::table_manager::{
+    BCATTableManager, BloomLayer, CacheLayer, LeveledBloomCacheTableManager,
+    LeveledCompactTableManager, SimpleBloomTableManager, SimpleCacheTableManager,
+    SimpleCompactTableManager, SimpleTableManager, TableManager, TieredCompactTableManager,
+};
+pub use crate::write_batch::WriteBatch;
+pub use crate::write_buffer_manager::WriteBufferManager;
+
+use crate::cache::*;
+use crate::column_family::*;
+use crate::compaction::*;
+use crate::error::*;
+use crate::forest::*;
+use crate::internal_key::*;
 use crate::log::*;
 use crate::lsm_forest::*;
+use crate::manifest::*;
+use crate::memtable::*;
+use crate::options::*;
+use crate::snapshot::*;
+use crate::sstable::*;
 use crate::table_manager::*;
-use anyhow::Result;
+use crate::write_batch::*;
+use crate::write_buffer_manager::*;
 use bincode::{Decode, Encode};
-use crc32fast;
 use std::io::Write;
+use std::ops::Bound;
 use std::{
     collections::BTreeMap,
     fs::{self, File},
@@ -26,23 +71,32 @@ use std::sync::Arc;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
-use crate::table_manager::bcat_table_manager::*;
-use crate::table_manager::simple_bloom_table_manager::*;
-use crate::table_manager::simple_cache_table_manager::*;
+use crate::table_manager::leveled_compact_table_manager::*;
 use crate::table_manager::simple_compact_table_manager::*;
 use crate::table_manager::simple_table_manager::*;
 use crate::table_manager::tiered_compact_table_manager::*;
 
-const TEST_N: i64 = 4096;
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const TEST_N: i64 = 4096;
+
+    // stamps every entry with sequence number `seq`
+    fn stamp<K: LogSerial, V: LogSerial>(
+        memtable: BTreeMap<K, Option<V>>,
+        seq: u64,
+    ) -> Memtable<K, V> {
+        memtable
+            .into_iter()
+            .map(|(key, value)| (InternalKey::new(key, seq), value))
+            .collect()
+    }
+
     #[test]
     fn test_log_new() {
         let p = Path::new("test/test_log_new.log");
-        let _log = Log::new(p);
+        let _log = Log::new(p).unwrap();
 
         assert!(p.exists());
         // fs::remove_file(p).unwrap();
@@ -51,7 +105,7 @@ mod tests {
     #[test]
     fn test_log_append_recovery() {
         let p = Path::new("test/test_log_append.log");
-        let mut log = Log::new(p);
+        let mut log = Log::new(p).unwrap();
 
         // create btree with data
         // append to log
@@ -65,26 +119,127 @@ mod tests {
             let value_opt = Some(value.clone());
             memtable.insert(key.clone(), value_opt.clone());
 
-            let mut entry = LogEntry {
-                crc: 0,
+            let entry = LogEntry {
+                seq: i as u64,
                 // is_delete: false,
                 key,
                 value: value_opt,
             };
 
-            entry.set_crc();
-
             let _ = log.append(entry);
         }
 
-        let mut other_log = Log::new(p);
-        let recovered_memtable: BTreeMap<String, Option<String>> = other_log.recovery().unwrap();
+        let mut other_log = Log::new(p).unwrap();
+        let recovered_memtable: Memtable<String, String> = other_log.recovery().unwrap();
 
-        for (k, v) in memtable.iter() {
-            assert_eq!(v, recovered_memtable.get(k).unwrap());
+        for (i, (k, v)) in memtable.iter().enumerate() {
+            let seq = recovered_memtable.keys().nth(i).unwrap().seq;
+            assert_eq!(
+                v,
+                recovered_memtable
+                    .get(&InternalKey::new(k.clone(), seq))
+                    .unwrap()
+            );
         }
     }
 
+    #[test]
+    fn test_log_framing() {
+        let p = Path::new("test/test_log_framing.log");
+        let _ = fs::remove_file(p);
+
+        // records from a few bytes to several blocks long
+        let sizes = [1, 100, LOG_BLOCK_SIZE - 20, 3 * LOG_BLOCK_SIZE, 10, 5];
+        let mut log = Log::new(p).unwrap();
+        for (i, size) in sizes.iter().enumerate() {
+            let entry = LogEntry {
+                seq: i as u64 + 1,
+                key: i as i64,
+                value: Some("x".repeat(*size)),
+            };
+            log.append(entry).unwrap();
+        }
+
+        let recovered: Memtable<i64, String> = Log::new(p).unwrap().recovery().unwrap();
+        assert_eq!(recovered.len(), sizes.len());
+        for (i, size) in sizes.iter().enumerate() {
+            let key = InternalKey::new(i as i64, i as u64 + 1);
+            assert_eq!(recovered.get(&key), Some(&Some("x".repeat(*size))));
+        }
+    }
+
+    #[test]
+    fn test_log_corruption() {
+        let p = Path::new("test/test_log_corruption.log");
+        let n = 10000;
+
+        let write = || {
+            let _ = fs::remove_file(p);
+            let mut log = Log::new(p).unwrap();
+            for i in 0..n {
+                let entry = LogEntry {
+                    seq: i as u64 + 1,
+                    key: i,
+                    value: Some(i),
+                };
+                log.append(entry).unwrap();
+            }
+            fs::read(p).unwrap()
+        };
+        let recover = |mode| Log::new(p).unwrap().recovery_with::<i64, i64>(mode);
+
+        // a torn record at the end is cut off, and appends go on after the
+        // last whole one
+        let buf = write();
+        fs::write(p, &buf[..buf.len() - 2]).unwrap();
+        assert!(recover(WalRecoveryMode::AbsoluteConsistency).is_err());
+        let mut log = Log::new(p).unwrap();
+        assert_eq!(log.recovery::<i64, i64>().unwrap().len(), n as usize - 1);
+        assert!(log.recovery_report.is_empty());
+        let entry = LogEntry {
+            seq: n as u64,
+            key: n - 1,
+            value: Some(n - 1),
+        };
+        log.append(entry).unwrap();
+        assert_eq!(
+            recover(WalRecoveryMode::default()).unwrap().len(),
+            n as usize
+        );
+
+        // corruption in the first block
+        let mut buf = write();
+        assert!(buf.len() > 2 * LOG_BLOCK_SIZE);
+        buf[100] ^= 0xff;
+
+        fs::write(p, &buf).unwrap();
+        assert!(recover(WalRecoveryMode::TolerateCorruptedTailRecords).is_err());
+        assert!(recover(WalRecoveryMode::AbsoluteConsistency).is_err());
+
+        // every record in the rest of the block is lost, the ones after it are not
+        // and reported
+        let mut log = Log::new(p).unwrap();
+        let skipped = log
+            .recovery_with::<i64, i64>(WalRecoveryMode::SkipAnyCorruptedRecords)
+            .unwrap();
+        assert!(skipped.len() < n as usize);
+        assert!(skipped.contains_key(&InternalKey::new(0, 1)));
+        assert!(skipped.contains_key(&InternalKey::new(n - 1, n as u64)));
+        let corruptions = &log.recovery_report.corruptions;
+        assert!(!corruptions.is_empty());
+        assert!(corruptions[0].contains("test_log_corruption.log"));
+
+        // only the records before the corruption are kept, the rest is cut off
+        fs::write(p, &buf).unwrap();
+        let mut log = Log::new(p).unwrap();
+        let recovered = log
+            .recovery_with::<i64, i64>(WalRecoveryMode::PointInTimeRecovery)
+            .unwrap();
+        assert!(!recovered.is_empty() && recovered.len() < 10);
+        assert_eq!(log.recovery_report.corruptions.len(), 1);
+        assert_eq!(recover(WalRecoveryMode::default()).unwrap(), recovered);
+    }
+
     #[test]
     fn test_simple_tm_new() {
         let p = Path::new("test/test_simple_tm_new");
@@ -92,22 +247,139 @@ mod tests {
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
+        let mut tm: BCATTableManager<String, String> =
+            BCATTableManager::new(p, &Options::default()).unwrap();
         let mut names = Vec::new();
-        for i in 0..TEST_N {
-            sleep(Duration::from_millis(1));
-            let name = format!("sstable_{:08}.sst", i);
-            names.push(p.join(name.clone()).to_path_buf());
-            File::create(p.join(name)).unwrap();
+        for i in 0..4 {
+            let mut memtable = BTreeMap::new();
+            memtable.insert(format!("key{}", i), Some(format!("value{}", i)));
+            tm.add_table(stamp(memtable, i as u64))
+                .expect("add table failed");
+            names.push(tm.tm.tm.tm.sstables[i].clone());
         }
 
-        let tm: BCATTableManager<String, String> = BCATTableManager::new(p);
+        // tables the manifest does not list are left over from a crash
+        let orphan = p.join("sstable_99999999.sst");
+        File::create(&orphan).unwrap();
+        drop(tm);
 
-        for i in 0..names.len() {
-            //assert!(tm.sstables.contains(&name));
-            assert_eq!(tm.tm.tm.sstables[i], names[i]);
+        let tm: BCATTableManager<String, String> =
+            BCATTableManager::new(p, &Options::default()).unwrap();
+
+        assert!(!orphan.exists());
+        assert_eq!(tm.tm.tm.tm.sstables, names);
+    }
+
+    #[test]
+    fn test_manifest_recovery() {
+        let p = Path::new("test/test_manifest_recovery");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let mut manifest = Manifest::open(p).unwrap();
+        let mut live = Vec::new();
+        for level in 0..3 {
+            let path = manifest.new_table_path(level);
+            File::create(&path).unwrap();
+            let mut edit = VersionEdit::default();
+            edit.add(level, &path);
+            manifest.apply(edit).unwrap();
+            live.push(path);
+        }
+
+        let mut edit = VersionEdit::default();
+        edit.remove(&live[0]);
+        manifest.apply(edit).unwrap();
+        drop(manifest);
+
+        // a torn record from a crash during append
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(p.join(manifest::MANIFEST_NAME))
+            .unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let manifest = Manifest::open(p).unwrap();
+        assert!(!live[0].exists());
+        assert_eq!(manifest.level(0), Vec::<std::path::PathBuf>::new());
+        assert_eq!(manifest.level(1), vec![live[1].clone()]);
+        assert_eq!(manifest.level(2), vec![live[2].clone()]);
+        assert_eq!(manifest.next_file_number, 3);
+
+        // the torn tail was cut off, so later edits replay cleanly
+        let mut manifest = manifest;
+        let path = manifest.new_table_path(0);
+        File::create(&path).unwrap();
+        let mut edit = VersionEdit::default();
+        edit.add(0, &path);
+        manifest.apply(edit).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(p).unwrap();
+        assert_eq!(manifest.level(0), vec![path]);
+        drop(manifest);
+
+        // a directory written before there was a manifest keeps its tables
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+        let table = p.join("sstable_00000000.sst");
+        fs::write(&table, [1, 2, 3]).unwrap();
+        assert!(matches!(Manifest::open(p), Err(Error::Corruption(_))));
+        assert!(matches!(
+            LSMTree::<i64, i64>::open(p, Options::default()),
+            Err(Error::Corruption(_))
+        ));
+        assert!(table.exists());
+        assert!(!p.join(manifest::MANIFEST_NAME).exists());
+    }
+
+    #[test]
+    fn test_compact_crash_safety() {
+        let p = Path::new("test/test_compact_crash_safety");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let mut tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        for i in 0..9 {
+            let mut memtable = BTreeMap::new();
+            memtable.insert(i, Some(i));
+            tm.add_table(stamp(memtable, i as u64))
+                .expect("add table failed");
+        }
+        let inputs = tm.tm.sstables.clone();
+
+        // a compaction that wrote its output but crashed before logging it
+        let output = tm.tm.manifest.new_table_path(0);
+        let compact_table: Memtable<i64, i64> = (0..9)
+            .map(|i| (InternalKey::new(i, i as u64), Some(i)))
+            .collect();
+        write_table(&output, compact_table, &Options::default()).unwrap();
+        drop(tm);
+
+        let mut tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        assert!(!output.exists());
+        assert_eq!(tm.tm.sstables, inputs);
+        for i in 0..9 {
+            assert_eq!(tm.read(&i).unwrap(), Some(i));
+        }
+
+        // the next flush makes a compaction due, which only then drops the inputs
+        let mut memtable = BTreeMap::new();
+        memtable.insert(9, Some(9));
+        tm.add_table(stamp(memtable, 9)).expect("add table failed");
+        compact_all::<i64, i64, _>(&mut tm, MAX_SEQ).expect("compact failed");
+        assert!(tm.tm.sstables.is_empty());
+        assert_eq!(tm.compacted.len(), 1);
+        assert!(inputs.iter().all(|input| !input.exists()));
+        drop(tm);
+
+        let tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        for i in 0..10 {
+            assert_eq!(tm.read(&i).unwrap(), Some(i));
         }
-        // assert_eq!(tm.sstables, names);
-        // fs::remove_dir_all(p).unwrap();
     }
 
     #[test]
@@ -117,7 +389,7 @@ mod tests {
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::<String, String>::new(p);
+        let mut tm = BCATTableManager::<String, String>::new(p, &Options::default()).unwrap();
         let mut memtable = BTreeMap::new();
 
         for i in 0..TEST_N {
@@ -127,22 +399,197 @@ mod tests {
             memtable.insert(key.clone(), value_opt.clone());
         }
 
-        assert_eq!(tm.add_table(memtable.clone()).unwrap(), ());
+        assert_eq!(tm.add_table(stamp(memtable.clone(), 1)).unwrap(), ());
         // assert_eq!(tm.sstables.len(), 1);
         // assert!(tm.sstables[0].exists());
 
-        let f = File::open(tm.tm.tm.sstables[0].clone()).unwrap();
-        let mut reader = BufReader::new(&f);
-        while let Ok(entry) = bincode::decode_from_reader::<
-            SimpleTableEntry<String, String>,
-            &mut BufReader<&File>,
-            _,
-        >(&mut reader, bincode::config::standard())
-        {
-            assert_eq!(entry.value, memtable.get(&entry.key).unwrap().clone());
+        let table = Table::<InternalKey<String>, String>::open(&tm.tm.tm.tm.sstables[0]).unwrap();
+        assert_eq!(table.footer.entry_count, memtable.len() as u64);
+        for entry in table.iter(Bound::Unbounded, Bound::Unbounded) {
+            let (key, value) = entry.unwrap();
+            assert_eq!(key.seq, 1);
+            assert_eq!(value, memtable.get(&key.key).unwrap().clone());
         }
     }
 
+    #[test]
+    fn test_sstable_blocks() {
+        let p = Path::new("test/test_sstable_blocks");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let path = p.join("sstable_00000000.sst");
+        let mut memtable = BTreeMap::new();
+        for i in 0..TEST_N {
+            let value = if i % 7 == 0 { None } else { Some(i * 2) };
+            memtable.insert(i * 2, value);
+        }
+        write_table(&path, memtable.clone(), &Options::default()).unwrap();
+
+        let table = Table::<i64, i64>::open(&path).unwrap();
+        assert!(table.index.len() > 1);
+        assert_eq!(table.first_key(), Some(&0));
+        assert_eq!(table.last_key(), Some(&((TEST_N - 1) * 2)));
+
+        for i in 0..TEST_N * 2 {
+            assert_eq!(table.get(&i).unwrap(), memtable.get(&i).cloned());
+        }
+        assert_eq!(table.get(&-1).unwrap(), None);
+        assert_eq!(table.get(&(TEST_N * 2)).unwrap(), None);
+
+        let entries: Vec<(i64, Option<i64>)> = table
+            .iter(Bound::Excluded(100), Bound::Included(3000))
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<(i64, Option<i64>)> = memtable
+            .range(101..=3000)
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_sstable_filter() {
+        let p = Path::new("test/test_sstable_filter");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let path = p.join("sstable_00000000.sst");
+        let memtable: BTreeMap<i64, Option<i64>> = (0..TEST_N)
+            .map(|i| (i, if i % 2 == 0 { Some(i) } else { None }))
+            .collect();
+        write_table(&path, memtable, &Options::default()).unwrap();
+
+        let filter = Table::<i64, i64>::open(&path).unwrap().read_filter().unwrap();
+
+        // tombstones are in the filter too, so they can shadow older tables
+        for i in 0..TEST_N {
+            assert!(filter.check(&i));
+        }
+
+        let false_positives = (TEST_N..TEST_N * 11).filter(|i| filter.check(i)).count();
+        assert!(false_positives < (TEST_N as usize * 10) / 10);
+    }
+
+    #[test]
+    fn test_bloom_tm_filters() {
+        let p = Path::new("test/test_bloom_tm_filters");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let mut tm = SimpleBloomTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        for i in 0..8 {
+            let memtable: BTreeMap<i64, Option<i64>> =
+                (i * 100..(i + 1) * 100).map(|k| (k, Some(k))).collect();
+            tm.add_table(stamp(memtable, i as u64))
+                .expect("add table failed");
+        }
+        let mut memtable = BTreeMap::new();
+        memtable.insert(5, None);
+        tm.add_table(stamp(memtable, 8)).expect("add table failed");
+        drop(tm);
+
+        // opening does not scan the tables, filters are read on demand
+        let tm = SimpleBloomTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        assert!(tm.filters.read().unwrap().is_empty());
+
+        assert_eq!(tm.read(&5).unwrap(), None);
+        assert_eq!(tm.read(&6).unwrap(), Some(6));
+        assert_eq!(tm.read(&799).unwrap(), Some(799));
+        assert_eq!(tm.read(&800).unwrap(), None);
+        assert_eq!(tm.filters.read().unwrap().len(), 9);
+    }
+
+    #[test]
+    fn test_sstable_corruption() {
+        let p = Path::new("test/test_sstable_corruption");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let path = p.join("sstable_00000000.sst");
+        let memtable: BTreeMap<i64, Option<i64>> = (0..TEST_N).map(|i| (i, Some(i))).collect();
+        write_table(&path, memtable, &Options::default()).unwrap();
+
+        // flip a byte in the first data block
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let table = Table::<i64, i64>::open(&path).unwrap();
+        assert!(table.get(&0).is_err());
+
+        // lengths in the footer that run past the end of the file
+        let len = bytes.len();
+        let footer = len - FOOTER_SIZE as usize;
+        let mut bogus = bytes.clone();
+        bogus[footer + 8..footer + 16].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        fs::write(&path, &bogus).unwrap();
+        let table = Table::<i64, i64>::open(&path).unwrap();
+        assert!(matches!(table.read_filter(), Err(Error::Corruption(_))));
+        bogus[footer + 24..footer + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &bogus).unwrap();
+        assert!(matches!(
+            Table::<i64, i64>::open(&path),
+            Err(Error::Corruption(_))
+        ));
+
+        // break the magic number in the footer
+        bytes[len - 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert!(Table::<i64, i64>::open(&path).is_err());
+
+        fs::write(&path, b"short").unwrap();
+        assert!(Table::<i64, i64>::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_compaction_corruption() {
+        let p = Path::new("test/test_compaction_corruption");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let mut tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let memtable: BTreeMap<i64, Option<i64>> = (0..2000).map(|i| (i, Some(i))).collect();
+        tm.add_table(stamp(memtable, 1)).expect("add table failed");
+        let memtable: BTreeMap<i64, Option<i64>> = (2000..2010).map(|i| (i, Some(i))).collect();
+        tm.add_table(stamp(memtable, 2)).expect("add table failed");
+        let inputs = tm.tm.sstables.clone();
+
+        // flip a byte in the first data block of the older table
+        let mut bytes = fs::read(&inputs[0]).unwrap();
+        bytes[10] ^= 0xff;
+        fs::write(&inputs[0], &bytes).unwrap();
+        let files = fs::read_dir(p).unwrap().count();
+
+        // the compaction fails instead of installing what it could read
+        assert!(matches!(tm.compact(MAX_SEQ), Err(Error::Corruption(_))));
+        assert_eq!(tm.tm.sstables, inputs);
+        assert!(tm.compacted.is_empty());
+        assert!(inputs.iter().all(|input| input.exists()));
+        assert_eq!(fs::read_dir(p).unwrap().count(), files);
+        assert!(matches!(tm.read(&0), Err(Error::Corruption(_))));
+        assert_eq!(tm.read(&1999).unwrap(), Some(1999));
+        assert!(matches!(tm.compact(MAX_SEQ), Err(Error::Corruption(_))));
+        drop(tm);
+
+        let tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        assert!(matches!(tm.read(&0), Err(Error::Corruption(_))));
+        assert_eq!(tm.read(&2009).unwrap(), Some(2009));
+
+        // scans return the error instead of ending early
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        let all: Result<Vec<(i64, i64)>> = lsm.iter().unwrap().collect();
+        assert!(matches!(all, Err(Error::Corruption(_))));
+        let some: Result<Vec<(i64, i64)>> = lsm.range(..10).unwrap().collect();
+        assert!(matches!(some, Err(Error::Corruption(_))));
+        let tail: Vec<(i64, i64)> = lsm.range(1990..).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(tail, (1990..2010).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
     #[test]
     fn test_simple_tm_should_flush() {
         let p = Path::new("test/test_simple_tm_should_flush");
@@ -150,31 +597,817 @@ mod tests {
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let tm = BCATTableManager::<String, String>::new(p);
-        let mut memtable = BTreeMap::new();
+        let mut memtable: Memtable<String, String> = BTreeMap::new();
 
-        let dummy_wal = Log::new(&p.join("temp"));
+        let dummy_wal = Log::new(&p.join("temp")).unwrap();
+        let options = Options::default();
 
-        assert_eq!(tm.should_flush(&dummy_wal, &memtable), false);
+        assert!(!BCATTableManager::<String, String>::should_flush(
+            &options,
+            &dummy_wal,
+            memtable_size(&memtable)
+        ));
+
+        let mut i = 0;
+        while memtable_size(&memtable) < options.memtable_max_bytes {
+            assert!(!BCATTableManager::<String, String>::should_flush(
+                &options,
+                &dummy_wal,
+                memtable_size(&memtable)
+            ));
 
-        for i in 0..255 {
             let key = format!("key{}", i);
             let value = format!("value{}", i);
-            let value_opt = Some(value.clone());
-            memtable.insert(key.clone(), value_opt.clone());
-
-            println!("{} {}", i, memtable.len());
-            assert_eq!(tm.should_flush(&dummy_wal, &memtable), false);
+            memtable.insert(InternalKey::new(key, i), Some(value));
+            i += 1;
         }
 
-        for i in 256..400 {
+        for i in i..i + 144 {
             let key = format!("key{}", i);
             let value = format!("value{}", i);
-            let value_opt = Some(value.clone());
-            memtable.insert(key.clone(), value_opt.clone());
+            memtable.insert(InternalKey::new(key, i), Some(value));
+
+            assert!(BCATTableManager::<String, String>::should_flush(
+                &options,
+                &dummy_wal,
+                memtable_size(&memtable)
+            ));
+        }
+
+        // a few large values fill the memtable as well
+        let mut memtable: Memtable<String, String> = BTreeMap::new();
+        for i in 0..4 {
+            let key = format!("key{}", i);
+            memtable.insert(InternalKey::new(key, i), Some("v".repeat(1024)));
+        }
+        assert!(BCATTableManager::<String, String>::should_flush(
+            &options,
+            &dummy_wal,
+            memtable_size(&memtable)
+        ));
+    }
+
+    #[test]
+    fn test_options() {
+        assert!(Options::default().validate().is_ok());
+        let invalid = [
+            Options {
+                memtable_max_bytes: 0,
+                ..Options::default()
+            },
+            Options {
+                write_buffer_manager: Some(Arc::new(WriteBufferManager::new(0))),
+                ..Options::default()
+            },
+            Options {
+                compact_threshold: 1,
+                ..Options::default()
+            },
+            Options {
+                filter_fp_rate: 1.0,
+                ..Options::default()
+            },
+            Options {
+                size_ratio: 1,
+                ..Options::default()
+            },
+        ];
+        for options in invalid {
+            assert!(options.validate().is_err());
+        }
+
+        let p = Path::new("test/test_options");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        // memtables are flushed at the size the options give, entries of
+        // small keys and values take four bytes
+        let options = Options {
+            memtable_max_bytes: 64,
+            ..Options::default()
+        };
+        let tm = SimpleTableManager::<i64, i64>::new(p, &options).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, options).unwrap();
+        for i in 0..64 {
+            lsm.put(i, i).expect("put failed");
+        }
+        lsm.wait_for_flush().expect("flush failed");
+        assert_eq!(lsm.table_manager.read().unwrap().sstables.len(), 4);
+    }
+
+    #[test]
+    fn test_write_buffer_manager() {
+        let p = Path::new("test/test_write_buffer_manager");
 
-            assert_eq!(tm.should_flush(&dummy_wal, &memtable), true);
+        let _ = fs::remove_dir_all(p);
+
+        // the shared budget flushes memtables long before their own limit
+        let manager = Arc::new(WriteBufferManager::new(2 * 1024));
+        let options = Options {
+            memtable_max_bytes: 1024 * 1024,
+            wal_max_bytes: 1024 * 1024,
+            write_buffer_manager: Some(Arc::clone(&manager)),
+            ..Options::default()
+        };
+        let first = LSMTree::<i64, i64>::open(p.join("first"), options.clone()).unwrap();
+        let second = LSMTree::<i64, i64>::open(p.join("second"), options).unwrap();
+        for i in 0..TEST_N {
+            first.put(i, i).expect("put failed");
+            second.put(i, i).expect("put failed");
+        }
+        first.wait_for_flush().expect("flush failed");
+        second.wait_for_flush().expect("flush failed");
+
+        assert!(!first.table_manager.read().unwrap().sstables.is_empty());
+        assert!(!second.table_manager.read().unwrap().sstables.is_empty());
+        assert!(manager.memory_usage() < manager.buffer_size());
+        assert_eq!(
+            manager.memory_usage(),
+            first.memtable_usage() + second.memtable_usage()
+        );
+        for i in 0..TEST_N {
+            assert_eq!(first.get(&i).unwrap(), Some(i));
+            assert_eq!(second.get(&i).unwrap(), Some(i));
         }
+
+        first.flush_memtable().expect("flush failed");
+        assert_eq!(first.memtable_usage(), 0);
+        assert_eq!(manager.memory_usage(), second.memtable_usage());
+
+        // memory of a closed tree is given back
+        drop(first);
+        drop(second);
+        assert_eq!(manager.memory_usage(), 0);
+        assert_eq!(manager.mutable_usage(), 0);
+    }
+
+    // inserts versions of 0..n out of order, then reads them back at
+    // sequence numbers between the versions
+    fn check_memtable<MT: MemTable<i64, i64>>(n: i64) {
+        let memtable = MT::new();
+        assert!(memtable.is_empty());
+
+        let mut keys: Vec<i64> = (0..n).collect();
+        keys.shuffle(&mut rand::thread_rng());
+        for &i in &keys {
+            memtable.insert(InternalKey::new(i, 1), Some(i));
+        }
+        for &i in &keys {
+            let value = if i % 2 == 0 { None } else { Some(-i) };
+            memtable.insert(InternalKey::new(i, 3), value);
+        }
+        assert_eq!(memtable.len(), 2 * n as usize);
+        assert!(memtable.size() > 0);
+
+        for i in 0..n {
+            assert_eq!(memtable.get(&i, 0), None);
+            assert_eq!(memtable.get(&i, 2), Some(Some(i)));
+            let value = if i % 2 == 0 { None } else { Some(-i) };
+            assert_eq!(memtable.get(&i, MAX_SEQ), Some(value));
+        }
+        assert_eq!(memtable.get(&n, MAX_SEQ), None);
+
+        let (start, end) = internal_range(&Bound::Excluded(10), &Bound::Included(20));
+        let found: Vec<(i64, u64)> = memtable
+            .range(start, end)
+            .into_iter()
+            .map(|(key, _)| (key.key, key.seq))
+            .collect();
+        let expected: Vec<(i64, u64)> = (11..=20).flat_map(|i| [(i, 3), (i, 1)]).collect();
+        assert_eq!(found, expected);
+
+        let entries = memtable.entries();
+        assert_eq!(entries.len(), 2 * n as usize);
+        assert!(entries
+            .keys()
+            .zip(entries.keys().skip(1))
+            .all(|(a, b)| a < b));
+        assert_eq!(memtable_size(&entries), memtable.size());
+    }
+
+    #[test]
+    fn test_memtables() {
+        check_memtable::<BTreeMemTable<i64, i64>>(TEST_N);
+        check_memtable::<SkipListMemTable<i64, i64>>(TEST_N);
+        check_memtable::<VectorMemTable<i64, i64>>(TEST_N);
+
+        // skiplist inserts from many threads at once all land
+        let memtable = Arc::new(SkipListMemTable::<i64, i64>::new());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let memtable = Arc::clone(&memtable);
+                std::thread::spawn(move || {
+                    for i in (t..TEST_N).step_by(8) {
+                        memtable.insert(InternalKey::new(i, 1), Some(i));
+                        assert_eq!(memtable.get(&i, MAX_SEQ), Some(Some(i)));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(memtable.len(), TEST_N as usize);
+        let keys: Vec<i64> = memtable.entries().into_keys().map(|key| key.key).collect();
+        assert_eq!(keys, (0..TEST_N).collect::<Vec<_>>());
+
+        // each tree picks its own memtable
+        type SkipListTree =
+            LSMTree<i64, i64, SimpleTableManager<i64, i64>, SkipListMemTable<i64, i64>>;
+        type VectorTree = LSMTree<i64, i64, SimpleTableManager<i64, i64>, VectorMemTable<i64, i64>>;
+        let p = Path::new("test/test_memtables");
+        let _ = fs::remove_dir_all(p);
+        let options = Options {
+            memtable_max_bytes: 1024,
+            ..Options::default()
+        };
+        let lsm = SkipListTree::open(p.join("skiplist"), options.clone()).unwrap();
+        for i in 0..TEST_N {
+            lsm.put(i, i).expect("put failed");
+        }
+        lsm.remove(&0).expect("remove failed");
+        drop(lsm);
+        let lsm = SkipListTree::open(p.join("skiplist"), options.clone()).unwrap();
+        assert_eq!(lsm.get(&0).unwrap(), None);
+        for i in 1..TEST_N {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+
+        let lsm = VectorTree::open(p.join("vector"), options).unwrap();
+        for i in (0..TEST_N).rev() {
+            lsm.put(i, i).expect("put failed");
+        }
+        lsm.wait_for_flush().expect("flush failed");
+        assert!(!lsm.table_manager.read().unwrap().sstables.is_empty());
+        let found: Vec<(i64, i64)> = lsm
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(found, (0..TEST_N).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_column_families() {
+        let p = Path::new("test/test_column_families");
+
+        let _ = fs::remove_dir_all(p);
+
+        let db = DB::<i64, i64>::open(p, Options::default()).unwrap();
+        assert_eq!(db.column_families(), vec![DEFAULT_FAMILY_NAME]);
+        let default = db.default_family();
+        let small = db
+            .create_column_family(
+                "small",
+                Options {
+                    memtable_max_bytes: 256,
+                    ..Options::default()
+                },
+            )
+            .unwrap();
+        let large = db
+            .create_column_family("large", Options::default())
+            .unwrap();
+        assert!(matches!(
+            db.create_column_family("small", Options::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // families share the wal but not their keys
+        let threads: Vec<_> = [Arc::clone(&default), Arc::clone(&small)]
+            .into_iter()
+            .enumerate()
+            .map(|(t, family)| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    for i in 0..TEST_N {
+                        db.put(&family, i, i * t as i64).expect("put failed");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        small.wait_for_flush().expect("flush failed");
+        assert!(!small.table_manager.read().unwrap().sstables.is_empty());
+
+        // one batch updates several families at once
+        let mut batch = WriteBatch::new();
+        batch
+            .put_cf(&large, 1, 1)
+            .put_cf(&small, 1, 100)
+            .remove_cf(&default, &2);
+        db.write(batch).expect("write failed");
+        let mut batch = WriteBatch::new();
+        batch.put(TEST_N, TEST_N);
+        db.write(batch).expect("write failed");
+
+        let check = |default: &ColumnFamily<i64, i64>, small: &ColumnFamily<i64, i64>| {
+            for i in 0..TEST_N {
+                let expected = if i == 2 { None } else { Some(0) };
+                assert_eq!(default.get(&i).unwrap(), expected);
+                let expected = if i == 1 { 100 } else { i };
+                assert_eq!(small.get(&i).unwrap(), Some(expected));
+            }
+            assert_eq!(default.get(&TEST_N).unwrap(), Some(TEST_N));
+            assert_eq!(small.iter().unwrap().count(), TEST_N as usize);
+        };
+        check(&default, &small);
+        let found: Vec<(i64, i64)> = large.iter().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(found, vec![(1, 1)]);
+
+        // a plain tree only has the default family
+        let tree_path = Path::new("test/test_column_families_tree");
+        let _ = fs::remove_dir_all(tree_path);
+        let lsm = LSMTree::<i64, i64>::open(tree_path, Options::default()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_cf(&small, 1, 1);
+        assert!(matches!(lsm.write(batch), Err(Error::InvalidArgument(_))));
+
+        // a dropped family is gone along with its directory
+        assert!(matches!(
+            db.drop_column_family(DEFAULT_FAMILY_NAME),
+            Err(Error::InvalidArgument(_))
+        ));
+        db.drop_column_family("large").expect("drop failed");
+        assert!(db.column_family("large").is_none());
+        assert!(matches!(
+            db.put(&large, 2, 2),
+            Err(Error::InvalidArgument(_))
+        ));
+        let large_path = large.path.clone();
+        drop(large);
+        assert!(!large_path.exists());
+
+        drop(default);
+        drop(small);
+        drop(db);
+        let db = DB::<i64, i64>::open(p, Options::default()).unwrap();
+        assert_eq!(db.column_families(), vec![DEFAULT_FAMILY_NAME, "small"]);
+        let default = db.default_family();
+        let small = db.column_family("small").unwrap();
+        check(&default, &small);
+
+        // the name can be used again, for a new empty family
+        let large = db
+            .create_column_family("large", Options::default())
+            .unwrap();
+        assert_eq!(large.get(&1).unwrap(), None);
+
+        // wal segments go once every family flushed what they hold
+        db.flush_memtables().expect("flush failed");
+        assert_eq!(segments(p).unwrap().len(), 1);
+        check(&default, &small);
+    }
+
+    #[test]
+    fn test_forest() {
+        let p = Path::new("test/test_forest");
+        let _ = fs::remove_dir_all(p);
+
+        assert!(matches!(
+            Forest::<i64, i64>::open(p, Partitioning::Hash(0), Options::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            Forest::<i64, i64>::open(p, Partitioning::Range(vec![2, 1]), Options::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // each thread writes its own keys, hashed over every shard
+        let forest =
+            Forest::<i64, i64>::open(p, Partitioning::Hash(4), Options::default()).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let forest = Arc::clone(&forest);
+                std::thread::spawn(move || {
+                    for i in (t..TEST_N).step_by(4) {
+                        forest.put(i, i).expect("put failed");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        forest.remove(&1).expect("remove failed");
+        assert!(forest
+            .shards()
+            .iter()
+            .all(|shard| shard.iter().unwrap().count() > 0));
+
+        let check = |forest: &Forest<i64, i64>| {
+            for i in 0..TEST_N {
+                let expected = if i == 1 { None } else { Some(i) };
+                assert_eq!(forest.get(&i).unwrap(), expected);
+            }
+            let found: Vec<(i64, i64)> = forest
+                .range(100..200)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(found, (100..200).map(|i| (i, i)).collect::<Vec<_>>());
+            let keys: Vec<i64> = forest
+                .iter()
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect();
+            assert_eq!(keys.len(), TEST_N as usize - 1);
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        };
+        check(&forest);
+
+        // a split copies the shard into two, which last over a reopen, while
+        // writes to it go on and are caught up before the halves take over
+        let writer = {
+            let forest = Arc::clone(&forest);
+            std::thread::spawn(move || {
+                for i in TEST_N..TEST_N + 1000 {
+                    forest.put(i, i).expect("put failed");
+                    if i % 2 == 0 {
+                        forest.remove(&i).expect("remove failed");
+                    }
+                }
+            })
+        };
+        assert!(forest.split_shard(&0).expect("split failed"));
+        writer.join().unwrap();
+        for i in TEST_N..TEST_N + 1000 {
+            let expected = if i % 2 == 0 { None } else { Some(i) };
+            assert_eq!(forest.get(&i).unwrap(), expected);
+            forest.remove(&i).expect("remove failed");
+        }
+        assert_eq!(forest.shards().len(), 5);
+        check(&forest);
+        drop(forest);
+        let shard_dirs = fs::read_dir(p)
+            .unwrap()
+            .filter(|file| {
+                let name = file.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("shard_")
+            })
+            .count();
+        assert_eq!(shard_dirs, 5);
+        let forest =
+            Forest::<i64, i64>::open(p, Partitioning::Hash(1), Options::default()).unwrap();
+        assert_eq!(forest.shards().len(), 5);
+        check(&forest);
+        drop(forest);
+
+        // range shards hold the keys between their boundaries
+        let p = Path::new("test/test_forest_range");
+        let _ = fs::remove_dir_all(p);
+        let forest = Forest::<i64, i64>::open(
+            p,
+            Partitioning::Range(vec![1000, 2000, 3000]),
+            Options::default(),
+        )
+        .unwrap();
+        for i in 0..TEST_N {
+            forest.put(i, i).expect("put failed");
+        }
+        forest.remove(&1).expect("remove failed");
+        let shards = forest.shards();
+        assert_eq!(shards[0].get(&999).unwrap(), Some(999));
+        assert_eq!(shards[0].get(&1000).unwrap(), None);
+        assert_eq!(shards[1].get(&1000).unwrap(), Some(1000));
+        assert_eq!(shards[3].iter().unwrap().count(), TEST_N as usize - 3000);
+        drop(shards);
+        check(&forest);
+        let found: Vec<(i64, i64)> = forest
+            .range(990..=2010)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(found, (990..=2010).map(|i| (i, i)).collect::<Vec<_>>());
+
+        // only shards over the limit are split, at their median key
+        forest.flush_memtables().expect("flush failed");
+        let sizes: Vec<u64> = forest
+            .shards()
+            .iter()
+            .map(|shard| {
+                fs::read_dir(&shard.path)
+                    .unwrap()
+                    .map(|file| file.unwrap().metadata().unwrap().len())
+                    .sum()
+            })
+            .collect();
+        let largest = *sizes.iter().max().unwrap();
+        assert_eq!(forest.split_large_shards(largest - 1).unwrap(), 1);
+        assert_eq!(forest.shards().len(), 5);
+        assert_eq!(forest.split_large_shards(u64::MAX).unwrap(), 0);
+        check(&forest);
+        drop(forest);
+        let forest =
+            Forest::<i64, i64>::open(p, Partitioning::Hash(1), Options::default()).unwrap();
+        assert_eq!(forest.shards().len(), 5);
+        check(&forest);
+    }
+
+    #[test]
+    fn test_errors() {
+        let p = Path::new("test/test_errors");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        // a directory is only opened once
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        lsm.put(1, 1).expect("put failed");
+        assert!(matches!(
+            SimpleTableManager::<i64, i64>::new(p, &Options::default()),
+            Err(Error::Busy(_))
+        ));
+        drop(lsm);
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+
+        // bad options are refused before anything is opened
+        let options = Options {
+            memtable_max_bytes: 0,
+            ..Options::default()
+        };
+        assert!(matches!(
+            LSMTree::with_table_manager(p.to_path_buf(), tm, options.clone()),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            SimpleTableManager::<i64, i64>::new(p, &options),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        assert!(matches!(
+            SimpleTableManager::<i64, i64>::new(&p.join("missing"), &Options::default()),
+            Err(Error::Io(_))
+        ));
+
+        // damaged wal records are reported as corruption, not a panic
+        let log_path = p.join("corrupt.log");
+        let mut log = Log::new(&log_path).unwrap();
+        for i in 0..10 {
+            let entry = LogEntry {
+                seq: i as u64 + 1,
+                key: i,
+                value: Some(i),
+            };
+            log.append(entry).unwrap();
+        }
+        let mut buf = fs::read(&log_path).unwrap();
+        buf[10] ^= 0xff;
+        fs::write(&log_path, &buf).unwrap();
+        assert!(matches!(
+            Log::new(&log_path)
+                .unwrap()
+                .recovery_with::<i64, i64>(WalRecoveryMode::AbsoluteConsistency),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn test_open() {
+        let p = Path::new("test/test_open");
+
+        let _ = fs::remove_dir_all(p);
+
+        // the directory is created, and the tree is shared without a lifetime
+        let lsm = LSMTree::<i64, i64, table_manager::BCATTableManager<i64, i64>>::open(
+            p,
+            Options::default(),
+        )
+        .unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let lsm = Arc::clone(&lsm);
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        lsm.put(i * 100 + j, j).expect("put failed");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        drop(lsm);
+
+        let lsm = LSMTree::<i64, i64, table_manager::BCATTableManager<i64, i64>>::open(
+            p,
+            Options::default(),
+        )
+        .unwrap();
+        for i in 0..400 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i % 100));
+        }
+        assert!(lsm.recovery_report().is_empty());
+        drop(lsm);
+
+        // corruption the recovery mode gets past is reported by open
+        let (_, segment) = segments(p).unwrap().pop().unwrap();
+        let mut buf = fs::read(&segment).unwrap();
+        buf[100] ^= 0xff;
+        fs::write(&segment, &buf).unwrap();
+        let options = Options {
+            recovery_mode: WalRecoveryMode::SkipAnyCorruptedRecords,
+            ..Options::default()
+        };
+        let lsm = LSMTree::<i64, i64, table_manager::BCATTableManager<i64, i64>>::open(p, options)
+            .unwrap();
+        assert!(!lsm.recovery_report().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let p = Path::new("test/test_concurrent_reads");
+
+        let _ = fs::remove_dir_all(p);
+
+        let lsm =
+            LSMTree::<i64, i64, BCATTableManager<i64, i64>>::open(p, Options::default()).unwrap();
+        for i in 0..2048 {
+            lsm.put(i, i).expect("put failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+
+        // readers share the table manager, one holding it does not stop another
+        let tm = lsm.table_manager.read().unwrap();
+        let reader = {
+            let lsm = Arc::clone(&lsm);
+            std::thread::spawn(move || lsm.get(&7).unwrap())
+        };
+        assert_eq!(reader.join().unwrap(), Some(7));
+        assert_eq!(tm.read(&8).unwrap(), Some(8));
+        assert!(!tm.tm.tm.tm.table_cache.is_empty());
+        drop(tm);
+
+        // readers keep going while tables are flushed and compacted under them
+        let readers: Vec<_> = (0..8)
+            .map(|i| {
+                let lsm = Arc::clone(&lsm);
+                std::thread::spawn(move || {
+                    for j in 0..2048 {
+                        let key = (i * 256 + j) % 2048;
+                        assert_eq!(lsm.get(&key).unwrap(), Some(key));
+                    }
+                })
+            })
+            .collect();
+        for i in 2048..4096 {
+            lsm.put(i, i).expect("put failed");
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+
+        let tm = lsm.table_manager.read().unwrap();
+        let tables = tm.tm.tm.tables();
+        for i in 0..4096 {
+            assert_eq!(tm.read(&i).unwrap(), Some(i));
+        }
+        // handles of compacted tables are not kept open
+        assert!(tm.tm.tm.tm.table_cache.len() <= tables.len());
+        drop(tm);
+        drop(lsm);
+
+        // scans open tables through the cache point reads use
+        let lsm =
+            LSMTree::<i64, i64, BCATTableManager<i64, i64>>::open(p, Options::default()).unwrap();
+        let tm = lsm.table_manager.read().unwrap();
+        assert!(tm.tm.tm.tm.table_cache.is_empty());
+        drop(tm);
+        assert_eq!(lsm.iter().unwrap().count(), 4096);
+        let tm = lsm.table_manager.read().unwrap();
+        assert_eq!(tm.tm.tm.tm.table_cache.len(), tm.tm.tm.tables().len());
+    }
+
+    fn check_multi_get<TM: TableManager<i64, i64>>(p: &Path) {
+        let _ = fs::remove_dir_all(p);
+
+        let options = Options {
+            memtable_max_bytes: 1024,
+            ..Options::default()
+        };
+        let lsm = LSMTree::<i64, i64, TM>::open(p, options).unwrap();
+        for i in 0..2048 {
+            lsm.put(i, i).expect("put failed");
+        }
+        for i in (0..2048).step_by(3) {
+            lsm.remove(&i).expect("remove failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+        let snapshot = lsm.snapshot();
+        // newer versions in sstables, immutables and the memtable
+        for i in (0..2048).step_by(5) {
+            lsm.put(i, i * 10).expect("put failed");
+        }
+
+        // unsorted, repeated and missing keys come back in the order asked for
+        let mut keys: Vec<i64> = (-10..2100).rev().collect();
+        keys.extend([7, 7, 3000, 0]);
+        let expected: Vec<Option<i64>> = keys.iter().map(|key| lsm.get(key).unwrap()).collect();
+        assert_eq!(lsm.multi_get(&keys).unwrap(), expected);
+        let threads = ReadOptions { threads: 4 };
+        assert_eq!(lsm.multi_get_with(&keys, &threads).unwrap(), expected);
+        assert_eq!(lsm.multi_get(&[]).unwrap(), Vec::new());
+
+        let old: Vec<Option<i64>> = keys.iter().map(|key| snapshot.get(key).unwrap()).collect();
+        assert_ne!(old, expected);
+        assert_eq!(snapshot.multi_get(&keys).unwrap(), old);
+        assert_eq!(
+            lsm.multi_get_at(&keys, snapshot.seq, &threads).unwrap(),
+            old
+        );
+    }
+
+    #[test]
+    fn test_multi_get() {
+        check_multi_get::<SimpleTableManager<i64, i64>>(Path::new("test/test_multi_get_simple"));
+        check_multi_get::<SimpleBloomTableManager<i64, i64>>(Path::new(
+            "test/test_multi_get_bloom",
+        ));
+        check_multi_get::<SimpleCacheTableManager<i64, i64>>(Path::new(
+            "test/test_multi_get_cache",
+        ));
+        check_multi_get::<SimpleCompactTableManager<i64, i64>>(Path::new(
+            "test/test_multi_get_compact",
+        ));
+        check_multi_get::<TieredCompactTableManager<i64, i64>>(Path::new(
+            "test/test_multi_get_tiered",
+        ));
+        check_multi_get::<BCATTableManager<i64, i64>>(Path::new("test/test_multi_get_bcat"));
+        check_multi_get::<LeveledCompactTableManager<i64, i64>>(Path::new(
+            "test/test_multi_get_leveled",
+        ));
+        check_multi_get::<LeveledBloomCacheTableManager<i64, i64>>(Path::new(
+            "test/test_multi_get_leveled_bloom_cache",
+        ));
+
+        // each block is read once for every key in it
+        let p = Path::new("test/test_multi_get.sst");
+        let memtable: BTreeMap<i64, Option<i64>> = (0..TEST_N).map(|i| (i * 2, Some(i))).collect();
+        write_table(p, memtable, &Options::default()).unwrap();
+        let table = Table::<i64, i64>::open(p).unwrap();
+        let keys: Vec<i64> = (-1..TEST_N * 2 + 1).collect();
+        let found = table.seek_many(&keys).unwrap();
+        for (key, found) in keys.iter().zip(found) {
+            assert_eq!(found, table.seek(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let p = Path::new("test/test_layers");
+
+        let _ = fs::remove_dir_all(p);
+
+        // layers stack over a compacting manager
+        let options = Options {
+            memtable_max_bytes: 512,
+            level0_threshold: 2,
+            level1_max_bytes: 4 * 1024,
+            ..Options::default()
+        };
+        let lsm =
+            LSMTree::<i64, i64, LeveledBloomCacheTableManager<i64, i64>>::open(p, options).unwrap();
+        for i in 0..2048 {
+            lsm.put(i, i).expect("put failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+        for i in 0..2048 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+
+        let tm = lsm.table_manager.read().unwrap();
+        let bloom = &tm.tm;
+        assert!(!tm.cache.is_empty());
+        // filters of compacted tables are dropped
+        let filters = bloom.filters.read().unwrap();
+        assert!(!filters.is_empty());
+        assert!(filters.keys().all(|path| path.exists()));
+        drop(filters);
+
+        // a filter passed down is applied on top of the bloom filters
+        assert_eq!(bloom.read_at(&5000, MAX_SEQ).unwrap(), None);
+        assert_eq!(bloom.read_at(&5, MAX_SEQ).unwrap(), Some(5));
+        assert_eq!(
+            bloom
+                .read_at_filtered(&5, MAX_SEQ, &|_, _| Ok(false))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            bloom
+                .multi_read_at_filtered(&[5, 6], MAX_SEQ, &|_, key| Ok(*key == 6))
+                .unwrap(),
+            vec![None, Some(6)]
+        );
     }
 
     #[test]
@@ -184,7 +1417,7 @@ mod tests {
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::<String, String>::new(p);
+        let mut tm = BCATTableManager::<String, String>::new(p, &Options::default()).unwrap();
         let mut memtable = BTreeMap::new();
 
         for i in 0..512 {
@@ -203,14 +1436,17 @@ mod tests {
             copy_memtable.insert(key.clone(), Some(value.clone()));
             memtable.insert(key.clone(), Some(value.clone()));
             sleep(Duration::from_millis(1));
-            tm.add_table(copy_memtable.clone())
+            tm.add_table(stamp(copy_memtable.clone(), i))
                 .expect("add table failed");
             copy_memtable.remove(&key);
         }
 
         for i in 0..512 {
             let key = format!("key{}", i);
-            assert_eq!(tm.read(&key), memtable.get(&key.clone()).unwrap().clone());
+            assert_eq!(
+                tm.read(&key).unwrap(),
+                memtable.get(&key.clone()).unwrap().clone()
+            );
         }
     }
 
@@ -220,220 +1456,986 @@ mod tests {
     }
 
     #[test]
-    fn test_lsm_put_get() {
-        let p = Path::new("test/test_lsm_put_get");
+    fn test_lsm_put_get() {
+        let p = Path::new("test/test_lsm_put_get");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = BCATTableManager::<String, String>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        let mut memtable = BTreeMap::new();
+
+        for i in 0..TEST_N {
+            let key = format!("key{}", i);
+            let value = format!("value{}", i);
+            memtable.insert(key.clone(), value.clone());
+            lsm.put(key.clone(), value.clone()).expect("put failed");
+            assert_eq!(lsm.get(&key).unwrap(), Some(value));
+        }
+
+        for (k, v) in memtable.iter() {
+            assert_eq!(lsm.get(k).unwrap(), Some(v.clone()));
+        }
+    }
+
+    #[test]
+    fn test_lsm_put_get_random() {
+        let p = Path::new("test/test_lsm_put_get_random");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = BCATTableManager::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        let mut memtable = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+
+        for _i in 0..TEST_N {
+            let key: String = format!("{}", rng.gen::<i32>());
+            let value: String = format!("{}", rng.gen::<i32>());
+            memtable.insert(key.clone(), value.clone());
+            lsm.put(key.clone(), value.clone()).expect("put failed");
+            assert_eq!(lsm.get(&key).unwrap(), Some(value));
+        }
+
+        for (k, v) in memtable.iter() {
+            // assert_eq!(lsm.get(&k).unwrap(), v.clone());
+            assert_eq!(lsm.get(k).unwrap(), Some(v.clone()));
+        }
+    }
+
+    #[test]
+    fn test_lsm_remove() {
+        let p = Path::new("test/test_lsm_remove");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = BCATTableManager::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+
+        for i in 0..TEST_N {
+            let key = format!("key{}", i);
+            let value = format!("value{}", i);
+            lsm.put(key, value).expect("put failed");
+        }
+
+        for i in 0..TEST_N {
+            let key = format!("key{}", i);
+            lsm.remove(&key).expect("remove failed");
+            assert_eq!(lsm.get(&key).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_lsm_remove_random() {
+        let p = Path::new("test/test_lsm_remove_random");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = BCATTableManager::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+
+        let mut rng = rand::thread_rng();
+
+        for i in 0..TEST_N {
+            let key = i;
+            let value = i;
+            lsm.put(key, value).expect("put failed");
+        }
+
+        let mut keys: Vec<i64> = (0..TEST_N).collect();
+        keys.shuffle(&mut rng);
+
+        for key in keys {
+            lsm.remove(&key).expect("remove failed");
+            assert_eq!(lsm.get(&key).unwrap(), None);
+        }
+
+        // for i in 0..TEST_N {
+        //     lsm.remove(&i).expect("remove failed");
+        //     assert_eq!(lsm.get(&i).unwrap(), None);
+        // }
+    }
+
+    #[test]
+    fn test_lsm_flush() {
+        let p = Path::new("test/test_lsm_flush");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        // entries of keys and values below 126 take four bytes, 62 fill the memtable
+        let options = Options {
+            memtable_max_bytes: 62 * 4,
+            ..Options::default()
+        };
+        let tm = BCATTableManager::new(p, &options).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, options).unwrap();
+
+        // add 64 entries to memtable
+        // check if memtbale is cleared
+        // check if wal is cleared
+        // check if sstable is created
+
+        for i in 0..61 {
+            let key = i;
+            let value = i;
+            lsm.put(key, value).expect("put failed");
+        }
+
+        assert_ne!(lsm.memtable.read().unwrap().len(), 0);
+        lsm.put(61, 61).expect("put failed");
+        assert_eq!(lsm.memtable.read().unwrap().len(), 0);
+        assert_eq!(lsm.wal.lock().file.metadata().unwrap().len(), 0);
+        lsm.wait_for_flush().expect("flush failed");
+        assert!(lsm.table_manager.read().unwrap().tm.tm.tm.sstables[0].exists());
+
+        for i in 0..62 {
+            assert_eq!(lsm.table_manager.read().unwrap().read(&i).unwrap(), Some(i));
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+
+        for i in 62..123 {
+            let key = i;
+            let value = i;
+            lsm.put(key, value).expect("put failed");
+        }
+
+        assert_ne!(lsm.memtable.read().unwrap().len(), 0);
+        lsm.put(123, 123).expect("put failed");
+        assert_eq!(lsm.memtable.read().unwrap().len(), 0);
+        assert_eq!(lsm.wal.lock().file.metadata().unwrap().len(), 0);
+        lsm.wait_for_flush().expect("flush failed");
+        assert!(lsm.table_manager.read().unwrap().tm.tm.tm.sstables[1].exists());
+        assert!(lsm.table_manager.read().unwrap().tm.tm.tm.sstables[0].exists());
+
+        for i in 0..124 {
+            assert_eq!(lsm.table_manager.read().unwrap().read(&i).unwrap(), Some(i));
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_lsm_recovery() {
+        let p = Path::new("test/test_lsm_recovery");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = BCATTableManager::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+
+        for i in 0..63 {
+            lsm.put(i, i).expect("put failed");
+        }
+        let before: Vec<_> = (0..63)
+            .map(|i| lsm.memtable.read().unwrap().get(&i, MAX_SEQ))
+            .collect();
+        drop(lsm);
+
+        let tm2 = BCATTableManager::new(p, &Options::default()).unwrap();
+        let lsm2 = LSMTree::with_table_manager(p.to_path_buf(), tm2, Options::default()).unwrap();
+
+        for i in 0..63 {
+            assert_eq!(lsm2.get(&i).unwrap(), Some(i));
+            assert_eq!(
+                before[i as usize],
+                lsm2.memtable.read().unwrap().get(&i, MAX_SEQ)
+            );
+        }
+    }
+
+    #[test]
+    fn test_lsm_background_flush() {
+        let p = Path::new("test/test_lsm_background_flush");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        // entries of keys and values below 126 take four bytes, 40 fill the memtable
+        let options = Options {
+            memtable_max_bytes: 40 * 4,
+            ..Options::default()
+        };
+        let lsm = LSMTree::<i64, i64, SimpleTableManager<i64, i64>>::open(p, options).unwrap();
+
+        // a held table manager stands in for a slow flush
+        let tm_lock = lsm.table_manager.write().unwrap();
+
+        // two full memtables are queued and writers keep going
+        for i in 0..80 {
+            lsm.put(i, i).expect("put failed");
+        }
+        for i in 0..80 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+
+        // a third one is more than max_immutables, so the writer waits
+        let writer = {
+            let lsm = Arc::clone(&lsm);
+            std::thread::spawn(move || {
+                for i in 80..120 {
+                    lsm.put(i, i).expect("put failed");
+                }
+            })
+        };
+        sleep(Duration::from_millis(200));
+        assert!(!writer.is_finished());
+
+        drop(tm_lock);
+        writer.join().unwrap();
+        lsm.flush_memtable().expect("flush failed");
+
+        let tm = lsm.table_manager.read().unwrap();
+        assert_eq!(tm.sstables.len(), 3);
+        drop(tm);
+        assert_eq!(segments(p).unwrap().len(), 1);
+        for i in 0..120 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+        let all: Vec<(i64, i64)> = lsm
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(all, (0..120).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_lsm_segment_recovery() {
+        let p = Path::new("test/test_lsm_segment_recovery");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        // a full memtable that was never flushed, and the memtable after it
+        for (number, value) in [(0, 1_i64), (1, 2)] {
+            let mut log = Log::new(&segment_path(p, number)).unwrap();
+            for i in 0..TEST_N {
+                let entry = LogEntry {
+                    seq: number * TEST_N as u64 + i as u64 + 1,
+                    key: i,
+                    value: Some(value),
+                };
+                log.append(entry).unwrap();
+            }
+        }
+
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        assert_eq!(lsm.memtable.read().unwrap().len(), TEST_N as usize);
+        // writes go on after the last recovered sequence number
+        assert_eq!(lsm.snapshot().seq, 2 * TEST_N as u64);
+
+        lsm.wait_for_flush().expect("flush failed");
+        assert!(!segment_path(p, 0).exists());
+        assert_eq!(lsm.table_manager.read().unwrap().sstables.len(), 1);
+        for i in 0..TEST_N {
+            assert_eq!(lsm.get(&i).unwrap(), Some(2));
+        }
+    }
+
+    #[test]
+    fn test_wal_archive() {
+        let p = Path::new("test/test_wal_archive");
+        let archive = p.join("archive");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        lsm.set_wal_archive(Some(archive.clone())).unwrap();
+
+        // a few memtables, the last one flushed by hand
+        for i in 0..1000 {
+            lsm.put(i, i).expect("put failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        let archived = segments(&archive).unwrap().len();
+        assert!(archived > 1);
+        assert_eq!(archived, lsm.table_manager.read().unwrap().sstables.len());
+        assert_eq!(segments(p).unwrap().len(), 1);
+
+        // the archive has every write, and rebuilds any point in time
+        let all: Memtable<i64, i64> = replay_segments(&archive, MAX_SEQ).unwrap();
+        assert_eq!(all.len(), 1000);
+        let older: Memtable<i64, i64> = replay_segments(&archive, 300).unwrap();
+        assert_eq!(older.len(), 300);
+        assert!(older.keys().all(|key| key.seq <= 300 && key.key < 300));
+
+        // replaying leaves a torn tail of an archived segment in place
+        let (_, newest) = segments(&archive).unwrap().pop().unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&newest).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+        let len = fs::metadata(&newest).unwrap().len();
+        let torn: Memtable<i64, i64> = replay_segments(&archive, MAX_SEQ).unwrap();
+        assert_eq!(torn, all);
+        assert_eq!(fs::metadata(&newest).unwrap().len(), len);
+
+        // without an archive flushed segments are deleted
+        lsm.set_wal_archive(None).unwrap();
+        for i in 0..300 {
+            lsm.put(i, i + 1).expect("put failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        assert_eq!(segments(&archive).unwrap().len(), archived);
+        assert_eq!(segments(p).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let p = Path::new("test/test_write_batch");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        for i in 0..10 {
+            lsm.put(i, i).expect("put failed");
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.put(10, 10).remove(&3).put(3, 30).remove(&4);
+        batch.put(11, 11);
+        assert_eq!(batch.len(), 5);
+        lsm.write(batch.clone()).expect("write failed");
+        assert_eq!(lsm.get(&3).unwrap(), Some(30));
+        assert_eq!(lsm.get(&4).unwrap(), None);
+        assert_eq!(lsm.get(&10).unwrap(), Some(10));
+        assert_eq!(lsm.get(&11).unwrap(), Some(11));
+
+        batch.clear();
+        assert!(batch.is_empty());
+        lsm.write(batch).expect("write failed");
+
+        // a batch cut short by a crash is dropped as a whole
+        let mut batch = WriteBatch::new();
+        for i in 0..100 {
+            batch.put(i, i + 100);
+        }
+        lsm.write(batch).expect("write failed");
+        let wal_path = lsm.wal.lock().path.clone();
+        drop(lsm);
+
+        let file = fs::OpenOptions::new().write(true).open(&wal_path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        assert_eq!(lsm.get(&0).unwrap(), Some(0));
+        assert_eq!(lsm.get(&3).unwrap(), Some(30));
+        assert_eq!(lsm.get(&4).unwrap(), None);
+        assert_eq!(lsm.get(&11).unwrap(), Some(11));
+        assert_eq!(lsm.get(&99).unwrap(), None);
+    }
+
+    #[test]
+    fn test_wal_sync() {
+        let p = Path::new("test/test_wal_sync");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let open = |sync_mode| {
+            let options = Options {
+                sync_mode,
+                ..Options::default()
+            };
+            let tm = SimpleTableManager::<i64, i64>::new(p, &options).unwrap();
+            LSMTree::with_table_manager(p.to_path_buf(), tm, options).unwrap()
+        };
+        let unsynced = |lsm: &LSMTree<i64, i64>| lsm.wal.lock().unsynced_bytes;
+
+        let lsm = open(SyncMode::Never);
+        lsm.put(0, 0).expect("put failed");
+        assert!(unsynced(&lsm) > 0);
+        lsm.put_with(1, 1, &WriteOptions { sync: true })
+            .expect("put failed");
+        assert_eq!(unsynced(&lsm), 0);
+        drop(lsm);
+
+        let lsm = open(SyncMode::Always);
+        lsm.put(2, 2).expect("put failed");
+        assert_eq!(unsynced(&lsm), 0);
+        drop(lsm);
+
+        let lsm = open(SyncMode::Bytes(64));
+        for i in 3..32 {
+            lsm.put(i, i).expect("put failed");
+            assert!(unsynced(&lsm) < 64);
+        }
+        drop(lsm);
+
+        // synced an interval after the last sync, even with no write after
+        let lsm = open(SyncMode::Interval(Duration::from_millis(200)));
+        lsm.sync_wal().expect("sync failed");
+        lsm.put(32, 32).expect("put failed");
+        assert!(unsynced(&lsm) > 0);
+        sleep(Duration::from_millis(400));
+        assert_eq!(unsynced(&lsm), 0);
+        lsm.put(33, 33).expect("put failed");
+        drop(lsm);
+
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        for i in 0..34 {
+            assert_eq!(lsm.get(&i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let p = Path::new("test/test_snapshot");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let options = Options {
+            compact_threshold: 2,
+            ..Options::default()
+        };
+        let tm = SimpleCompactTableManager::<i64, i64>::new(p, &options).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, options).unwrap();
+
+        for i in 0..TEST_N {
+            lsm.put(i, i).expect("put failed");
+        }
+        let snapshot = lsm.snapshot();
+
+        // overwrite every key and remove every other one
+        for i in 0..TEST_N {
+            lsm.put(i, i + 1).expect("put failed");
+        }
+        for i in (0..TEST_N).step_by(2) {
+            lsm.remove(&i).expect("remove failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+        assert!(!lsm.table_manager.read().unwrap().compacted.is_empty());
+        // so does a manual one
+        lsm.table_manager
+            .write()
+            .unwrap()
+            .compact(lsm.snapshots.smallest())
+            .expect("compact failed");
+
+        for i in 0..TEST_N {
+            assert_eq!(snapshot.get(&i).unwrap(), Some(i));
+            let expected = if i % 2 == 0 { None } else { Some(i + 1) };
+            assert_eq!(lsm.get(&i).unwrap(), expected);
+        }
+        let seen: Vec<(i64, i64)> = snapshot
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(seen, (0..TEST_N).map(|i| (i, i)).collect::<Vec<_>>());
+        let seen: Vec<(i64, i64)> = snapshot
+            .range(10..20)
+            .expect("range failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(seen, (10..20).map(|i| (i, i)).collect::<Vec<_>>());
+
+        // without the snapshot the old versions are compacted away
+        drop(snapshot);
+        assert!(lsm.snapshots.is_empty());
+        let mut tm = lsm.table_manager.write().unwrap();
+        tm.compact(lsm.snapshots.smallest())
+            .expect("compact failed");
+        assert!(tm.compaction_stats.dropped_tombstones > 0);
+        drop(tm);
+
+        let all: Vec<(i64, i64)> = lsm
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<(i64, i64)> = (1..TEST_N).step_by(2).map(|i| (i, i + 1)).collect();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn test_lsm_threads() {
+        let p = Path::new("test/test_lsm_threads");
+
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
+
+        let lsm = LSMTree::<_, _, BCATTableManager<_, _>>::open(p, Options::default()).unwrap();
+        let mut threads = Vec::new();
+
+        for i in 1..=512 {
+            let my_lsm = Arc::clone(&lsm);
+            threads.push(std::thread::spawn(move || {
+                for j in 0..64 {
+                    let (key, value) = (i * 1048 + j, i * 1048 + j);
+                    my_lsm.put(key, value).expect("put failed");
+                    assert_eq!(my_lsm.get(&key).expect("get failed"), Some(value));
+                    my_lsm.remove(&key).expect("remove failed");
+                    assert_eq!(my_lsm.get(&key).unwrap(), None);
+                }
+            }));
+        }
+
+        for j in threads {
+            j.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let p = Path::new("test/test_group_commit");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::<String, String>::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
-        let mut memtable = BTreeMap::new();
+        let options = Options {
+            sync_mode: SyncMode::Always,
+            ..Options::default()
+        };
+        let tm = SimpleTableManager::<i64, i64>::new(p, &options).unwrap();
+        let lsm = Arc::new(LSMTree::with_table_manager(p.to_path_buf(), tm, options).unwrap());
 
-        for i in 0..TEST_N {
-            let key = format!("key{}", i);
-            let value = format!("value{}", i);
-            memtable.insert(key.clone(), value.clone());
-            lsm.put(key.clone(), value.clone()).expect("put failed");
-            assert_eq!(lsm.get(&key), Some(value));
+        // synced puts and batches from many writers land in shared groups
+        let mut threads = Vec::new();
+        for t in 0..16 {
+            let lsm = Arc::clone(&lsm);
+            threads.push(std::thread::spawn(move || {
+                for i in 0..64 {
+                    let key = t * 1024 + i * 2;
+                    if i % 4 == 0 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(key, key).put(key + 1, key + 1);
+                        lsm.write(batch).expect("write failed");
+                    } else {
+                        lsm.put(key, key).expect("put failed");
+                        lsm.put(key + 1, key + 1).expect("put failed");
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
         }
 
-        for (k, v) in memtable.iter() {
-            assert_eq!(lsm.get(&k), Some(v.clone()));
+        // every entry got its own sequence number
+        assert_eq!(lsm.snapshot().seq, 16 * 64 * 2);
+        assert_eq!(lsm.wal.lock().unsynced_bytes, 0);
+        drop(lsm);
+
+        let tm = SimpleTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
+        for t in 0..16 {
+            for key in t * 1024..t * 1024 + 128 {
+                assert_eq!(lsm.get(&key).unwrap(), Some(key));
+            }
         }
     }
 
     #[test]
-    fn test_lsm_put_get_random() {
-        let p = Path::new("test/test_lsm_put_get_random");
+    fn test_lsm_range() {
+        let p = Path::new("test/test_lsm_range");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let tm = BCATTableManager::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
         let mut memtable = BTreeMap::new();
-        let mut rng = rand::thread_rng();
 
-        for _i in 0..TEST_N {
-            let key: String = format!("{}", rng.gen::<i32>());
-            let value: String = format!("{}", rng.gen::<i32>());
-            memtable.insert(key.clone(), value.clone());
-            lsm.put(key.clone(), value.clone()).expect("put failed");
-            assert_eq!(lsm.get(&key), Some(value));
+        for i in 0..TEST_N {
+            lsm.put(i, i).expect("put failed");
+            memtable.insert(i, i);
         }
 
-        for (k, v) in memtable.iter() {
-            // assert_eq!(lsm.get(&k), v.clone());
-            assert_eq!(lsm.get(&k), Some(v.clone()));
+        for i in (0..TEST_N).step_by(3) {
+            lsm.remove(&i).expect("remove failed");
+            memtable.remove(&i);
         }
+
+        for i in (0..TEST_N).step_by(5) {
+            lsm.put(i, i * 10).expect("put failed");
+            memtable.insert(i, i * 10);
+        }
+
+        let all: Vec<(i64, i64)> = lsm
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<(i64, i64)> = memtable.clone().into_iter().collect();
+        assert_eq!(all, expected);
+
+        let some: Vec<(i64, i64)> = lsm
+            .range(100..2000)
+            .expect("range failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<(i64, i64)> = memtable
+            .range(100..2000)
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        assert_eq!(some, expected);
+
+        let some: Vec<(i64, i64)> = lsm
+            .range(..=7)
+            .expect("range failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(some, vec![(0, 0), (1, 1), (2, 2), (4, 4), (5, 50), (7, 7)]);
     }
 
     #[test]
-    fn test_lsm_remove() {
-        let p = Path::new("test/test_lsm_remove");
+    fn test_tm_scan() {
+        let p = Path::new("test/test_tm_scan");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let mut tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
 
-        for i in 0..TEST_N {
-            let key = format!("key{}", i);
-            let value = format!("value{}", i);
-            lsm.put(key.clone(), value.clone()).expect("put failed");
+        for i in 0..32 {
+            let mut memtable = BTreeMap::new();
+            memtable.insert(i, Some(i));
+            memtable.insert(i + 1, None);
+            memtable.insert(100, Some(i));
+            tm.add_table(stamp(memtable, i as u64))
+                .expect("add table failed");
         }
 
-        for i in 0..TEST_N {
-            let key = format!("key{}", i);
-            lsm.remove(&key).expect("remove failed");
-            assert_eq!(lsm.get(&key), None);
-        }
+        let scan = tm
+            .scan(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)
+            .expect("scan failed");
+        let entries: Vec<(i64, Option<i64>)> =
+            visible(scan, MAX_SEQ).collect::<Result<_>>().unwrap();
+
+        let mut expected: Vec<(i64, Option<i64>)> = (0..32).map(|i| (i, Some(i))).collect();
+        expected.push((32, None));
+        expected.push((100, Some(31)));
+        assert_eq!(entries, expected);
     }
 
     #[test]
-    fn test_lsm_remove_random() {
-        let p = Path::new("test/test_lsm_remove_random");
+    fn test_compact_tables() {
+        let p = Path::new("test/test_compact_tables");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let mut manifest = Manifest::open(p).unwrap();
+        let options = Options::default();
+        let mut memtable = BTreeMap::new();
+        let mut inputs = Vec::new();
+
+        // three overlapping versions of the same keys, newest last
+        for version in 0..3 {
+            let table: BTreeMap<i64, Option<i64>> = (0..TEST_N)
+                .filter(|i| i % (version + 1) == 0)
+                .map(|i| (i, Some(i * 10 + version)))
+                .collect();
+            memtable.extend(table.clone());
+
+            let path = manifest.new_table_path(0);
+            write_table(&path, stamp(table, version as u64), &options).unwrap();
+            inputs.insert(0, path);
+        }
 
-        let mut rng = rand::thread_rng();
+        let target_file_size = 4 * 1024;
+        let (outputs, stats) =
+            compact_tables::<i64, i64>(&inputs, target_file_size, false, MAX_SEQ, &options, || {
+                manifest.new_table_path(1)
+            })
+            .unwrap();
+        assert!(outputs.len() > 1);
+        assert_eq!(stats.output_entries, memtable.len() as u64);
+        assert_eq!(
+            stats.input_entries,
+            stats.output_entries + stats.dropped_versions
+        );
 
-        for i in 0..TEST_N {
-            let key = i;
-            let value = i;
-            lsm.put(key.clone(), value.clone()).expect("put failed");
+        for pair in outputs.windows(2) {
+            assert!(pair[0].last_key < pair[1].first_key);
         }
 
-        let mut keys: Vec<i64> = (0..TEST_N).collect();
-        keys.shuffle(&mut rng);
-
-        for key in keys {
-            lsm.remove(&key).expect("remove failed");
-            assert_eq!(lsm.get(&key), None);
+        let mut entries = Vec::new();
+        for output in outputs.iter() {
+            let table = Table::<InternalKey<i64>, i64>::open(&output.path).unwrap();
+            assert!(table.index.len() <= 2);
+            entries.extend(
+                table
+                    .iter(Bound::Unbounded, Bound::Unbounded)
+                    .map(|entry| entry.map(|(key, value)| (key.key, value)).unwrap()),
+            );
         }
+        assert_eq!(entries, memtable.into_iter().collect::<Vec<_>>());
 
-        // for i in 0..TEST_N {
-        //     lsm.remove(&i).expect("remove failed");
-        //     assert_eq!(lsm.get(&i), None);
-        // }
+        install_compaction(&mut manifest, 1, &outputs, &inputs).unwrap();
+        assert!(inputs.iter().all(|input| !input.exists()));
+        assert_eq!(manifest.level(0), Vec::<std::path::PathBuf>::new());
+        assert_eq!(manifest.level(1).len(), outputs.len());
     }
 
     #[test]
-    fn test_lsm_flush() {
-        let p = Path::new("test/test_lsm_flush");
+    fn test_compact_bottommost() {
+        let p = Path::new("test/test_compact_bottommost");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let mut manifest = Manifest::open(p).unwrap();
+        let options = Options::default();
 
-        // add 64 entries to memtable
-        // check if memtbale is cleared
-        // check if wal is cleared
-        // check if sstable is created
+        // every key written twice, then every other key removed
+        let old: BTreeMap<i64, Option<i64>> = (0..TEST_N).map(|i| (i, Some(i))).collect();
+        let new: BTreeMap<i64, Option<i64>> = (0..TEST_N).map(|i| (i, Some(i + 1))).collect();
+        let removed: BTreeMap<i64, Option<i64>> =
+            (0..TEST_N).step_by(2).map(|i| (i, None)).collect();
 
-        for i in 0..255 {
-            let key = i;
-            let value = i;
-            lsm.put(key, value).expect("put failed");
+        let mut inputs = Vec::new();
+        for (seq, table) in [old, new, removed.clone()].into_iter().enumerate() {
+            let path = manifest.new_table_path(0);
+            write_table(&path, stamp(table, seq as u64), &options).unwrap();
+            inputs.insert(0, path);
         }
 
-        assert_ne!(lsm.memtable.read().unwrap().len(), 0);
-        lsm.put(256, 256).expect("put failed");
-        assert_eq!(lsm.memtable.read().unwrap().len(), 0);
-        assert_eq!(lsm.wal.lock().unwrap().file.metadata().unwrap().len(), 0);
-        assert!(lsm.table_manager.lock().unwrap().tm.tm.sstables[0].exists());
+        let (outputs, stats) =
+            compact_tables::<i64, i64>(&inputs, u64::MAX, true, MAX_SEQ, &options, || {
+                manifest.new_table_path(1)
+            })
+            .unwrap();
+        assert_eq!(
+            stats.input_entries,
+            2 * TEST_N as u64 + removed.len() as u64
+        );
+        assert_eq!(stats.dropped_versions, TEST_N as u64 + removed.len() as u64);
+        assert_eq!(stats.dropped_tombstones, removed.len() as u64);
+        assert_eq!(stats.output_entries, TEST_N as u64 - removed.len() as u64);
+        assert!(stats.reclaimed_bytes() > 0);
+
+        let table = Table::<InternalKey<i64>, i64>::open(&outputs[0].path).unwrap();
+        let entries: Vec<(i64, Option<i64>)> = table
+            .iter(Bound::Unbounded, Bound::Unbounded)
+            .map(|entry| entry.map(|(key, value)| (key.key, value)))
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<(i64, Option<i64>)> = (0..TEST_N)
+            .filter(|i| i % 2 == 1)
+            .map(|i| (i, Some(i + 1)))
+            .collect();
+        assert_eq!(entries, expected);
+    }
 
-        for i in 0..255 {
-            assert_eq!(lsm.table_manager.lock().unwrap().read(&i), Some(i));
-            assert_eq!(lsm.get(&i), Some(i));
-        }
+    #[test]
+    fn test_compact_drops_tombstones() {
+        let p = Path::new("test/test_compact_drops_tombstones");
 
-        for i in 256..511 {
-            let key = i;
-            let value = i;
-            lsm.put(key, value).expect("put failed");
-        }
+        let _ = fs::remove_dir_all(p);
+        let _ = fs::create_dir(p);
 
-        assert_ne!(lsm.memtable.read().unwrap().len(), 0);
-        lsm.put(255, 255).expect("put failed");
-        assert_eq!(lsm.memtable.read().unwrap().len(), 0);
-        assert_eq!(lsm.wal.lock().unwrap().file.metadata().unwrap().len(), 0);
-        assert!(lsm.table_manager.lock().unwrap().tm.tm.sstables[1].exists());
-        assert!(lsm.table_manager.lock().unwrap().tm.tm.sstables[0].exists());
+        let tm = SimpleCompactTableManager::<i64, i64>::new(p, &Options::default()).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, Options::default()).unwrap();
 
-        for i in 0..256 {
-            assert_eq!(lsm.table_manager.lock().unwrap().read(&i), Some(i));
-            assert_eq!(lsm.get(&i), Some(i));
+        for i in 0..TEST_N * 4 {
+            lsm.put(i, i).expect("put failed");
+        }
+        for i in 0..TEST_N * 4 {
+            lsm.remove(&i).expect("remove failed");
+        }
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+        let mut tm = lsm.table_manager.write().unwrap();
+        tm.compact(lsm.snapshots.smallest())
+            .expect("compact failed");
+        assert!(tm.compaction_stats.dropped_tombstones > 0);
+        assert!(tm.compaction_stats.reclaimed_bytes() > 0);
+        assert!(tm.compacted.is_empty());
+        drop(tm);
+
+        for i in 0..TEST_N * 4 {
+            assert_eq!(lsm.get(&i).unwrap(), None);
         }
     }
 
     #[test]
-    fn test_lsm_recovery() {
-        let p = Path::new("test/test_lsm_recovery");
+    fn test_leveled_tm() {
+        let p = Path::new("test/test_leveled_tm");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let mut tm = BCATTableManager::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let options = Options {
+            level1_max_bytes: 16 * 1024,
+            level_target_file_size: 8 * 1024,
+            ..Options::default()
+        };
+        let tm = LeveledCompactTableManager::<i64, String>::new(p, &options).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, options.clone()).unwrap();
+        let mut memtable = BTreeMap::new();
+        let mut rng = rand::thread_rng();
 
-        for i in 0..63 {
-            lsm.put(i, i).expect("put failed");
+        let mut keys: Vec<i64> = (0..TEST_N * 4).collect();
+        keys.shuffle(&mut rng);
+        for key in keys.iter() {
+            let value = format!("value{}", key);
+            lsm.put(*key, value.clone()).expect("put failed");
+            memtable.insert(*key, value);
+        }
+        for key in keys.iter().step_by(3) {
+            lsm.remove(key).expect("remove failed");
+            memtable.remove(key);
         }
 
-        let mut tm2 = BCATTableManager::new(p);
-        let lsm2 = LSMTree::new(p.to_path_buf(), &mut tm2);
+        lsm.compact().expect("compact failed");
+        {
+            let tm = lsm.table_manager.read().unwrap();
+            assert!(tm.tm.sstables.len() < tm.level0_threshold);
+            assert!(!tm.levels[1].is_empty());
 
-        for i in 0..63 {
-            assert_eq!(lsm2.get(&i), Some(i));
-            assert_eq!(
-                lsm.memtable.read().unwrap().get(&i),
-                lsm2.memtable.read().unwrap().get(&i)
-            );
+            for level in 1..tm.levels.len() {
+                assert!(tm.level_bytes(level) <= tm.max_bytes(level));
+            }
+            for tables in tm.levels.iter() {
+                for pair in tables.windows(2) {
+                    assert!(pair[0].last_key < pair[1].first_key);
+                }
+            }
+        }
+
+        for key in 0..TEST_N * 4 {
+            assert_eq!(lsm.get(&key).unwrap(), memtable.get(&key).cloned());
+        }
+
+        let all: Vec<(i64, String)> = lsm
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(all, memtable.clone().into_iter().collect::<Vec<_>>());
+
+        let some: Vec<(i64, String)> = lsm
+            .range(1000..=5000)
+            .expect("range failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<(i64, String)> = memtable
+            .range(1000..=5000)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        assert_eq!(some, expected);
+
+        // levels come back from the manifest
+        lsm.flush_memtable().expect("flush failed");
+        lsm.compact().expect("compact failed");
+        let levels = lsm.table_manager.read().unwrap().levels.clone();
+        drop(lsm);
+        let tm = LeveledCompactTableManager::<i64, String>::new(p, &options).unwrap();
+        assert_eq!(tm.levels, levels);
+        for key in (0..TEST_N * 4).step_by(7) {
+            assert_eq!(tm.read(&key).unwrap(), memtable.get(&key).cloned());
+        }
+
+        // a manual compaction keeps the versions a live snapshot reads
+        let mut tm = tm;
+        let seq = tm.last_sequence();
+        for round in 1..=tm.level0_threshold as u64 {
+            let memtable: Memtable<i64, String> = (0..100)
+                .map(|key| {
+                    let key = InternalKey::new(key, seq + round * 100 + key as u64);
+                    (key, Some(format!("round{}", round)))
+                })
+                .collect();
+            tm.add_table(memtable).unwrap();
+        }
+        let snapshot = seq + 100 + 99;
+        tm.compact(snapshot).expect("compact failed");
+        assert!(tm.tm.sstables.is_empty());
+        for key in 0..100 {
+            let expected = format!("round{}", tm.level0_threshold);
+            assert_eq!(tm.read(&key).unwrap(), Some(expected));
+            let old = tm.read_at(&key, snapshot).unwrap();
+            assert_eq!(old, Some("round1".to_string()));
         }
     }
 
     #[test]
-    fn test_lsm_threads() {
-        let p = Path::new("test/test_lsm_threads");
+    fn test_compaction_scheduler() {
+        let p = Path::new("test/test_compaction_scheduler");
 
         let _ = fs::remove_dir_all(p);
         let _ = fs::create_dir(p);
 
-        let temp_box = Box::new(BCATTableManager::new(p));
-        let tm = Box::leak(temp_box);
-        let lsm = Arc::new(LSMTree::new(p.to_path_buf(), tm));
-        let mut threads = Vec::new();
+        let options = Options {
+            level1_max_bytes: 16 * 1024,
+            level_target_file_size: 8 * 1024,
+            compaction_threads: 4,
+            ..Options::default()
+        };
+        let lsm =
+            LSMTree::<i64, i64, LeveledCompactTableManager<i64, i64>>::open(p, options).unwrap();
 
-        for i in 1..=512 {
-            let my_lsm = Arc::clone(&lsm);
+        // writers and readers run while tables are compacted underneath them
+        let mut threads = Vec::new();
+        for t in 0..4 {
+            let lsm = Arc::clone(&lsm);
             threads.push(std::thread::spawn(move || {
-                for j in 0..64 {
-                    let (key, value) = (i * 1048 + j, i * 1048 + j);
-                    my_lsm.put(key, value).expect("put failed");
-                    assert_eq!(my_lsm.get(&key).expect("get failed"), value);
-                    my_lsm.remove(&key).expect("remove failed");
-                    assert_eq!(my_lsm.get(&key), None);
+                for i in 0..TEST_N {
+                    let key = i * 4 + t;
+                    lsm.put(key, key).expect("put failed");
+                    if i % 128 == 0 {
+                        for earlier in (t..key).step_by(4 * 31) {
+                            assert_eq!(lsm.get(&earlier).unwrap(), Some(earlier));
+                        }
+                    }
                 }
             }));
         }
+        for thread in threads {
+            thread.join().unwrap();
+        }
 
-        for j in threads {
-            j.join().unwrap();
+        lsm.compact().expect("compact failed");
+        {
+            let tm = lsm.table_manager.read().unwrap();
+            assert!(tm.compacting.is_empty());
+            assert!(tm.compaction_stats.input_entries > 0);
+            assert!(tm.tm.sstables.len() < tm.level0_threshold);
+            for level in 1..tm.levels.len() {
+                assert!(tm.level_bytes(level) <= tm.max_bytes(level));
+            }
+            for tables in tm.levels.iter() {
+                for pair in tables.windows(2) {
+                    assert!(pair[0].last_key < pair[1].first_key);
+                }
+            }
         }
+
+        for key in 0..TEST_N * 4 {
+            assert_eq!(lsm.get(&key).unwrap(), Some(key));
+        }
+        let all: Vec<(i64, i64)> = lsm
+            .iter()
+            .expect("iter failed")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(all, (0..TEST_N * 4).map(|i| (i, i)).collect::<Vec<_>>());
     }
 
     fn fillseq<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        fillseq_with::<TM, BTreeMemTable<String, String>>(p, n)
+    }
+
+    fn fillseq_with<TM: TableManager<String, String>, MT: MemTable<String, String>>(
+        p: &Path,
+        n: i64,
+    ) -> Result<()> {
+        let lsm = LSMTree::<String, String, TM, MT>::open(p, Options::default()).unwrap();
 
         for i in 0..n {
             let key = format!("{}", i);
@@ -444,21 +2446,40 @@ mod tests {
         Ok(())
     }
 
+    fn fillsync<TM: TableManager<String, String>>(p: &Path, n: i64, mode: SyncMode) -> Result<()> {
+        let options = Options {
+            sync_mode: mode,
+            ..Options::default()
+        };
+        let tm = TM::new(p, &options).unwrap();
+        let lsm = LSMTree::with_table_manager(p.to_path_buf(), tm, options).unwrap();
+        let mut rng = rand::thread_rng();
+
+        let mut keys: Vec<i64> = (0..n).collect();
+        keys.shuffle(&mut rng);
+
+        for key in keys {
+            let key = format!("{}", key);
+            let value = key.clone();
+            lsm.put(key, value)?;
+        }
+
+        Ok(())
+    }
+
     fn readseq<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
 
         for i in 0..n {
             let key = format!("{}", i);
-            lsm.get(&key);
+            lsm.get(&key)?;
         }
 
         Ok(())
     }
 
     fn deleteseq<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
 
         for i in 0..n {
             let key = format!("{}", i);
@@ -469,8 +2490,14 @@ mod tests {
     }
 
     fn fillrand<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        fillrand_with::<TM, BTreeMemTable<String, String>>(p, n)
+    }
+
+    fn fillrand_with<TM: TableManager<String, String>, MT: MemTable<String, String>>(
+        p: &Path,
+        n: i64,
+    ) -> Result<()> {
+        let lsm = LSMTree::<String, String, TM, MT>::open(p, Options::default()).unwrap();
         let mut rng = rand::thread_rng();
 
         let mut keys: Vec<i64> = (0..n).collect();
@@ -486,8 +2513,7 @@ mod tests {
     }
 
     fn readrand<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
         let mut rng = rand::thread_rng();
 
         let mut keys: Vec<i64> = (0..n).collect();
@@ -495,15 +2521,14 @@ mod tests {
 
         for key in keys {
             let key = format!("{}", key);
-            lsm.get(&key);
+            lsm.get(&key)?;
         }
 
         Ok(())
     }
 
     fn deleterand<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
         let mut rng = rand::thread_rng();
 
         let mut keys: Vec<i64> = (0..n).collect();
@@ -518,21 +2543,18 @@ mod tests {
     }
 
     fn _readreverse<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
 
         for i in (0..n).rev() {
             let key = format!("{}", i);
-            let value = format!("{}", i);
-            lsm.get(&key);
+            lsm.get(&key)?;
         }
 
         Ok(())
     }
 
     fn overwrite<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
         let mut rng = rand::thread_rng();
 
         let mut keys: Vec<i64> = (0..n).collect();
@@ -548,20 +2570,18 @@ mod tests {
     }
 
     fn readmissing<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
 
         for i in n..n * 2 {
             let key = format!("{}", i);
-            lsm.get(&key);
+            lsm.get(&key)?;
         }
 
         Ok(())
     }
 
     fn readhot<TM: TableManager<String, String>>(p: &Path, n: i64) -> Result<()> {
-        let mut tm = TM::new(p);
-        let lsm = LSMTree::new(p.to_path_buf(), &mut tm);
+        let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
         let mut rng = rand::thread_rng();
 
         let mut keys: Vec<i64> = (0..n).collect();
@@ -571,7 +2591,7 @@ mod tests {
         for _ in 0..n {
             let index = keys.get(rng.gen_range(0..(n / 100) as usize)).unwrap();
             let key = format!("{}", index);
-            lsm.get(&key);
+            lsm.get(&key)?;
         }
         Ok(())
     }
@@ -736,7 +2756,7 @@ mod tests {
         let mut benchmark_space_results = vec![name.clone()];
 
         for benchmark in benchmarks_fill {
-            println!("{} {}", name.clone(), format!("{:?}", benchmark));
+            println!("{} {:?}", name, benchmark);
             let mut total_time = 0;
             let mut total_space = 0;
             for _ in 0..iterations {
@@ -757,7 +2777,7 @@ mod tests {
         }
 
         for benchmark in benchmarks {
-            println!("{} {}", name.clone(), format!("{:?}", benchmark));
+            println!("{} {:?}", name, benchmark);
             let mut total_time = 0;
             let mut total_space = 0;
             for _ in 0..iterations {
@@ -845,6 +2865,142 @@ mod tests {
             &mut time_wtr,
             &mut space_wtr,
         );
+        benchmark::<LeveledCompactTableManager<String, String>>(
+            "leveled".to_string(),
+            &mut time_wtr,
+            &mut space_wtr,
+        );
+    }
+
+    fn memtable_benchmark<MT: MemTable<String, String>>(name: String, time_wtr: &mut Writer<File>) {
+        let n = 10_000;
+        let iterations = 5;
+        let p = Path::new("test/benchmark_memtable");
+
+        let benchmarks = [
+            fillseq_with::<SimpleTableManager<String, String>, MT>,
+            fillrand_with::<SimpleTableManager<String, String>, MT>,
+        ];
+        let mut benchmark_time_results = vec![name.clone()];
+
+        for benchmark in benchmarks {
+            println!("{} {:?}", name, benchmark);
+            let mut total_time = 0;
+            for _ in 0..iterations {
+                let _ = fs::remove_dir_all(p);
+                let _ = fs::create_dir(p);
+
+                let start = SystemTime::now();
+                benchmark(p, n).expect("benchmark failed");
+                let end = SystemTime::now();
+                total_time += end.duration_since(start).unwrap().as_millis();
+            }
+            let avg_time = total_time as f64 / iterations as f64;
+            benchmark_time_results.push(format!("{}", avg_time));
+        }
+
+        time_wtr
+            .write_record(&benchmark_time_results)
+            .expect("CSV write failed");
+        time_wtr.flush().expect("CSV flush failed");
+    }
+
+    #[test]
+    fn run_benchmark_memtable() {
+        let mut time_wtr = csv::Writer::from_path("test/benchmark_memtable_time.csv").unwrap();
+
+        let benchmark_header = ["memtable", "fillseq", "fillrand"];
+        time_wtr
+            .write_record(benchmark_header)
+            .expect("CSV write failed");
+        time_wtr.flush().expect("CSV flush failed");
+
+        memtable_benchmark::<BTreeMemTable<String, String>>("btree".to_string(), &mut time_wtr);
+        memtable_benchmark::<SkipListMemTable<String, String>>(
+            "skiplist".to_string(),
+            &mut time_wtr,
+        );
+        memtable_benchmark::<VectorMemTable<String, String>>("vector".to_string(), &mut time_wtr);
+    }
+
+    fn sync_benchmark<TM: TableManager<String, String>>(name: String, time_wtr: &mut Writer<File>) {
+        // syncing is slow, so a hundredth of the other benchmarks
+        let n = 100;
+        let iterations = 5;
+        let p = Path::new("test/benchmark_sync");
+
+        let modes = [
+            SyncMode::Never,
+            SyncMode::Always,
+            SyncMode::Interval(Duration::from_millis(10)),
+            SyncMode::Bytes(4 * 1024),
+        ];
+        let mut benchmark_time_results = vec![name.clone()];
+
+        for mode in modes {
+            println!("{} fillsync {:?}", name, mode);
+            let mut total_time = 0;
+            for _ in 0..iterations {
+                let _ = fs::remove_dir_all(p);
+                let _ = fs::create_dir(p);
+
+                let start = SystemTime::now();
+                fillsync::<TM>(p, n, mode).expect("benchmark failed");
+                let end = SystemTime::now();
+                total_time += end.duration_since(start).unwrap().as_millis();
+            }
+            let avg_time = total_time as f64 / iterations as f64;
+            benchmark_time_results.push(format!("{}", avg_time));
+        }
+
+        time_wtr
+            .write_record(&benchmark_time_results)
+            .expect("CSV write failed");
+        time_wtr.flush().expect("CSV flush failed");
+    }
+
+    #[test]
+    fn run_benchmark_sync() {
+        let mut time_wtr = csv::Writer::from_path("test/benchmark_sync_time.csv").unwrap();
+
+        let benchmark_header = ["tablemanager", "nosync", "sync", "interval", "bytes"];
+        time_wtr
+            .write_record(benchmark_header)
+            .expect("CSV write failed");
+        time_wtr.flush().expect("CSV flush failed");
+
+        sync_benchmark::<SimpleTableManager<String, String>>("simple".to_string(), &mut time_wtr);
+        sync_benchmark::<BCATTableManager<String, String>>("bcat".to_string(), &mut time_wtr);
+        sync_benchmark::<LeveledCompactTableManager<String, String>>(
+            "leveled".to_string(),
+            &mut time_wtr,
+        );
+    }
+
+    // milliseconds `num_threads` writers take to put `n` keys between them
+    fn multithread_put<TM: TableManager<String, String>>(
+        lsm: &Arc<LSMTree<String, String, TM>>,
+        num_threads: i64,
+        n: i64,
+    ) -> u128 {
+        let mut threads = Vec::new();
+        let start = SystemTime::now();
+        for i in 0..num_threads {
+            let my_lsm = Arc::clone(lsm);
+            threads.push(std::thread::spawn(move || {
+                for j in 0..(n / num_threads) {
+                    let key = format!("{}", i * (n / num_threads) + j);
+                    let value = format!("{}", i * (n / num_threads) + j);
+                    my_lsm.put(key, value).expect("put failed");
+                }
+            }))
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let end = SystemTime::now();
+        end.duration_since(start).unwrap().as_millis()
     }
 
     fn multithread_benchmark<TM: TableManager<String, String>>(
@@ -858,35 +3014,14 @@ mod tests {
         let mut benchmark_time_results = vec![format!("{}", num_threads)];
         let mut total_put_time = 0;
         let mut total_get_time = 0;
+        let mut total_sync_put_time = 0;
 
         for _ in 0..iterations {
             let _ = fs::remove_dir_all(p);
             let _ = fs::create_dir(p);
 
-            let temp_box = Box::new(BCATTableManager::new(p));
-            let tm = Box::leak(temp_box);
-            // let mut tm = TM::<String,String>::new(p);
-            // let mut tm = BCATTableManager::<String, String>::new(p);
-            let lsm = Arc::new(LSMTree::new(p.to_path_buf(), tm));
-            // let mut lsm_rc = Arc::new(lsm);
-            let mut threads = Vec::new();
-            let start = SystemTime::now();
-            for i in 0..num_threads {
-                let my_lsm = Arc::clone(&lsm);
-                threads.push(std::thread::spawn(move || {
-                    for j in 0..(n / num_threads) {
-                        let key = format!("{}", i * (n / num_threads) + j);
-                        let value = format!("{}", i * (n / num_threads) + j);
-                        my_lsm.put(key, value).expect("put failed");
-                    }
-                }))
-            }
-            for thread in threads {
-                thread.join().unwrap();
-            }
-
-            let end = SystemTime::now();
-            total_put_time += end.duration_since(start).unwrap().as_millis();
+            let lsm = LSMTree::<String, String, TM>::open(p, Options::default()).unwrap();
+            total_put_time += multithread_put(&lsm, num_threads, n);
 
             let mut threads = Vec::new();
 
@@ -905,10 +3040,26 @@ mod tests {
             }
             let end = SystemTime::now();
             total_get_time += end.duration_since(start).unwrap().as_millis();
+            drop(lsm);
+
+            // every write is synced, so writers committing together share a sync
+            let _ = fs::remove_dir_all(p);
+            let _ = fs::create_dir(p);
+
+            let options = Options {
+                sync_mode: SyncMode::Always,
+                ..Options::default()
+            };
+            let lsm = LSMTree::<String, String, TM>::open(p, options).unwrap();
+            total_sync_put_time += multithread_put(&lsm, num_threads, n / 10);
         }
 
         benchmark_time_results.push(format!("{}", total_put_time as f64 / iterations as f64));
         benchmark_time_results.push(format!("{}", total_get_time as f64 / iterations as f64));
+        benchmark_time_results.push(format!(
+            "{}",
+            total_sync_put_time as f64 / iterations as f64
+        ));
 
         time_wtr
             .write_record(&benchmark_time_results)
@@ -920,7 +3071,7 @@ mod tests {
     fn run_multithread_benchmark() {
         let mut time_wtr = csv::Writer::from_path("test/benchmark_multithread_time.csv").unwrap();
 
-        let benchmark_header = ["num of threads", "write", "read"];
+        let benchmark_header = ["num of threads", "write", "read", "write sync"];
         time_wtr
             .write_record(benchmark_header)
             .expect("CSV write failed");
@@ -1,106 +1,686 @@
+use crate::column_family::DEFAULT_FAMILY;
+use crate::error::{Error, Result};
+use crate::internal_key::{InternalKey, Memtable};
 use crate::lsm_forest::LogSerial;
-use anyhow::Result;
+use crate::options::{SyncMode, WalRecoveryMode};
 use bincode::{Decode, Encode};
 use core::fmt::Debug;
-use crc32fast;
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::{
-    collections::BTreeMap,
     fs::{self, File},
-    hash::{DefaultHasher, Hash, Hasher},
-    io::BufReader,
     path::Path,
     path::PathBuf,
+    time::Instant,
 };
 
+// the wal is a sequence of 32 KiB blocks. each record is bincode encoded and
+// split into fragments that do not cross a block boundary, each framed as
+//
+//   [crc32 of type and data: u32] [data length: u16] [type: u8] [data]
+//
+// where the type tells whether the fragment is a whole record or its first,
+// a middle or its last part. a block with less than a header left is zero
+// padded. a crash during append leaves a torn record at the end of the
+// segment, which recovery cuts off; corruption before it depends on the
+// recovery mode. since every block starts a fresh fragment, reading can
+// resume at the next block after a corrupt one.
+
+pub const LOG_BLOCK_SIZE: usize = 32 * 1024;
+pub const LOG_HEADER_SIZE: usize = 4 + 2 + 1;
+
+const FULL: u8 = 1;
+const FIRST: u8 = 2;
+const MIDDLE: u8 = 3;
+const LAST: u8 = 4;
+
 pub struct Log {
     pub file: File,
     pub path: PathBuf,
+    // written since the last sync, and may not be on disk yet
+    pub unsynced_bytes: u64,
+    pub last_sync: Instant,
+    // bytes already used in the current block
+    block_offset: usize,
+    // a segment that is only replayed, never appended to or cut off
+    read_only: bool,
+    pub recovery_report: RecoveryReport,
+}
+
+/// Corruption that recovery got past under a `WalRecoveryMode` that does
+/// not fail on it, the records it covered being lost.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryReport {
+    // one message per corruption, naming the segment and offset
+    pub corruptions: Vec<String>,
+}
+
+impl RecoveryReport {
+    pub fn is_empty(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+
+    pub fn extend(&mut self, other: &RecoveryReport) {
+        self.corruptions.extend(other.corruptions.iter().cloned());
+    }
 }
 
 #[derive(Encode, Decode, Debug)]
 pub struct LogEntry<K: LogSerial, V: LogSerial> {
-    pub crc: u32,
+    pub seq: u64,
     pub key: K,
     pub value: V,
 }
 
-impl<K: LogSerial, V: LogSerial> LogEntry<K, Option<V>> {
-    pub fn compute_crc(&self) -> u32 {
-        let mut hasher = crc32fast::Hasher::new();
-        let mut h = DefaultHasher::new();
-        self.key.hash(&mut h);
-        self.value.hash(&mut h);
-        hasher.update(&h.finish().to_le_bytes());
+/// Several entries written to the log as one record, so recovery applies
+/// either all of them or none. Entry `i` has sequence number `seq + i`.
+#[derive(Encode, Decode, Debug)]
+pub struct LogBatch<K: LogSerial, V: LogSerial> {
+    pub seq: u64,
+    pub entries: Vec<(K, Option<V>)>,
+}
+
+impl<K: LogSerial, V: LogSerial> LogBatch<K, V> {
+    pub fn new(seq: u64, entries: Vec<(K, Option<V>)>) -> LogBatch<K, V> {
+        LogBatch { seq, entries }
+    }
+}
+
+/// A batch whose entries each name the column family they go to, written by
+/// `DB` to the wal its families share.
+#[derive(Encode, Decode, Debug)]
+pub struct LogFamilyBatch<K: LogSerial, V: LogSerial> {
+    pub seq: u64,
+    pub entries: Vec<(u32, K, Option<V>)>,
+}
+
+impl<K: LogSerial, V: LogSerial> LogFamilyBatch<K, V> {
+    pub fn new(seq: u64, entries: Vec<(u32, K, Option<V>)>) -> LogFamilyBatch<K, V> {
+        LogFamilyBatch { seq, entries }
+    }
+}
+
+#[derive(Encode, Decode, Debug)]
+pub enum LogRecord<K: LogSerial, V: LogSerial> {
+    Entry(LogEntry<K, Option<V>>),
+    Batch(LogBatch<K, V>),
+    Families(LogFamilyBatch<K, V>),
+}
+
+impl<K: LogSerial, V: LogSerial> LogRecord<K, V> {
+    /// Entries of the record stamped with their sequence numbers.
+    pub fn into_entries(self) -> Vec<(InternalKey<K>, Option<V>)> {
+        self.into_family_entries()
+            .into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect()
+    }
+
+    /// Entries of the record with the column family each goes to, the
+    /// default one unless the record names them.
+    pub fn into_family_entries(self) -> Vec<(u32, InternalKey<K>, Option<V>)> {
+        match self {
+            LogRecord::Entry(entry) => vec![(
+                DEFAULT_FAMILY,
+                InternalKey::new(entry.key, entry.seq),
+                entry.value,
+            )],
+            LogRecord::Batch(batch) => {
+                let seq = batch.seq;
+                batch
+                    .entries
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (key, value))| {
+                        (DEFAULT_FAMILY, InternalKey::new(key, seq + i as u64), value)
+                    })
+                    .collect()
+            }
+            LogRecord::Families(batch) => {
+                let seq = batch.seq;
+                batch
+                    .entries
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (family, key, value))| {
+                        (family, InternalKey::new(key, seq + i as u64), value)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Path of WAL segment `number` in `dir`.
+pub fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("wal_{:08}.log", number))
+}
+
+/// WAL segments in `dir`, oldest first.
+pub fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let number = name
+            .strip_prefix("wal_")
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|number| number.parse::<u64>().ok());
+        if let Some(number) = number {
+            segments.push((number, path));
+        }
+    }
+    segments.sort();
+
+    Ok(segments)
+}
+
+/// Why reading a log stopped before its end.
+enum Fault {
+    // the log ends partway through a record
+    Tail,
+    // unreadable bytes, reading may resume at `resume`
+    Corruption { message: String, resume: usize },
+}
+
+fn fragment_crc(kind: u8, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[kind]);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Reads the fragment at `pos`, which has a header's worth of block left.
+fn read_fragment(buf: &[u8], pos: usize) -> std::result::Result<(u8, &[u8]), Fault> {
+    let rest = &buf[pos..];
+    // zeros are what a crash after the file grew but before the data landed leaves
+    if rest.len() < LOG_HEADER_SIZE || rest.iter().all(|byte| *byte == 0) {
+        return Err(Fault::Tail);
+    }
+
+    let crc = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+    let len = u16::from_le_bytes(rest[4..6].try_into().unwrap()) as usize;
+    let kind = rest[6];
+    let block_end = (pos / LOG_BLOCK_SIZE + 1) * LOG_BLOCK_SIZE;
+    let corruption = |message: &str| Fault::Corruption {
+        message: format!("{} at offset {}", message, pos),
+        resume: block_end,
+    };
+
+    let end = LOG_HEADER_SIZE + len;
+    if pos + end > block_end {
+        return Err(corruption("wal fragment crosses a block boundary"));
+    }
+    if rest.len() < end {
+        return Err(Fault::Tail);
+    }
+    let data = &rest[LOG_HEADER_SIZE..end];
+    if fragment_crc(kind, data) != crc {
+        if rest.len() == end {
+            return Err(Fault::Tail);
+        }
+        return Err(corruption("wal checksum mismatch"));
+    }
+
+    Ok((kind, data))
+}
+
+/// Reads the records of the log in `buf`, passing each one to `apply`, and
+/// the corruption `mode` gets past to `corruptions`. Returns how much of
+/// `buf` to keep, less than its length if the end is cut off.
+pub fn read_log(
+    buf: &[u8],
+    mode: WalRecoveryMode,
+    mut apply: impl FnMut(&[u8]) -> Result<()>,
+    corruptions: &mut Vec<String>,
+) -> Result<usize> {
+    let mut pos = 0;
+    // end of the last record read whole
+    let mut valid_end = 0;
+    // record being put together from its fragments, with where it starts
+    let mut pending: Option<(usize, Vec<u8>)> = None;
 
-        hasher.finalize()
+    while pos < buf.len() {
+        let block_left = LOG_BLOCK_SIZE - pos % LOG_BLOCK_SIZE;
+        if block_left < LOG_HEADER_SIZE {
+            // padding
+            pos += block_left;
+            continue;
+        }
+
+        let start = pos;
+        let fault = match read_fragment(buf, pos) {
+            Ok((kind, data)) => {
+                pos += LOG_HEADER_SIZE + data.len();
+                let record = match (kind, pending.take()) {
+                    (FULL, None) => Ok(Some(data.to_vec())),
+                    (FIRST, None) => {
+                        pending = Some((start, data.to_vec()));
+                        Ok(None)
+                    }
+                    (MIDDLE, Some((record_start, mut record))) => {
+                        record.extend_from_slice(data);
+                        pending = Some((record_start, record));
+                        Ok(None)
+                    }
+                    (LAST, Some((_, mut record))) => {
+                        record.extend_from_slice(data);
+                        Ok(Some(record))
+                    }
+                    // the fragment is fine, the record before it never ended
+                    (FULL | FIRST, Some((record_start, _))) => Err(Fault::Corruption {
+                        message: format!("unfinished wal record at offset {}", record_start),
+                        resume: start,
+                    }),
+                    (kind, _) => Err(Fault::Corruption {
+                        message: format!(
+                            "unexpected wal fragment type {} at offset {}",
+                            kind, start
+                        ),
+                        resume: pos,
+                    }),
+                };
+                match record {
+                    Ok(Some(record)) => match apply(&record) {
+                        Ok(()) => {
+                            valid_end = pos;
+                            continue;
+                        }
+                        Err(e) => Fault::Corruption {
+                            message: format!("undecodable wal record before offset {}: {}", pos, e),
+                            resume: pos,
+                        },
+                    },
+                    Ok(None) => continue,
+                    Err(fault) => fault,
+                }
+            }
+            Err(fault) => fault,
+        };
+
+        match (fault, mode) {
+            (Fault::Tail, WalRecoveryMode::AbsoluteConsistency) => {
+                return Err(Error::Corruption(format!(
+                    "torn wal record at offset {}",
+                    start
+                )));
+            }
+            (Fault::Tail, _) => return Ok(valid_end),
+            (Fault::Corruption { message, .. }, WalRecoveryMode::PointInTimeRecovery) => {
+                corruptions.push(format!("{}, the rest of the log is cut off", message));
+                return Ok(valid_end);
+            }
+            (Fault::Corruption { message, resume }, WalRecoveryMode::SkipAnyCorruptedRecords) => {
+                corruptions.push(message);
+                pending = None;
+                pos = resume;
+            }
+            (Fault::Corruption { message, .. }, _) => return Err(Error::Corruption(message)),
+        }
+    }
+
+    match pending {
+        // the log ends before the last fragment of a record
+        Some((record_start, _)) if mode == WalRecoveryMode::AbsoluteConsistency => Err(
+            Error::Corruption(format!("torn wal record at offset {}", record_start)),
+        ),
+        Some(_) => Ok(valid_end),
+        // skipped past the end, nothing after the last record is readable
+        None if pos > buf.len() => Ok(valid_end),
+        None => Ok(buf.len()),
     }
+}
+
+/// The wal segments of a directory, replayed when a tree or db is opened.
+pub struct RecoveredWal<T> {
+    // segments of full memtables that were not flushed yet, oldest first,
+    // with what was replayed from each
+    pub full: Vec<(PathBuf, T)>,
+    // the newest segment, kept open to append to, and what it held
+    pub log: Log,
+    pub number: u64,
+    pub current: T,
+    pub report: RecoveryReport,
+}
+
+/// Replays every wal segment in `dir` with `replay`, starting the first one
+/// if there is none.
+pub fn recover_wal<T>(
+    dir: &Path,
+    mut replay: impl FnMut(&mut Log) -> Result<T>,
+) -> Result<RecoveredWal<T>> {
+    let mut segments = segments(dir)?;
+    // the newest segment belongs to the memtable, older ones were full
+    // memtables that had not been flushed yet
+    let (number, mut log) = match segments.pop() {
+        Some((number, path)) => (number, Log::new(&path)?),
+        None => (0, Log::new(&segment_path(dir, 0))?),
+    };
 
-    pub fn check_crc(&self) -> bool {
-        self.crc == self.compute_crc()
+    let mut report = RecoveryReport::default();
+    let mut full = Vec::with_capacity(segments.len());
+    for (_, path) in segments {
+        // only the newest segment is appended to, so only it is cut off
+        let mut segment = Log::open_read_only(&path)?;
+        full.push((path, replay(&mut segment)?));
+        report.extend(&segment.recovery_report);
     }
+    let current = replay(&mut log)?;
+    report.extend(&log.recovery_report);
 
-    pub fn set_crc(&mut self) {
-        self.crc = self.compute_crc();
+    Ok(RecoveredWal {
+        full,
+        log,
+        number,
+        current,
+        report,
+    })
+}
+
+/// Entries of the segments in `dir` written at or before `seq`, such as the
+/// archived ones, to rebuild the tree as of `seq`.
+pub fn replay_segments<K: LogSerial, V: LogSerial>(dir: &Path, seq: u64) -> Result<Memtable<K, V>> {
+    let mut memtable = Memtable::new();
+    for (_, path) in segments(dir)? {
+        let segment: Memtable<K, V> = Log::open_read_only(&path)?.recovery()?;
+        memtable.extend(segment.into_iter().filter(|(key, _)| key.seq <= seq));
     }
+
+    Ok(memtable)
 }
 
 impl Log {
-    pub fn new(path: &Path) -> Log {
+    pub fn new(path: &Path) -> Result<Log> {
         let file = fs::OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
-            .open(path)
-            .unwrap();
+            .append(true)
+            .open(path)?;
+        let block_offset = file.metadata()?.len() as usize % LOG_BLOCK_SIZE;
 
-        Log {
+        Ok(Log {
             file,
             path: path.to_path_buf(),
-        }
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+            block_offset,
+            read_only: false,
+            recovery_report: RecoveryReport::default(),
+        })
+    }
+
+    /// Opens the segment in `path` to replay it, leaving it as it is.
+    pub fn open_read_only(path: &Path) -> Result<Log> {
+        let file = File::open(path)?;
+        let block_offset = file.metadata()?.len() as usize % LOG_BLOCK_SIZE;
+
+        Ok(Log {
+            file,
+            path: path.to_path_buf(),
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+            block_offset,
+            read_only: true,
+            recovery_report: RecoveryReport::default(),
+        })
     }
 
     pub fn append<K: LogSerial, V: LogSerial>(
         &mut self,
         entry: LogEntry<K, Option<V>>,
     ) -> Result<()> {
-        let payload = bincode::encode_to_vec(&entry, bincode::config::standard())?;
-        self.file.write(&payload)?;
+        self.append_record(&LogRecord::Entry(entry))
+    }
+
+    pub fn append_batch<K: LogSerial, V: LogSerial>(
+        &mut self,
+        batch: LogBatch<K, V>,
+    ) -> Result<()> {
+        self.append_record(&LogRecord::Batch(batch))
+    }
+
+    pub fn append_record<K: LogSerial, V: LogSerial>(
+        &mut self,
+        record: &LogRecord<K, V>,
+    ) -> Result<()> {
+        self.append_records(std::slice::from_ref(record))
+    }
+
+    /// Appends `records` with a single write.
+    pub fn append_records<K: LogSerial, V: LogSerial>(
+        &mut self,
+        records: &[LogRecord<K, V>],
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        for record in records.iter() {
+            let payload = bincode::encode_to_vec(record, bincode::config::standard())?;
+            self.frame(&payload, &mut buf);
+        }
+        self.file.write_all(&buf)?;
         self.file.flush()?;
+        self.unsynced_bytes += buf.len() as u64;
 
         Ok(())
     }
 
-    pub fn recovery<K: LogSerial, V: LogSerial>(&mut self) -> Result<BTreeMap<K, Option<V>>> {
-        let mut reader = BufReader::new(&self.file);
+    /// Appends `payload` to `buf` as fragments that fit in the blocks.
+    fn frame(&mut self, payload: &[u8], buf: &mut Vec<u8>) {
+        let mut rest = payload;
+        let mut first = true;
+        loop {
+            let block_left = LOG_BLOCK_SIZE - self.block_offset;
+            if block_left < LOG_HEADER_SIZE {
+                buf.resize(buf.len() + block_left, 0);
+                self.block_offset = 0;
+                continue;
+            }
 
-        let mut memtable = BTreeMap::new();
+            let (data, after) = rest.split_at(rest.len().min(block_left - LOG_HEADER_SIZE));
+            let last = after.is_empty();
+            let kind = match (first, last) {
+                (true, true) => FULL,
+                (true, false) => FIRST,
+                (false, false) => MIDDLE,
+                (false, true) => LAST,
+            };
+            buf.extend_from_slice(&fragment_crc(kind, data).to_le_bytes());
+            buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            buf.push(kind);
+            buf.extend_from_slice(data);
+            self.block_offset += LOG_HEADER_SIZE + data.len();
 
-        while let Ok(entry) = bincode::decode_from_reader::<
-            LogEntry<K, Option<V>>,
-            &mut BufReader<&File>,
-            _,
-        >(&mut reader, bincode::config::standard())
-        {
-            if entry.check_crc() {
-                memtable.insert(entry.key, entry.value);
+            if last {
+                return;
             }
+            rest = after;
+            first = false;
+        }
+    }
+
+    /// Forces everything appended so far to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Whether `mode` calls for a sync after the appends so far.
+    pub fn sync_due(&self, mode: SyncMode) -> bool {
+        if self.unsynced_bytes == 0 {
+            return false;
         }
 
+        match mode {
+            SyncMode::Never => false,
+            SyncMode::Always => true,
+            SyncMode::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncMode::Bytes(bytes) => self.unsynced_bytes >= bytes,
+        }
+    }
+
+    pub fn recovery<K: LogSerial, V: LogSerial>(&mut self) -> Result<Memtable<K, V>> {
+        self.recovery_with(WalRecoveryMode::default())
+    }
+
+    /// Replays the log, cutting off what `mode` gives up on so later
+    /// appends follow the last record recovered.
+    pub fn recovery_with<K: LogSerial, V: LogSerial>(
+        &mut self,
+        mode: WalRecoveryMode,
+    ) -> Result<Memtable<K, V>> {
+        let mut memtable = Memtable::new();
+        self.replay(mode, |record: LogRecord<K, V>| {
+            memtable.extend(record.into_entries())
+        })?;
+
         Ok(memtable)
     }
 
-    pub fn clear(&mut self) -> Result<()> {
-        fs::remove_file(&self.path)?;
+    /// `recovery_with` for a wal shared by column families, with the entries
+    /// of each family by its id.
+    pub fn recovery_families_with<K: LogSerial, V: LogSerial>(
+        &mut self,
+        mode: WalRecoveryMode,
+    ) -> Result<BTreeMap<u32, Memtable<K, V>>> {
+        let mut families: BTreeMap<u32, Memtable<K, V>> = BTreeMap::new();
+        self.replay(mode, |record: LogRecord<K, V>| {
+            for (family, key, value) in record.into_family_entries() {
+                families.entry(family).or_default().insert(key, value);
+            }
+        })?;
 
-        self.file = fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&self.path)
-            .unwrap();
+        Ok(families)
+    }
+
+    /// Passes every record recovered to `apply`, cutting off what `mode`
+    /// gives up on so later appends follow the last one. Corruption that
+    /// `mode` gets past is added to `recovery_report`.
+    fn replay<K: LogSerial, V: LogSerial>(
+        &mut self,
+        mode: WalRecoveryMode,
+        mut apply: impl FnMut(LogRecord<K, V>),
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        (&self.file).read_to_end(&mut buf)?;
+
+        let mut corruptions = Vec::new();
+        let keep = read_log(
+            &buf,
+            mode,
+            |payload| {
+                let (record, _): (LogRecord<K, V>, _) =
+                    bincode::decode_from_slice(payload, bincode::config::standard())?;
+                apply(record);
+                Ok(())
+            },
+            &mut corruptions,
+        )?;
+        self.recovery_report.corruptions.extend(
+            corruptions
+                .into_iter()
+                .map(|message| format!("{}: {}", self.path.display(), message)),
+        );
+
+        if keep < buf.len() && !self.read_only {
+            self.file.set_len(keep as u64)?;
+            self.file.sync_all()?;
+        }
+        self.block_offset = keep % LOG_BLOCK_SIZE;
 
-        assert_eq!(self.file.metadata()?.len(), 0);
         Ok(())
     }
 }
+
+struct SyncerState {
+    stopped: bool,
+    error: Option<Error>,
+}
+
+/// Thread syncing a wal in `SyncMode::Interval` once the interval passed
+/// since the last sync, so the last writes before a quiet spell reach the
+/// disk without waiting for the next write. Does nothing in other modes.
+pub struct WalSyncer {
+    state: Arc<(Mutex<SyncerState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WalSyncer {
+    pub fn new(wal: &Arc<Mutex<Log>>, mode: SyncMode) -> WalSyncer {
+        let state = Arc::new((
+            Mutex::new(SyncerState {
+                stopped: false,
+                error: None,
+            }),
+            Condvar::new(),
+        ));
+        let thread = match mode {
+            SyncMode::Interval(interval) => {
+                let wal = Arc::clone(wal);
+                let state = Arc::clone(&state);
+                Some(thread::spawn(move || sync_worker(wal, interval, state)))
+            }
+            _ => None,
+        };
+
+        WalSyncer { state, thread }
+    }
+
+    /// Error that stopped the thread, if any.
+    pub fn check(&self) -> Result<()> {
+        match &self.state.0.lock().unwrap().error {
+            Some(e) => Err(e.with_context("background wal sync failed")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for WalSyncer {
+    fn drop(&mut self) {
+        self.state.0.lock().unwrap().stopped = true;
+        self.state.1.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn sync_worker(
+    wal: Arc<Mutex<Log>>,
+    interval: Duration,
+    state: Arc<(Mutex<SyncerState>, Condvar)>,
+) {
+    let (lock, changed) = &*state;
+    loop {
+        // writes made while nothing was unsynced are synced by the next wake
+        // up at the latest, which is an interval after the last sync
+        let wait = {
+            let wal = wal.lock().unwrap();
+            if wal.unsynced_bytes > 0 {
+                interval.saturating_sub(wal.last_sync.elapsed())
+            } else {
+                interval
+            }
+        };
+        let guard = lock.lock().unwrap();
+        let (guard, _) = changed
+            .wait_timeout_while(guard, wait, |state| !state.stopped)
+            .unwrap();
+        if guard.stopped {
+            return;
+        }
+        drop(guard);
+
+        let mut wal = wal.lock().unwrap();
+        if wal.sync_due(SyncMode::Interval(interval)) {
+            if let Err(e) = wal.sync() {
+                lock.lock().unwrap().error = Some(e);
+                return;
+            }
+        }
+    }
+}
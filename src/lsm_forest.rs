@@ -1,11 +1,25 @@
+use crate::column_family::DEFAULT_FAMILY;
+use crate::compaction::{run_compactions, CompactionScheduler};
+use crate::error::{Error, Result};
+use crate::internal_key::{internal_range, visible, InternalKey, Memtable, MAX_SEQ};
+use crate::manifest::sync_dir;
+use crate::memtable::{BTreeMemTable, MemTable};
+use crate::merge_iterator::{EntryIterator, MergeIterator};
+use crate::options::{Options, ReadOptions, SyncMode, WriteOptions};
+use crate::snapshot::{Snapshot, SnapshotList};
+use crate::sstable::write_memtable;
+use crate::write_batch::WriteBatch;
+use crate::write_buffer_manager::WriteBufferManager;
 use crate::{log::*, table_manager::TableManager};
-use anyhow::Result;
 use bincode::{Decode, Encode};
 use core::fmt::Debug;
-use crc32fast;
+use std::collections::{HashMap, VecDeque};
 use std::io::Write;
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
-use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
 use std::{
     collections::BTreeMap,
     fs::{self, File},
@@ -16,107 +30,996 @@ use std::{
 
 use crate::table_manager::simple_table_manager::SimpleTableManager;
 
-pub trait LogSerial = Encode + Decode + Hash + Ord + 'static + Debug + Clone;
+pub trait LogSerial = Encode + Decode + Hash + Ord + 'static + Debug + Clone + Send + Sync;
+
+/// A wal segment that is no longer written to. It is deleted, or archived,
+/// once every memtable logged in it is in an sstable.
+pub struct WalSegment {
+    pub path: PathBuf,
+    // memtables logged in it that are not flushed yet
+    unflushed: AtomicUsize,
+}
+
+impl WalSegment {
+    pub fn new(path: PathBuf, memtables: usize) -> WalSegment {
+        WalSegment {
+            path,
+            unflushed: AtomicUsize::new(memtables),
+        }
+    }
+
+    /// One of its memtables was flushed, or dropped with its column family.
+    /// The last one retires the segment.
+    pub fn release(&self, archive: Option<&Path>) -> Result<()> {
+        if self.unflushed.fetch_sub(1, Ordering::SeqCst) == 1 {
+            retire_segment(&self.path, archive)?;
+        }
+        Ok(())
+    }
+}
+
+/// A full memtable waiting to be written out, still readable until then.
+pub struct ImmutableMemtable<MT> {
+    pub memtable: MT,
+    pub segment: Arc<WalSegment>,
+}
+
+struct FlushQueue<MT> {
+    // oldest first
+    immutables: VecDeque<Arc<ImmutableMemtable<MT>>>,
+    // flushed wal segments are moved here instead of deleted
+    archive: Option<PathBuf>,
+    error: Option<Error>,
+    shutdown: bool,
+}
+
+/// Immutable memtables of a tree and the thread flushing them.
+pub struct FlushState<MT> {
+    queue: Mutex<FlushQueue<MT>>,
+    // signalled whenever an immutable memtable is added or flushed
+    changed: Condvar,
+}
+
+impl<MT> FlushState<MT> {
+    pub fn new(immutables: VecDeque<Arc<ImmutableMemtable<MT>>>) -> FlushState<MT> {
+        FlushState {
+            queue: Mutex::new(FlushQueue {
+                immutables,
+                archive: None,
+                error: None,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Immutable memtables, newest first.
+    pub fn immutables(&self) -> Vec<Arc<ImmutableMemtable<MT>>> {
+        let queue = self.queue.lock().unwrap();
+        queue.immutables.iter().rev().cloned().collect()
+    }
+
+    /// Hands a full memtable to the flush thread.
+    pub fn push(&self, immutable: ImmutableMemtable<MT>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.immutables.push_back(Arc::new(immutable));
+        self.changed.notify_all();
+    }
+
+    /// Blocks while more than `max_immutables` memtables wait to be flushed.
+    pub fn wait_for_room(&self, max_immutables: usize) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        while queue.immutables.len() > max_immutables && queue.error.is_none() {
+            queue = self.changed.wait(queue).unwrap();
+        }
+        flush_result(&queue)
+    }
+
+    /// Blocks until every immutable memtable is in an sstable.
+    pub fn wait_for_flush(&self) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.immutables.is_empty() && queue.error.is_none() {
+            queue = self.changed.wait(queue).unwrap();
+        }
+        flush_result(&queue)
+    }
+
+    pub fn set_archive(&self, dir: Option<PathBuf>) {
+        self.queue.lock().unwrap().archive = dir;
+    }
+
+    /// Lets the flush thread stop once the queue is empty.
+    pub fn shutdown(&self) {
+        self.queue.lock().unwrap().shutdown = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Starts the thread that flushes the memtables of `flush` into `table_manager`.
+pub fn spawn_flush_worker<
+    K: LogSerial,
+    V: LogSerial,
+    TM: TableManager<K, V>,
+    MT: MemTable<K, V>,
+>(
+    flush: &Arc<FlushState<MT>>,
+    table_manager: &Arc<RwLock<TM>>,
+    snapshots: &Arc<SnapshotList>,
+    compactions: &Arc<CompactionScheduler>,
+    options: &Options,
+) -> JoinHandle<()> {
+    let flush = Arc::clone(flush);
+    let table_manager = Arc::clone(table_manager);
+    let snapshots = Arc::clone(snapshots);
+    let compactions = Arc::clone(compactions);
+    let manager = options.write_buffer_manager.clone();
+    thread::spawn(move || flush_worker(flush, table_manager, snapshots, compactions, manager))
+}
+
+struct PendingWrite<E> {
+    ticket: u64,
+    entries: Vec<E>,
+    sync: bool,
+}
+
+struct WriteQueue<E> {
+    // writes waiting for the next group, oldest first
+    pending: VecDeque<PendingWrite<E>>,
+    next_ticket: u64,
+    // a leader is appending a group
+    leading: bool,
+    // writes a leader appended for another writer, with its error if it failed
+    finished: HashMap<u64, Option<Error>>,
+}
+
+/// Writers waiting to be appended to a wal, one group at a time.
+struct WriteState<E> {
+    queue: Mutex<WriteQueue<E>>,
+    // signalled whenever a group is finished
+    changed: Condvar,
+}
+
+impl<E> Default for WriteState<E> {
+    fn default() -> Self {
+        WriteState {
+            queue: Mutex::new(WriteQueue {
+                pending: VecDeque::new(),
+                next_ticket: 0,
+                leading: false,
+                finished: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl<E> WriteState<E> {
+    /// Queues a write of `entries` and waits until it is applied. The first
+    /// writer to find no leader becomes one and passes every queued write to
+    /// `append` as a group, with a single wal write and sync, while the
+    /// others wait for it.
+    fn commit(
+        &self,
+        entries: Vec<E>,
+        sync: bool,
+        append: impl FnOnce(Vec<PendingWrite<E>>) -> Result<()>,
+    ) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push_back(PendingWrite {
+            ticket,
+            entries,
+            sync,
+        });
+
+        let group: Vec<PendingWrite<E>> = loop {
+            if let Some(error) = queue.finished.remove(&ticket) {
+                return match error {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                };
+            }
+            if !queue.leading {
+                queue.leading = true;
+                break queue.pending.drain(..).collect();
+            }
+            queue = self.changed.wait(queue).unwrap();
+        };
+        drop(queue);
+
+        let tickets: Vec<u64> = group.iter().map(|write| write.ticket).collect();
+        let result = append(group);
+
+        let mut queue = self.queue.lock().unwrap();
+        for other in tickets.into_iter().filter(|other| *other != ticket) {
+            let error = result
+                .as_ref()
+                .err()
+                .map(|e| e.with_context("group commit failed"));
+            queue.finished.insert(other, error);
+        }
+        queue.leading = false;
+        self.changed.notify_all();
+
+        result
+    }
+}
+
+/// The wal of a tree, or the one the column families of a db share, with
+/// the writers waiting to be appended to it.
+pub struct WalWriter<E> {
+    // directory of the wal segments
+    dir: PathBuf,
+    log: Arc<Mutex<Log>>,
+    syncer: WalSyncer,
+    sync_mode: SyncMode,
+    // number of the next wal segment, only changed with the wal locked
+    next_number: AtomicU64,
+    // sequence number of the last write in the memtables, only changed with
+    // the wal locked
+    last_seq: Arc<AtomicU64>,
+    writers: WriteState<E>,
+}
+
+impl<E> WalWriter<E> {
+    /// Appends to `log`, the segment numbered `number` in `dir`, after the
+    /// writes up to `last_seq`.
+    pub fn new(
+        dir: PathBuf,
+        log: Log,
+        number: u64,
+        last_seq: Arc<AtomicU64>,
+        sync_mode: SyncMode,
+    ) -> WalWriter<E> {
+        let log = Arc::new(Mutex::new(log));
+        let syncer = WalSyncer::new(&log, sync_mode);
+        WalWriter {
+            dir,
+            log,
+            syncer,
+            sync_mode,
+            next_number: AtomicU64::new(number + 1),
+            last_seq,
+            writers: WriteState::default(),
+        }
+    }
+
+    /// The current segment. Nothing is written while it is held, so every
+    /// write up to `last_seq` is in the memtables.
+    pub fn lock(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap()
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Logs `entries` as one record and applies them, in a group with the
+    /// writes queued meanwhile. The group leader stamps each write, turns it
+    /// into a record with `record`, and appends and syncs the group with the
+    /// wal locked. `insert` adds the records to the memtables and tells
+    /// whether one is full, in which case `rotate` is called once readers
+    /// can see the group.
+    pub fn write<K: LogSerial, V: LogSerial>(
+        &self,
+        entries: Vec<E>,
+        sync: bool,
+        record: impl Fn(u64, Vec<E>) -> LogRecord<K, V>,
+        insert: impl FnOnce(&Log, Vec<LogRecord<K, V>>) -> bool,
+        rotate: impl FnOnce(&mut MutexGuard<Log>) -> Result<()>,
+    ) -> Result<()> {
+        self.writers.commit(entries, sync, |group| {
+            let mut wal_lock = self.lock();
+
+            let mut seq = self.last_seq() + 1;
+            let mut sync = false;
+            let mut records = Vec::with_capacity(group.len());
+            for write in group {
+                sync |= write.sync;
+                let count = write.entries.len() as u64;
+                records.push(record(seq, write.entries));
+                seq += count;
+            }
+
+            wal_lock.append_records(&records)?;
+            if sync || wal_lock.sync_due(self.sync_mode) {
+                wal_lock.sync()?;
+            }
+
+            // readers do not see the group until `last_seq` is moved past it
+            let full = insert(&wal_lock, records);
+            self.last_seq.store(seq - 1, Ordering::SeqCst);
+
+            if full {
+                rotate(&mut wal_lock)?;
+            }
+            Ok(())
+        })?;
+
+        self.syncer.check()
+    }
+
+    /// Closes the current segment, with `memtables` memtables logged in it,
+    /// and starts a new one.
+    pub fn rotate(
+        &self,
+        wal_lock: &mut MutexGuard<Log>,
+        memtables: usize,
+    ) -> Result<Arc<WalSegment>> {
+        // a mode that syncs at all syncs the tail before the segment is closed
+        if self.sync_mode != SyncMode::Never && wal_lock.unsynced_bytes > 0 {
+            wal_lock.sync()?;
+        }
+
+        let number = self.next_number.fetch_add(1, Ordering::SeqCst);
+        let next = Log::new(&segment_path(&self.dir, number))?;
+        let wal = std::mem::replace(&mut **wal_lock, next);
+        Ok(Arc::new(WalSegment::new(wal.path, memtables)))
+    }
+
+    /// Forces every write so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.lock().sync()
+    }
+}
+
+impl<E> Drop for WalWriter<E> {
+    fn drop(&mut self) {
+        if self.sync_mode != SyncMode::Never {
+            let _ = self.sync();
+        }
+    }
+}
 
 pub struct LSMTree<
-    'a,
     K: LogSerial,
     V: LogSerial,
     TM: TableManager<K, V> = SimpleTableManager<K, V>,
+    MT: MemTable<K, V> = BTreeMemTable<K, V>,
 > {
-    pub path: PathBuf,
-    pub wal: Mutex<Log>,
-    pub memtable: RwLock<BTreeMap<K, Option<V>>>,
-    pub table_manager: Mutex<&'a mut TM>,
+    pub(crate) path: PathBuf,
+    pub(crate) wal: WalWriter<(K, Option<V>)>,
+    // writers insert with it locked for reading, it is only locked for
+    // writing to be swapped for an empty one
+    pub(crate) memtable: RwLock<MT>,
+    pub(crate) snapshots: Arc<SnapshotList>,
+    pub(crate) table_manager: Arc<RwLock<TM>>,
+    pub(crate) options: Options,
+    flush: Arc<FlushState<MT>>,
+    flush_thread: Option<JoinHandle<()>>,
+    compactions: Arc<CompactionScheduler>,
+    recovery_report: RecoveryReport,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>> LSMTree<K, V, TM> {
+    /// `open_with` for the default memtable, so it does not have to be named.
+    pub fn with_table_manager(p: PathBuf, tm: TM, options: Options) -> Result<LSMTree<K, V, TM>> {
+        Self::open_with(p, tm, options)
+    }
 }
 
 /// LSM Tree
-impl<'a, K: LogSerial, V: LogSerial, TM: TableManager<K, V>> LSMTree<'a, K, V, TM> {
-    /// Create a new LSM Tree
-    pub fn new(p: PathBuf, tm: &'a mut TM) -> LSMTree<'a, K, V, TM> {
-        let mut log = Log::new(&p.join("wal.log"));
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> LSMTree<K, V, TM, MT> {
+    /// Opens the LSM Tree in `p`, creating it if needed, with a table manager
+    /// of its own. Shared between threads through the `Arc`.
+    pub fn open<P: AsRef<Path>>(p: P, options: Options) -> Result<Arc<LSMTree<K, V, TM, MT>>> {
+        let p = p.as_ref();
+        options.validate()?;
+        fs::create_dir_all(p)?;
+        let tm = TM::new(p, &options)?;
+        let lsm = Self::open_with(p.to_path_buf(), tm, options)?;
+        Ok(Arc::new(lsm))
+    }
+
+    /// Opens the LSM Tree in `p` on top of `tm`, which was opened in the same
+    /// directory, recovering writes that were not flushed
+    pub fn open_with(p: PathBuf, tm: TM, options: Options) -> Result<LSMTree<K, V, TM, MT>> {
+        options.validate()?;
+        let recovered: RecoveredWal<Memtable<K, V>> =
+            recover_wal(&p, |log| log.recovery_with(options.recovery_mode))?;
 
-        let memtable = RwLock::new(log.recovery().unwrap_or(BTreeMap::new()));
+        let mut last_seq = tm.last_sequence();
+        let mut immutables = VecDeque::new();
+        for (path, memtable) in recovered.full {
+            last_seq = last_seq.max(max_seq(&memtable));
+            let memtable = fill::<K, V, MT>(memtable);
+            if let Some(manager) = &options.write_buffer_manager {
+                manager.reserve(memtable.size());
+                manager.schedule_free(memtable.size());
+            }
+            immutables.push_back(Arc::new(ImmutableMemtable {
+                memtable,
+                segment: Arc::new(WalSegment::new(path, 1)),
+            }));
+        }
 
-        LSMTree {
-            path: p.clone(),
-            wal: Mutex::new(log),
-            memtable: memtable,
-            table_manager: Mutex::new(tm),
+        last_seq = last_seq.max(max_seq(&recovered.current));
+        let memtable = fill::<K, V, MT>(recovered.current);
+        if let Some(manager) = &options.write_buffer_manager {
+            manager.reserve(memtable.size());
         }
+
+        let table_manager = Arc::new(RwLock::new(tm));
+        let snapshots = Arc::new(SnapshotList::new());
+        let flush = Arc::new(FlushState::new(immutables));
+        let compactions = Arc::new(CompactionScheduler::new(
+            Arc::clone(&table_manager),
+            Arc::clone(&snapshots),
+            options.compaction_threads,
+        ));
+        let flush_thread =
+            spawn_flush_worker(&flush, &table_manager, &snapshots, &compactions, &options);
+        let wal = WalWriter::new(
+            p.clone(),
+            recovered.log,
+            recovered.number,
+            Arc::new(AtomicU64::new(last_seq)),
+            options.sync_mode,
+        );
+
+        Ok(LSMTree {
+            path: p,
+            wal,
+            memtable: RwLock::new(memtable),
+            snapshots,
+            table_manager,
+            options,
+            flush,
+            flush_thread: Some(flush_thread),
+            compactions,
+            recovery_report: recovered.report,
+        })
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
-        // look at memtable
-        match self.memtable.read().unwrap().get(&key) {
-            Some(value) => value.clone(),
-            None => self.table_manager.lock().unwrap().read(&key),
-        }
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.get_at(key, MAX_SEQ)
     }
 
-    fn put_helper(&self, key: K, value: Option<V>) -> Result<()> {
-        // add to memtable
-        let mut log_entry = LogEntry {
-            crc: 0,
-            key: key.clone(),
-            value: value.clone(),
-        };
-        log_entry.set_crc();
+    /// Value of `key` as of sequence number `seq`
+    pub fn get_at(&self, key: &K, seq: u64) -> Result<Option<V>> {
+        read_at(
+            &self.memtable,
+            &self.flush,
+            &self.table_manager,
+            key,
+            seq,
+            self.visible_seq(seq),
+        )
+    }
 
-        {
-            let mut wal_lock = self.wal.lock().unwrap();
-            wal_lock.append(log_entry)?;
+    /// Values of `keys`, in the order asked for. Each sstable is opened, and
+    /// each of its blocks read, at most once for all of them.
+    pub fn multi_get(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        self.multi_get_with(keys, &ReadOptions::default())
+    }
 
-            self.memtable
-                .write()
-                .unwrap()
-                .insert(key.clone(), value.clone());
-        }
+    pub fn multi_get_with(&self, keys: &[K], options: &ReadOptions) -> Result<Vec<Option<V>>> {
+        self.multi_get_at(keys, MAX_SEQ, options)
+    }
 
-        {
-            let mut wal_lock = self.wal.lock().unwrap();
-            let mut memtable_lock = self.memtable.write().unwrap();
-            let mut tm_lock = self.table_manager.lock().unwrap();
+    /// Values of `keys` as of sequence number `seq`
+    pub fn multi_get_at(
+        &self,
+        keys: &[K],
+        seq: u64,
+        options: &ReadOptions,
+    ) -> Result<Vec<Option<V>>> {
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        sorted.dedup();
 
-            if tm_lock.should_flush(&wal_lock, &&memtable_lock) {
-                self.flush_memtable_helper(wal_lock, memtable_lock, tm_lock)?;
+        // newest version of each sorted key, `Some(None)` for a tombstone
+        let mut found: Vec<Option<Option<V>>> = vec![None; sorted.len()];
+        let visible_seq = self.visible_seq(seq);
+        let memtable_lock = self.memtable.read().unwrap();
+        for (key, value) in sorted.iter().zip(found.iter_mut()) {
+            *value = memtable_lock.get(key, visible_seq);
+        }
+        let immutables = self.flush.immutables();
+        drop(memtable_lock);
+        for immutable in immutables.iter() {
+            for (key, value) in sorted.iter().zip(found.iter_mut()) {
+                if value.is_none() {
+                    *value = immutable.memtable.get(key, visible_seq);
+                }
             }
         }
 
-        Ok(())
+        let (misses, missing): (Vec<usize>, Vec<K>) = sorted
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| found[*i].is_none())
+            .map(|(i, key)| (i, key.clone()))
+            .unzip();
+        let tm_lock = self.table_manager.read().unwrap();
+        let tm = &*tm_lock;
+        // each thread takes a run of neighbouring keys, so tables and blocks
+        // are mostly not read twice
+        let chunk_size = missing.len().div_ceil(options.threads.max(1)).max(1);
+        let chunks: Vec<&[K]> = missing.chunks(chunk_size).collect();
+        let values: Vec<Option<V>> = if chunks.len() > 1 {
+            let values = thread::scope(|scope| {
+                let handles: Vec<_> = chunks
+                    .iter()
+                    .map(|chunk| scope.spawn(move || tm.multi_read_at(chunk, seq)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<Result<Vec<_>>>()
+            })?;
+            values.into_iter().flatten().collect()
+        } else {
+            tm.multi_read_at(&missing, seq)?
+        };
+        drop(tm_lock);
+        for (i, value) in misses.into_iter().zip(values) {
+            found[i] = Some(value);
+        }
+
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let i = sorted.binary_search(key).unwrap();
+                found[i].clone().flatten()
+            })
+            .collect())
+    }
+
+    /// WAL corruption that `options.recovery_mode` got past when the tree
+    /// was opened, empty if none.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    /// Read-only view of the tree as of the last write
+    pub fn snapshot(&self) -> Snapshot<'_, K, V, TM, MT> {
+        // writes stamp and insert with the wal locked, so every write at or
+        // before `seq` is in the memtable
+        let _wal_lock = self.wal.lock();
+        Snapshot::new(self, self.wal.last_seq())
+    }
+
+    /// `seq`, or the last write if that is older. Writes after it may be
+    /// partly inserted into the memtable and are not read yet.
+    fn visible_seq(&self, seq: u64) -> u64 {
+        seq.min(self.wal.last_seq())
+    }
+
+    /// Ordered iterator over the live entries with keys in `range`
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        self.range_at(range, MAX_SEQ)
+    }
+
+    /// Ordered iterator over the entries with keys in `range` as of sequence number `seq`
+    pub fn range_at<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        seq: u64,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        range_at(
+            &self.memtable,
+            &self.flush,
+            &self.table_manager,
+            range,
+            self.visible_seq(seq),
+        )
+    }
+
+    /// Ordered iterator over every live entry
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        self.range(..)
+    }
+
+    fn put_helper(&self, key: K, value: Option<V>, options: &WriteOptions) -> Result<()> {
+        self.write_entries(vec![(key, value)], options)
+    }
+
+    /// Applies every put and remove in `batch`, or none of them if the
+    /// process crashes partway.
+    pub fn write(&self, batch: WriteBatch<K, V>) -> Result<()> {
+        self.write_with(batch, &WriteOptions::default())
+    }
+
+    pub fn write_with(&self, batch: WriteBatch<K, V>, options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if batch
+            .entries
+            .iter()
+            .any(|(family, _, _)| *family != DEFAULT_FAMILY)
+        {
+            return Err(Error::InvalidArgument(
+                "column families are written through DB::write".to_string(),
+            ));
+        }
+
+        let entries = batch
+            .entries
+            .into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect();
+        self.write_entries(entries, options)
+    }
+
+    /// Logs `entries` as one record and applies them to the memtable.
+    fn write_entries(&self, entries: Vec<(K, Option<V>)>, options: &WriteOptions) -> Result<()> {
+        self.wal.write(
+            entries,
+            options.sync,
+            |seq, mut entries| {
+                if entries.len() == 1 {
+                    let (key, value) = entries.pop().unwrap();
+                    LogRecord::Entry(LogEntry { seq, key, value })
+                } else {
+                    LogRecord::Batch(LogBatch::new(seq, entries))
+                }
+            },
+            |wal, records| {
+                let entries = records.into_iter().flat_map(|record| record.into_entries());
+                insert_entries::<K, V, TM, MT>(&self.memtable, entries, &self.options, wal)
+            },
+            |wal_lock| self.rotate_memtable(wal_lock, &mut self.memtable.write().unwrap()),
+        )?;
+
+        // backpressure
+        self.flush.wait_for_room(self.options.max_immutables)?;
+        self.compactions.check()
     }
 
     pub fn put(&self, key: K, value: V) -> Result<()> {
-        self.put_helper(key, Some(value))
+        self.put_with(key, value, &WriteOptions::default())
+    }
+
+    pub fn put_with(&self, key: K, value: V, options: &WriteOptions) -> Result<()> {
+        self.put_helper(key, Some(value), options)
     }
 
     pub fn remove(&self, key: &K) -> Result<()> {
-        self.put_helper(key.clone(), None)
+        self.remove_with(key, &WriteOptions::default())
     }
 
-    fn flush_memtable_helper(
-        &self,
-        mut wal_lock: MutexGuard<Log>,
-        mut memtable_lock: RwLockWriteGuard<BTreeMap<K, Option<V>>>,
-        mut tm_lock: MutexGuard<&mut TM>,
-    ) -> Result<()> {
-        tm_lock.add_table(memtable_lock.clone())?;
+    pub fn remove_with(&self, key: &K, options: &WriteOptions) -> Result<()> {
+        self.put_helper(key.clone(), None, options)
+    }
 
-        memtable_lock.clear();
+    /// Forces every write so far to disk.
+    pub fn sync_wal(&self) -> Result<()> {
+        self.wal.sync()
+    }
 
-        assert!(memtable_lock.is_empty());
+    /// Moves wal segments to `dir` once their memtable is flushed, instead of
+    /// deleting them, or stops archiving if `dir` is `None`.
+    pub fn set_wal_archive(&self, dir: Option<PathBuf>) -> Result<()> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+        }
+        self.flush.set_archive(dir);
+        Ok(())
+    }
 
-        wal_lock.clear()?;
-        assert!(wal_lock.file.metadata()?.len() == 0);
+    /// Hands the memtable to the flush thread and starts a new wal segment.
+    fn rotate_memtable(
+        &self,
+        wal_lock: &mut MutexGuard<Log>,
+        memtable_lock: &mut RwLockWriteGuard<MT>,
+    ) -> Result<()> {
+        if memtable_lock.is_empty() {
+            return Ok(());
+        }
 
+        let segment = self.wal.rotate(wal_lock, 1)?;
+        let memtable = std::mem::replace(&mut **memtable_lock, MT::new());
+        make_immutable(&self.flush, memtable, &segment, &self.options);
         Ok(())
     }
 
+    /// Encoded size of the memtable and the immutable memtables not flushed yet.
+    pub fn memtable_usage(&self) -> usize {
+        memtable_usage(&self.memtable, &self.flush)
+    }
+
+    /// Blocks until every immutable memtable is in an sstable.
+    pub fn wait_for_flush(&self) -> Result<()> {
+        self.flush.wait_for_flush()
+    }
+
+    /// Writes the memtable, and any waiting immutable memtables, to sstables.
     pub fn flush_memtable(&self) -> Result<()> {
-        let mut wal_lock = self.wal.lock().unwrap();
-        let mut memtable_lock = self.memtable.write().unwrap();
-        let mut tm_lock = self.table_manager.lock().unwrap();
+        {
+            let mut wal_lock = self.wal.lock();
+            let mut memtable_lock = self.memtable.write().unwrap();
+            self.rotate_memtable(&mut wal_lock, &mut memtable_lock)?;
+        }
 
-        self.flush_memtable_helper(wal_lock, memtable_lock, tm_lock)
+        self.wait_for_flush()
+    }
+
+    /// Runs every compaction that is due and waits for those in the background.
+    pub fn compact(&self) -> Result<()> {
+        self.compactions.wait_idle()?;
+        run_compactions::<K, V, TM>(&self.table_manager, &self.snapshots, || {})?;
+        self.compactions.wait_idle()
+    }
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> Drop
+    for LSMTree<K, V, TM, MT>
+{
+    fn drop(&mut self) {
+        close_memtables(
+            &mut self.memtable,
+            &self.flush,
+            self.flush_thread.take(),
+            &self.options,
+        );
+    }
+}
+
+/// Newest version of `key` at `seq` in a tree, looking at its memtables up
+/// to `visible_seq` and then at its tables.
+pub fn read_at<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>>(
+    memtable: &RwLock<MT>,
+    flush: &FlushState<MT>,
+    table_manager: &RwLock<TM>,
+    key: &K,
+    seq: u64,
+    visible_seq: u64,
+) -> Result<Option<V>> {
+    // look at memtable
+    let memtable_lock = memtable.read().unwrap();
+    if let Some(value) = memtable_lock.get(key, visible_seq) {
+        return Ok(value);
+    }
+    // taken before the memtable lock is released so a memtable that was
+    // just made immutable is not missed
+    let immutables = flush.immutables();
+    drop(memtable_lock);
+
+    for immutable in immutables.iter() {
+        if let Some(value) = immutable.memtable.get(key, visible_seq) {
+            return Ok(value);
+        }
+    }
+
+    table_manager.read().unwrap().read_at(key, seq)
+}
+
+/// Ordered iterator over the live entries of a tree with keys in `range`,
+/// as of `seq`.
+pub fn range_at<
+    K: LogSerial,
+    V: LogSerial,
+    TM: TableManager<K, V>,
+    MT: MemTable<K, V>,
+    R: RangeBounds<K>,
+>(
+    memtable: &RwLock<MT>,
+    flush: &FlushState<MT>,
+    table_manager: &RwLock<TM>,
+    range: R,
+    seq: u64,
+) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+    let start: Bound<K> = range.start_bound().cloned();
+    let end: Bound<K> = range.end_bound().cloned();
+    let (internal_start, internal_end) = internal_range(&start, &end);
+
+    let memtable_lock = memtable.read().unwrap();
+    let memtable_entries = memtable_lock.range(internal_start.clone(), internal_end.clone());
+    let immutables = flush.immutables();
+    drop(memtable_lock);
+
+    let mut sources: Vec<EntryIterator<InternalKey<K>, V>> =
+        vec![Box::new(memtable_entries.into_iter().map(Ok))];
+    for immutable in immutables {
+        let entries = immutable
+            .memtable
+            .range(internal_start.clone(), internal_end.clone());
+        sources.push(Box::new(entries.into_iter().map(Ok)));
+    }
+    // a memtable flushed since the snapshot shows up twice, the newer copy wins
+    let tables = table_manager.read().unwrap().scan(start, end)?;
+    sources.push(Box::new(tables));
+
+    Ok(
+        visible(MergeIterator::new(sources), seq).filter_map(|entry| {
+            entry
+                .map(|(key, value)| value.map(|value| (key, value)))
+                .transpose()
+        }),
+    )
+}
+
+/// Encoded size of a memtable and the immutable memtables not flushed yet.
+pub fn memtable_usage<K: LogSerial, V: LogSerial, MT: MemTable<K, V>>(
+    memtable: &RwLock<MT>,
+    flush: &FlushState<MT>,
+) -> usize {
+    let immutables: usize = flush
+        .immutables()
+        .iter()
+        .map(|immutable| immutable.memtable.size())
+        .sum();
+    immutables + memtable.read().unwrap().size()
+}
+
+/// Inserts the entries of a group into `memtable`, telling whether it should
+/// be flushed.
+pub fn insert_entries<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>>(
+    memtable: &RwLock<MT>,
+    entries: impl IntoIterator<Item = (InternalKey<K>, Option<V>)>,
+    options: &Options,
+    wal: &Log,
+) -> bool {
+    let memtable_lock = memtable.read().unwrap();
+    let before = memtable_lock.size();
+    for (key, value) in entries {
+        memtable_lock.insert(key, value);
+    }
+    let size = memtable_lock.size();
+    drop(memtable_lock);
+
+    // only one group is applied at a time, so nothing else changed the size
+    let mut over_budget = false;
+    if let Some(manager) = &options.write_buffer_manager {
+        manager.reserve(size - before);
+        over_budget = manager.should_flush();
+    }
+    over_budget || TM::should_flush(options, wal, size)
+}
+
+/// Hands a full memtable, logged in `segment`, to the flush thread.
+pub fn make_immutable<K: LogSerial, V: LogSerial, MT: MemTable<K, V>>(
+    flush: &FlushState<MT>,
+    memtable: MT,
+    segment: &Arc<WalSegment>,
+    options: &Options,
+) {
+    if let Some(manager) = &options.write_buffer_manager {
+        manager.schedule_free(memtable.size());
+    }
+    flush.push(ImmutableMemtable {
+        memtable,
+        segment: Arc::clone(segment),
+    });
+}
+
+/// Stops the flush thread of a tree being closed, once it flushed what it
+/// could, and gives back the memory of what is left.
+pub fn close_memtables<K: LogSerial, V: LogSerial, MT: MemTable<K, V>>(
+    memtable: &mut RwLock<MT>,
+    flush: &FlushState<MT>,
+    flush_thread: Option<JoinHandle<()>>,
+    options: &Options,
+) {
+    flush.shutdown();
+    if let Some(flush_thread) = flush_thread {
+        let _ = flush_thread.join();
+    }
+
+    // memtables left are recovered from the wal, not kept in memory
+    if let Some(manager) = &options.write_buffer_manager {
+        manager.release(memtable.get_mut().unwrap().size());
+        for immutable in flush.immutables() {
+            manager.free(immutable.memtable.size());
+        }
+    }
+}
+
+pub fn max_seq<K: LogSerial, V: LogSerial>(memtable: &Memtable<K, V>) -> u64 {
+    memtable.keys().map(|key| key.seq).max().unwrap_or(0)
+}
+
+pub fn fill<K: LogSerial, V: LogSerial, MT: MemTable<K, V>>(entries: Memtable<K, V>) -> MT {
+    let memtable = MT::new();
+    for (key, value) in entries {
+        memtable.insert(key, value);
+    }
+    memtable
+}
+
+/// Drops the versions a newer one hides from every snapshot, the way
+/// compaction would.
+fn drop_hidden<K: LogSerial, V: LogSerial>(
+    entries: Memtable<K, V>,
+    smallest_snapshot: u64,
+) -> Memtable<K, V> {
+    let mut newer: Option<InternalKey<K>> = None;
+    entries
+        .into_iter()
+        .filter(|(key, _)| {
+            let hidden = newer
+                .as_ref()
+                .is_some_and(|newer| newer.key == key.key && newer.seq <= smallest_snapshot);
+            newer = Some(key.clone());
+            !hidden
+        })
+        .collect()
+}
+
+fn flush_result<MT>(queue: &FlushQueue<MT>) -> Result<()> {
+    match &queue.error {
+        Some(e) => Err(e.with_context("background flush failed")),
+        None => Ok(()),
+    }
+}
+
+/// Deletes a wal segment whose memtable is flushed, or moves it to `archive`.
+pub fn retire_segment(path: &Path, archive: Option<&Path>) -> Result<()> {
+    match archive {
+        Some(dir) => {
+            fs::rename(path, dir.join(path.file_name().unwrap()))?;
+            sync_dir(dir)?;
+        }
+        None => fs::remove_file(path)?,
+    }
+
+    Ok(())
+}
+
+/// Writes immutable memtables to sstables, oldest first, until shut down.
+/// Memtables still queued at shutdown are recovered from their wal segments.
+fn flush_worker<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>>(
+    flush: Arc<FlushState<MT>>,
+    table_manager: Arc<RwLock<TM>>,
+    snapshots: Arc<SnapshotList>,
+    compactions: Arc<CompactionScheduler>,
+    write_buffer_manager: Option<Arc<WriteBufferManager>>,
+) {
+    let options = table_manager.read().unwrap().options().clone();
+    loop {
+        let (immutable, archive) = {
+            let mut queue = flush.queue.lock().unwrap();
+            loop {
+                if let Some(immutable) = queue.immutables.front() {
+                    break (immutable.clone(), queue.archive.clone());
+                }
+                if queue.shutdown {
+                    return;
+                }
+                queue = flush.changed.wait(queue).unwrap();
+            }
+        };
+
+        // snapshots taken later are newer than every entry, so they need
+        // no more than the newest version of each key
+        let entries = drop_hidden(immutable.memtable.entries(), snapshots.smallest());
+
+        // the table manager is only locked to hand out the path and to
+        // install the table, readers go on against the old table set while
+        // it is written. the wal segment is removed, once no other column
+        // family needs it, before the next memtable is flushed, so replaying
+        // leftover segments never reorders flushes
+        let path = table_manager.write().unwrap().new_table_path(0);
+        let result = match write_memtable(&path, &entries, &options) {
+            Ok(()) => table_manager.write().unwrap().install_table(path, entries),
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                Err(e)
+            }
+        }
+        .and_then(|_| immutable.segment.release(archive.as_deref()));
+
+        let mut queue = flush.queue.lock().unwrap();
+        match result {
+            Ok(()) => {
+                queue.immutables.pop_front();
+                if let Some(manager) = &write_buffer_manager {
+                    manager.free(immutable.memtable.size());
+                }
+                flush.changed.notify_all();
+                compactions.notify();
+            }
+            Err(e) => {
+                queue.error = Some(e);
+                flush.changed.notify_all();
+                return;
+            }
+        }
     }
 }
@@ -0,0 +1,280 @@
+use crate::error::{Error, Result};
+use bincode::{Decode, Encode};
+use std::collections::BTreeMap;
+use std::fs::{self, File, TryLockError};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// the manifest is an append only log of version edits. each edit is one
+// record framed as
+//
+//   [payload length: u32] [crc32 of payload: u32] [bincode VersionEdit]
+//
+// and is synced before the edit is applied in memory, so every edit is either
+// fully in the manifest or not at all. a torn record at the end of the file
+// is a crash during append and is cut off on open.
+
+pub const MANIFEST_NAME: &str = "MANIFEST";
+pub const LOCK_NAME: &str = "LOCK";
+pub const MANIFEST_MAGIC: u64 = 0x5453_4546_494e_414d; // "MANIFEST"
+pub const MANIFEST_VERSION: u32 = 2;
+const HEADER_SIZE: u64 = 8 + 4;
+const RECORD_HEADER_SIZE: usize = 4 + 4;
+
+/// One atomic change to the set of live sstables.
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq)]
+pub struct VersionEdit {
+    // (level, file name)
+    pub added: Vec<(u32, String)>,
+    pub removed: Vec<String>,
+    pub next_file_number: u64,
+    // largest sequence number in any table
+    pub last_sequence: u64,
+}
+
+impl VersionEdit {
+    pub fn add(&mut self, level: u32, path: &Path) {
+        self.added.push((level, file_name(path)));
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.removed.push(file_name(path));
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().to_string()
+}
+
+/// Sstable file extension for a level, `sst` for level 0 and `sst{level + 1}` after.
+pub fn table_extension(level: u32) -> String {
+    match level {
+        0 => "sst".to_string(),
+        _ => format!("sst{}", level + 1),
+    }
+}
+
+pub struct Manifest {
+    pub path: PathBuf,
+    file: File,
+    // held while the manifest is open, so only one owner changes the tables
+    _lock: File,
+    // live table file name -> level
+    pub tables: BTreeMap<String, u32>,
+    pub next_file_number: u64,
+    pub last_sequence: u64,
+}
+
+impl Manifest {
+    /// Opens the manifest in `dir`, replays it and deletes sstables it does not list.
+    /// Fails with `Error::Busy` if the manifest is already open, and with
+    /// `Error::Corruption` if `dir` has sstables but no manifest.
+    pub fn open(dir: &Path) -> Result<Manifest> {
+        let lock = lock_dir(dir)?;
+
+        let path = dir.join(MANIFEST_NAME);
+        // tables written before there was a manifest would all look like
+        // orphans, so they are left alone rather than deleted
+        if !path.exists() {
+            if let Some(table) = list_tables(dir)?.first() {
+                return Err(Error::Corruption(format!(
+                    "{} has sstables such as {} but no manifest",
+                    dir.display(),
+                    table.display()
+                )));
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        if buf.len() < HEADER_SIZE as usize {
+            // new manifest, or a crash before the header made it to disk
+            file.set_len(0)?;
+            file.write_all(&MANIFEST_MAGIC.to_le_bytes())?;
+            file.write_all(&MANIFEST_VERSION.to_le_bytes())?;
+            file.sync_all()?;
+            sync_dir(dir)?;
+            buf.clear();
+        } else {
+            let magic = u64::from_le_bytes(buf[0..8].try_into()?);
+            let version = u32::from_le_bytes(buf[8..12].try_into()?);
+            if magic != MANIFEST_MAGIC {
+                return Err(Error::Corruption(format!(
+                    "bad manifest magic number {:#x}",
+                    magic
+                )));
+            }
+            if version != MANIFEST_VERSION {
+                return Err(Error::Corruption(format!(
+                    "unsupported manifest version {}",
+                    version
+                )));
+            }
+        }
+
+        let mut manifest = Manifest {
+            path: dir.to_path_buf(),
+            file,
+            _lock: lock,
+            tables: BTreeMap::new(),
+            next_file_number: 0,
+            last_sequence: 0,
+        };
+
+        let mut pos = HEADER_SIZE as usize;
+        while pos < buf.len() {
+            let edit = match decode_record(&buf[pos..]) {
+                Ok(Some((edit, read))) => {
+                    pos += read;
+                    edit
+                }
+                // torn write at the tail, drop it
+                Ok(None) => {
+                    manifest.file.set_len(pos as u64)?;
+                    manifest.file.sync_all()?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            manifest.apply_in_memory(edit);
+        }
+
+        manifest.remove_orphans()?;
+
+        Ok(manifest)
+    }
+
+    fn apply_in_memory(&mut self, edit: VersionEdit) {
+        for name in edit.removed {
+            self.tables.remove(&name);
+        }
+        for (level, name) in edit.added {
+            self.tables.insert(name, level);
+        }
+        self.next_file_number = self.next_file_number.max(edit.next_file_number);
+        self.last_sequence = self.last_sequence.max(edit.last_sequence);
+    }
+
+    /// Deletes sstables in the directory that no edit has added, such as the
+    /// output of a compaction that crashed before it was logged.
+    fn remove_orphans(&self) -> Result<()> {
+        for path in list_tables(&self.path)? {
+            if !self.tables.contains_key(&file_name(&path)) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reserves a new, never used, sstable path for `level`.
+    pub fn new_table_path(&mut self, level: u32) -> PathBuf {
+        let name = format!(
+            "sstable_{:08}.{}",
+            self.next_file_number,
+            table_extension(level)
+        );
+        self.next_file_number += 1;
+        self.path.join(name)
+    }
+
+    /// Durably logs `edit`, then applies it to the live table set.
+    pub fn apply(&mut self, mut edit: VersionEdit) -> Result<()> {
+        edit.next_file_number = self.next_file_number;
+        edit.last_sequence = edit.last_sequence.max(self.last_sequence);
+
+        let payload = bincode::encode_to_vec(&edit, bincode::config::standard())?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        // the directory is synced too so newly created tables survive a crash
+        sync_dir(&self.path)?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+
+        self.apply_in_memory(edit);
+        Ok(())
+    }
+
+    /// Live tables of one level, oldest first.
+    pub fn level(&self, level: u32) -> Vec<PathBuf> {
+        self.tables
+            .iter()
+            .filter(|(_, table_level)| **table_level == level)
+            .map(|(name, _)| self.path.join(name))
+            .collect()
+    }
+}
+
+/// Sstables in `dir`, of any level.
+fn list_tables(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut tables = Vec::new();
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext.to_string_lossy().starts_with("sst"))
+        {
+            tables.push(path);
+        }
+    }
+    tables.sort();
+    Ok(tables)
+}
+
+/// Decodes one record, or `None` if `buf` ends partway through it.
+fn decode_record(buf: &[u8]) -> Result<Option<(VersionEdit, usize)>> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let len = u32::from_le_bytes(buf[0..4].try_into()?) as usize;
+    let crc = u32::from_le_bytes(buf[4..8].try_into()?);
+    let end = RECORD_HEADER_SIZE + len;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let payload = &buf[RECORD_HEADER_SIZE..end];
+    if crc32fast::hash(payload) != crc {
+        if buf.len() == end {
+            return Ok(None);
+        }
+        return Err(Error::Corruption(
+            "manifest record checksum mismatch".to_string(),
+        ));
+    }
+
+    let (edit, _) = bincode::decode_from_slice(payload, bincode::config::standard())?;
+    Ok(Some((edit, end)))
+}
+
+/// Takes the LOCK file of `dir`, held until the file is dropped, or fails
+/// with `Error::Busy` if someone else holds it.
+pub fn lock_dir(dir: &Path) -> Result<File> {
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_NAME))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => {
+            Err(Error::Busy(format!("{} is already open", dir.display())))
+        }
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use crate::internal_key::{entry_size, memtable_get, InternalKey, Memtable};
+use crate::lsm_forest::LogSerial;
+use crate::memtable::MemTable;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+/// Ordered map behind a rwlock, inserts wait for readers and each other.
+pub struct BTreeMemTable<K: LogSerial, V: LogSerial> {
+    entries: RwLock<Memtable<K, V>>,
+    size: AtomicUsize,
+}
+
+impl<K: LogSerial, V: LogSerial> MemTable<K, V> for BTreeMemTable<K, V> {
+    fn new() -> Self {
+        BTreeMemTable {
+            entries: RwLock::new(Memtable::new()),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    fn insert(&self, key: InternalKey<K>, value: Option<V>) {
+        let size = entry_size(&key, &value);
+        let mut entries = self.entries.write().unwrap();
+        if let Some(old) = entries.insert(key.clone(), value) {
+            self.size
+                .fetch_sub(entry_size(&key, &old), Ordering::SeqCst);
+        }
+        self.size.fetch_add(size, Ordering::SeqCst);
+    }
+
+    fn get(&self, key: &K, seq: u64) -> Option<Option<V>> {
+        memtable_get(&self.entries.read().unwrap(), key, seq)
+    }
+
+    fn range(
+        &self,
+        start: Bound<InternalKey<K>>,
+        end: Bound<InternalKey<K>>,
+    ) -> Vec<(InternalKey<K>, Option<V>)> {
+        self.entries
+            .read()
+            .unwrap()
+            .range((start, end))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn entries(&self) -> Memtable<K, V> {
+        self.entries.read().unwrap().clone()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+}
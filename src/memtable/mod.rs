@@ -0,0 +1,38 @@
+use crate::internal_key::{InternalKey, Memtable};
+use crate::lsm_forest::LogSerial;
+use std::ops::Bound;
+
+pub mod btree_memtable;
+pub mod skiplist_memtable;
+pub mod vector_memtable;
+pub use btree_memtable::BTreeMemTable;
+pub use skiplist_memtable::SkipListMemTable;
+pub use vector_memtable::VectorMemTable;
+
+/// Where writes go before they are flushed to an sstable. Inserts and reads
+/// go through `&self`, each implementation synchronizes them its own way.
+///
+/// Versions are only ever added, a memtable is dropped as a whole once it is
+/// flushed.
+pub trait MemTable<K: LogSerial, V: LogSerial>: Send + Sync + 'static {
+    fn new() -> Self
+    where
+        Self: Sized;
+    fn insert(&self, key: InternalKey<K>, value: Option<V>);
+    /// Newest version of `key` visible at `seq`, `Some(None)` for a tombstone.
+    fn get(&self, key: &K, seq: u64) -> Option<Option<V>>;
+    /// Entries between two internal key bounds, in order.
+    fn range(
+        &self,
+        start: Bound<InternalKey<K>>,
+        end: Bound<InternalKey<K>>,
+    ) -> Vec<(InternalKey<K>, Option<V>)>;
+    /// Every entry in order, as it is written to an sstable.
+    fn entries(&self) -> Memtable<K, V>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Encoded size of the entries, which flushing is measured in.
+    fn size(&self) -> usize;
+}
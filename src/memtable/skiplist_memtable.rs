@@ -0,0 +1,189 @@
+use crate::internal_key::{entry_size, InternalKey, Memtable};
+use crate::lsm_forest::LogSerial;
+use crate::memtable::MemTable;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+const MAX_HEIGHT: usize = 12;
+
+struct Node<K: LogSerial, V: LogSerial> {
+    key: InternalKey<K>,
+    value: Option<V>,
+    // successor on each level the node is linked into
+    next: Box<[AtomicPtr<Node<K, V>>]>,
+}
+
+/// Skiplist linked with compare-and-swap, so inserts and reads never lock
+/// and never wait on each other. Nodes are only freed with the whole list.
+pub struct SkipListMemTable<K: LogSerial, V: LogSerial> {
+    head: [AtomicPtr<Node<K, V>>; MAX_HEIGHT],
+    len: AtomicUsize,
+    size: AtomicUsize,
+    phantom: PhantomData<Box<Node<K, V>>>,
+}
+
+impl<K: LogSerial, V: LogSerial> MemTable<K, V> for SkipListMemTable<K, V> {
+    fn new() -> Self {
+        SkipListMemTable {
+            head: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            len: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    fn insert(&self, key: InternalKey<K>, value: Option<V>) {
+        let size = entry_size(&key, &value);
+        // each level holds a quarter of the nodes of the one below
+        let mut height = 1;
+        while height < MAX_HEIGHT && rand::random::<u32>().is_multiple_of(4) {
+            height += 1;
+        }
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            value,
+            next: (0..height).map(|_| AtomicPtr::default()).collect(),
+        }));
+        // SAFETY: nodes live as long as the list
+        let node_ref = unsafe { &*node };
+
+        // linked bottom up, a node is in the list once it is on level 0
+        let (mut links, mut succs) = self.splice(&node_ref.key);
+        let mut level = 0;
+        while level < height {
+            node_ref.next[level].store(succs[level], Ordering::Release);
+            match links[level].compare_exchange(
+                succs[level],
+                node,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => level += 1,
+                // another insert got in between, look again
+                Err(_) => (links, succs) = self.splice(&node_ref.key),
+            }
+        }
+
+        self.len.fetch_add(1, Ordering::SeqCst);
+        self.size.fetch_add(size, Ordering::SeqCst);
+    }
+
+    fn get(&self, key: &K, seq: u64) -> Option<Option<V>> {
+        let target = InternalKey::new(key.clone(), seq);
+        self.seek(|found| *found >= target)
+            .filter(|node| node.key.key == *key)
+            .map(|node| node.value.clone())
+    }
+
+    fn range(
+        &self,
+        start: Bound<InternalKey<K>>,
+        end: Bound<InternalKey<K>>,
+    ) -> Vec<(InternalKey<K>, Option<V>)> {
+        let first = match &start {
+            Bound::Included(start) => self.seek(|key| key >= start),
+            Bound::Excluded(start) => self.seek(|key| key > start),
+            Bound::Unbounded => self.first(),
+        };
+        let before_end = |key: &InternalKey<K>| match &end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        self.iter_from(first)
+            .take_while(|node| before_end(&node.key))
+            .map(|node| (node.key.clone(), node.value.clone()))
+            .collect()
+    }
+
+    fn entries(&self) -> Memtable<K, V> {
+        self.iter_from(self.first())
+            .map(|node| (node.key.clone(), node.value.clone()))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> SkipListMemTable<K, V> {
+    /// For each level, the link a node with `key` goes after and the node
+    /// it points to.
+    #[allow(clippy::type_complexity)]
+    fn splice(
+        &self,
+        key: &InternalKey<K>,
+    ) -> (
+        [&AtomicPtr<Node<K, V>>; MAX_HEIGHT],
+        [*mut Node<K, V>; MAX_HEIGHT],
+    ) {
+        let mut links = [&self.head[0]; MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let mut next: &[AtomicPtr<Node<K, V>>] = &self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            loop {
+                let succ = next[level].load(Ordering::Acquire);
+                // SAFETY: nodes live as long as the list
+                match unsafe { succ.as_ref() } {
+                    Some(node) if node.key < *key => next = &node.next,
+                    _ => {
+                        links[level] = &next[level];
+                        succs[level] = succ;
+                        break;
+                    }
+                }
+            }
+        }
+        (links, succs)
+    }
+
+    /// First node whose key is `past`, which has to stay true once it is.
+    fn seek(&self, past: impl Fn(&InternalKey<K>) -> bool) -> Option<&Node<K, V>> {
+        let mut next: &[AtomicPtr<Node<K, V>>] = &self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            // SAFETY: nodes live as long as the list
+            while let Some(node) = unsafe { next[level].load(Ordering::Acquire).as_ref() } {
+                if past(&node.key) {
+                    break;
+                }
+                next = &node.next;
+            }
+        }
+        // SAFETY: nodes live as long as the list
+        unsafe { next[0].load(Ordering::Acquire).as_ref() }
+    }
+
+    fn first(&self) -> Option<&Node<K, V>> {
+        // SAFETY: nodes live as long as the list
+        unsafe { self.head[0].load(Ordering::Acquire).as_ref() }
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        first: Option<&'a Node<K, V>>,
+    ) -> impl Iterator<Item = &'a Node<K, V>> {
+        // SAFETY: nodes live as long as the list
+        std::iter::successors(first, |node| unsafe {
+            node.next[0].load(Ordering::Acquire).as_ref()
+        })
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> Drop for SkipListMemTable<K, V> {
+    fn drop(&mut self) {
+        let mut next = *self.head[0].get_mut();
+        while !next.is_null() {
+            // SAFETY: every node is on level 0 once, and nothing else can
+            // reach it any more
+            let mut node = unsafe { Box::from_raw(next) };
+            next = *node.next[0].get_mut();
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::internal_key::{entry_size, InternalKey, Memtable};
+use crate::lsm_forest::LogSerial;
+use crate::memtable::MemTable;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+struct Entries<K: LogSerial, V: LogSerial> {
+    entries: Vec<(InternalKey<K>, Option<V>)>,
+    // every insert so far came after the one before, so the entries can be
+    // binary searched
+    sorted: bool,
+}
+
+/// Entries appended to a vector and sorted once, when flushed. Meant for
+/// bulk loads in key order, reads of entries written out of order scan
+/// the whole vector.
+pub struct VectorMemTable<K: LogSerial, V: LogSerial> {
+    entries: RwLock<Entries<K, V>>,
+    size: AtomicUsize,
+}
+
+impl<K: LogSerial, V: LogSerial> MemTable<K, V> for VectorMemTable<K, V> {
+    fn new() -> Self {
+        VectorMemTable {
+            entries: RwLock::new(Entries {
+                entries: Vec::new(),
+                sorted: true,
+            }),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    fn insert(&self, key: InternalKey<K>, value: Option<V>) {
+        let size = entry_size(&key, &value);
+        let mut entries = self.entries.write().unwrap();
+        if entries.entries.last().is_some_and(|(last, _)| *last >= key) {
+            entries.sorted = false;
+        }
+        entries.entries.push((key, value));
+        self.size.fetch_add(size, Ordering::SeqCst);
+    }
+
+    fn get(&self, key: &K, seq: u64) -> Option<Option<V>> {
+        let target = InternalKey::new(key.clone(), seq);
+        let entries = self.entries.read().unwrap();
+        let found = if entries.sorted {
+            let i = entries
+                .entries
+                .partition_point(|(found, _)| *found < target);
+            entries.entries.get(i)
+        } else {
+            entries
+                .entries
+                .iter()
+                .filter(|(found, _)| *found >= target)
+                .min_by(|(a, _), (b, _)| a.cmp(b))
+        };
+        found
+            .filter(|(found, _)| found.key == *key)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn range(
+        &self,
+        start: Bound<InternalKey<K>>,
+        end: Bound<InternalKey<K>>,
+    ) -> Vec<(InternalKey<K>, Option<V>)> {
+        let entries = self.entries.read().unwrap();
+        if entries.sorted {
+            let from = match &start {
+                Bound::Included(start) => entries.entries.partition_point(|(key, _)| key < start),
+                Bound::Excluded(start) => entries.entries.partition_point(|(key, _)| key <= start),
+                Bound::Unbounded => 0,
+            };
+            let to = match &end {
+                Bound::Included(end) => entries.entries.partition_point(|(key, _)| key <= end),
+                Bound::Excluded(end) => entries.entries.partition_point(|(key, _)| key < end),
+                Bound::Unbounded => entries.entries.len(),
+            };
+            return entries.entries[from..to.max(from)].to_vec();
+        }
+
+        let mut found: Vec<(InternalKey<K>, Option<V>)> = entries
+            .entries
+            .iter()
+            .filter(|(key, _)| (start.as_ref(), end.as_ref()).contains(key))
+            .cloned()
+            .collect();
+        found.sort_by(|(a, _), (b, _)| a.cmp(b));
+        found
+    }
+
+    fn entries(&self) -> Memtable<K, V> {
+        // collecting sorts them
+        self.entries
+            .read()
+            .unwrap()
+            .entries
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().entries.len()
+    }
+
+    fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,111 @@
+use crate::error::{Error, Result};
+use crate::lsm_forest::LogSerial;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+pub type EntryIterator<K, V> = Box<dyn Iterator<Item = Result<(K, Option<V>)>>>;
+
+struct HeapEntry<K: LogSerial, V: LogSerial> {
+    key: K,
+    value: Option<V>,
+    source: usize,
+}
+
+impl<K: LogSerial, V: LogSerial> PartialEq for HeapEntry<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> Eq for HeapEntry<K, V> {}
+
+impl<K: LogSerial, V: LogSerial> PartialOrd for HeapEntry<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> Ord for HeapEntry<K, V> {
+    // BinaryHeap is a max heap, so flip the order to pop the smallest key,
+    // and for equal keys the newest source
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .key
+            .cmp(&self.key)
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+/// Merges sorted sources into one sorted stream of entries.
+///
+/// Sources are ordered newest first. When several sources hold the same key
+/// only the entry from the newest one is returned. Tombstones (`None` values)
+/// are passed through so callers can shadow older data with them.
+///
+/// An error from any source ends the merge with that error, since the entry
+/// it could not read may have shadowed the next one.
+pub struct MergeIterator<K: LogSerial, V: LogSerial> {
+    sources: Vec<EntryIterator<K, V>>,
+    heap: BinaryHeap<HeapEntry<K, V>>,
+    // returned before anything else, a source failed while the merge started
+    error: Option<Error>,
+}
+
+impl<K: LogSerial, V: LogSerial> MergeIterator<K, V> {
+    pub fn new(sources: Vec<EntryIterator<K, V>>) -> MergeIterator<K, V> {
+        let mut merge = MergeIterator {
+            sources,
+            heap: BinaryHeap::new(),
+            error: None,
+        };
+
+        for source in 0..merge.sources.len() {
+            if let Err(e) = merge.advance(source) {
+                merge.error = Some(e);
+                break;
+            }
+        }
+
+        merge
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        if let Some(entry) = self.sources[source].next() {
+            let (key, value) = entry?;
+            self.heap.push(HeapEntry { key, value, source });
+        }
+        Ok(())
+    }
+
+    fn fail(&mut self, e: Error) -> Option<Result<(K, Option<V>)>> {
+        self.heap.clear();
+        Some(Err(e))
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> Iterator for MergeIterator<K, V> {
+    type Item = Result<(K, Option<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return self.fail(e);
+        }
+        let entry = self.heap.pop()?;
+        if let Err(e) = self.advance(entry.source) {
+            return self.fail(e);
+        }
+
+        // drop older versions of the same key
+        while let Some(older) = self.heap.peek() {
+            if older.key != entry.key {
+                break;
+            }
+            let older = self.heap.pop().unwrap();
+            if let Err(e) = self.advance(older.source) {
+                return self.fail(e);
+            }
+        }
+
+        Some(Ok((entry.key, entry.value)))
+    }
+}
@@ -0,0 +1,208 @@
+use crate::error::{Error, Result};
+use crate::write_buffer_manager::WriteBufferManager;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// When the WAL is synced to disk, for writes that do not ask for it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SyncMode {
+    /// Left to the OS, a crash of the machine can lose acknowledged writes.
+    #[default]
+    Never,
+    /// Synced before every write returns.
+    Always,
+    /// Synced this long after the last sync, by the next write or by a
+    /// background thread if there is none.
+    Interval(Duration),
+    /// Synced once this many bytes were written since the last sync.
+    Bytes(u64),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Sync the WAL before the write returns, whatever the sync mode.
+    /// Defaults to false.
+    pub sync: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Threads the sstable lookups of a `multi_get` are split between.
+    /// Defaults to 1.
+    pub threads: usize,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions { threads: 1 }
+    }
+}
+
+/// How WAL recovery treats records it cannot read.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WalRecoveryMode {
+    /// A torn record at the end of a segment is cut off, corruption before
+    /// it fails recovery.
+    #[default]
+    TolerateCorruptedTailRecords,
+    /// Any unreadable record fails recovery.
+    AbsoluteConsistency,
+    /// Recovery stops at the first unreadable record of a segment and cuts
+    /// off the rest of it.
+    PointInTimeRecovery,
+    /// Unreadable records are skipped and the ones after them recovered.
+    SkipAnyCorruptedRecords,
+}
+
+/// Tuning of an LSM Tree and its table manager, checked by `validate`.
+/// Table managers only read the fields that apply to them.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The memtable is flushed once its entries take this many bytes
+    /// encoded. Defaults to 4 KiB.
+    pub memtable_max_bytes: usize,
+    /// The memtable is also flushed once its WAL segment grows to this many
+    /// bytes. Defaults to 8 KiB.
+    pub wal_max_bytes: u64,
+    /// Writers wait once more than this many memtables are waiting to be
+    /// flushed. Defaults to 2.
+    pub max_immutables: usize,
+    /// Background threads running compactions. Defaults to 1.
+    pub compaction_threads: usize,
+    /// When the WAL is synced. Defaults to `SyncMode::Never`.
+    pub sync_mode: SyncMode,
+    /// How recovery treats unreadable WAL records. Defaults to
+    /// `WalRecoveryMode::TolerateCorruptedTailRecords`.
+    pub recovery_mode: WalRecoveryMode,
+    /// Memory budget shared with other trees, which flush early once it is
+    /// used up. Defaults to none.
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+
+    /// Sstable data blocks are cut once they grow past this many bytes.
+    /// Defaults to 4 KiB.
+    pub block_size: usize,
+    /// False positive rate of the bloom filter of each sstable. Defaults to
+    /// 5%.
+    pub filter_fp_rate: f64,
+    /// Entries kept by the cache table managers. Defaults to 128.
+    pub cache_capacity: usize,
+    /// Sstables kept open, with their index in memory, between reads.
+    /// Defaults to 64.
+    pub max_open_tables: usize,
+
+    /// Simple compaction merges every table once there are this many.
+    /// Defaults to 10.
+    pub compact_threshold: usize,
+    /// Tiered compaction merges a tier once it has this many tables.
+    /// Defaults to 5.
+    pub tiered_compact_threshold: usize,
+    /// Size of the tables simple and tiered compaction write. Defaults to
+    /// 2 MiB.
+    pub target_file_size: u64,
+
+    /// Levels of leveled compaction after level 0. Defaults to 6.
+    pub num_levels: u32,
+    /// Level 0 is compacted once it has this many tables. Defaults to 4.
+    pub level0_threshold: usize,
+    /// Bytes level 1 may hold before it is compacted into the next level.
+    /// Defaults to 256 KiB.
+    pub level1_max_bytes: u64,
+    /// Each level may hold this many times more bytes than the one above.
+    /// Defaults to 10.
+    pub size_ratio: u64,
+    /// Size of the tables leveled compaction writes. Defaults to 64 KiB.
+    pub level_target_file_size: u64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            memtable_max_bytes: 4 * 1024,
+            wal_max_bytes: 8 * 1024,
+            max_immutables: 2,
+            compaction_threads: 1,
+            sync_mode: SyncMode::Never,
+            recovery_mode: WalRecoveryMode::TolerateCorruptedTailRecords,
+            write_buffer_manager: None,
+
+            block_size: 4 * 1024,
+            filter_fp_rate: 0.05,
+            cache_capacity: 128,
+            max_open_tables: 64,
+
+            compact_threshold: 10,
+            tiered_compact_threshold: 5,
+            target_file_size: 2 * 1024 * 1024,
+
+            num_levels: 6,
+            level0_threshold: 4,
+            level1_max_bytes: 256 * 1024,
+            size_ratio: 10,
+            level_target_file_size: 64 * 1024,
+        }
+    }
+}
+
+impl Options {
+    /// Checks that every field is in range.
+    pub fn validate(&self) -> Result<()> {
+        let positive = [
+            ("memtable_max_bytes", self.memtable_max_bytes as u64),
+            ("wal_max_bytes", self.wal_max_bytes),
+            ("compaction_threads", self.compaction_threads as u64),
+            ("block_size", self.block_size as u64),
+            ("cache_capacity", self.cache_capacity as u64),
+            ("max_open_tables", self.max_open_tables as u64),
+            ("target_file_size", self.target_file_size),
+            ("num_levels", self.num_levels as u64),
+            ("level0_threshold", self.level0_threshold as u64),
+            ("level1_max_bytes", self.level1_max_bytes),
+            ("level_target_file_size", self.level_target_file_size),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(Error::InvalidArgument(format!(
+                    "{} must be greater than 0",
+                    name
+                )));
+            }
+        }
+
+        if self
+            .write_buffer_manager
+            .as_ref()
+            .is_some_and(|manager| manager.buffer_size() == 0)
+        {
+            return Err(Error::InvalidArgument(
+                "write_buffer_manager buffer_size must be greater than 0".to_string(),
+            ));
+        }
+
+        // merging a single table would pick the same compaction forever
+        for (name, value) in [
+            ("compact_threshold", self.compact_threshold),
+            ("tiered_compact_threshold", self.tiered_compact_threshold),
+        ] {
+            if value < 2 {
+                return Err(Error::InvalidArgument(format!(
+                    "{} must be at least 2, got {}",
+                    name, value
+                )));
+            }
+        }
+        if self.size_ratio < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "size_ratio must be at least 2, got {}",
+                self.size_ratio
+            )));
+        }
+        if !(self.filter_fp_rate > 0.0 && self.filter_fp_rate < 1.0) {
+            return Err(Error::InvalidArgument(format!(
+                "filter_fp_rate must be between 0 and 1, got {}",
+                self.filter_fp_rate
+            )));
+        }
+
+        Ok(())
+    }
+}
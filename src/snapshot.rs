@@ -0,0 +1,101 @@
+use crate::error::Result;
+use crate::internal_key::MAX_SEQ;
+use crate::lsm_forest::{LSMTree, LogSerial};
+use crate::memtable::{BTreeMemTable, MemTable};
+use crate::options::ReadOptions;
+use crate::table_manager::TableManager;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::Mutex;
+
+/// Sequence numbers of the live snapshots, which compaction has to keep
+/// every version for.
+#[derive(Default)]
+pub struct SnapshotList {
+    // sequence number -> number of snapshots taken at it
+    live: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> SnapshotList {
+        SnapshotList::default()
+    }
+
+    pub fn acquire(&self, seq: u64) {
+        *self.live.lock().unwrap().entry(seq).or_insert(0) += 1;
+    }
+
+    pub fn release(&self, seq: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seq);
+            }
+        }
+    }
+
+    /// Oldest live snapshot, or `MAX_SEQ` if there is none.
+    pub fn smallest(&self) -> u64 {
+        let live = self.live.lock().unwrap();
+        live.keys().next().copied().unwrap_or(MAX_SEQ)
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.live.lock().unwrap().is_empty()
+    }
+}
+
+/// Read-only view of an LSM Tree as of one sequence number. Writes made
+/// after it was taken are not visible through it.
+pub struct Snapshot<
+    'a,
+    K: LogSerial,
+    V: LogSerial,
+    TM: TableManager<K, V>,
+    MT: MemTable<K, V> = BTreeMemTable<K, V>,
+> {
+    lsm: &'a LSMTree<K, V, TM, MT>,
+    pub seq: u64,
+}
+
+impl<'a, K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>>
+    Snapshot<'a, K, V, TM, MT>
+{
+    /// Registers a snapshot at `seq`, released when it is dropped.
+    pub fn new(lsm: &'a LSMTree<K, V, TM, MT>, seq: u64) -> Snapshot<'a, K, V, TM, MT> {
+        lsm.snapshots.acquire(seq);
+        Snapshot { lsm, seq }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.lsm.get_at(key, self.seq)
+    }
+
+    pub fn multi_get(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        self.lsm
+            .multi_get_at(keys, self.seq, &ReadOptions::default())
+    }
+
+    /// Ordered iterator over the entries with keys in `range` as of the snapshot
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        self.lsm.range_at(range, self.seq)
+    }
+
+    /// Ordered iterator over every entry as of the snapshot
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(K, V)>>> {
+        self.range(..)
+    }
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>, MT: MemTable<K, V>> Drop
+    for Snapshot<'_, K, V, TM, MT>
+{
+    fn drop(&mut self) {
+        self.lsm.snapshots.release(self.seq);
+    }
+}
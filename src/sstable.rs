@@ -0,0 +1,525 @@
+use crate::error::{Error, Result};
+use crate::internal_key::Memtable;
+use crate::lsm_forest::LogSerial;
+use crate::options::Options;
+use crate::table_manager::simple_table_manager::SimpleTableEntry;
+use bincode::{Decode, Encode};
+use bloomfilter::Bloom;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// sstable layout:
+//
+//   [data block 0] ... [data block n] [filter block] [index block] [footer]
+//
+// data blocks hold bincode encoded SimpleTableEntry records in key order and
+// are cut once they grow past the block size in the options. the filter
+// block is a bloom filter over every key in the table, tombstones included.
+// the index block is a bincode encoded Vec<BlockHandle>, one per data block.
+// the footer has a fixed size so it can be read from the end of the file
+// without knowing anything else.
+
+pub const MAGIC: u64 = 0x4c53_4d46_4f52_4553; // "LSMFORES"
+pub const FORMAT_VERSION: u32 = 2;
+pub const FOOTER_SIZE: u64 = 8 * 5 + 4 + 8;
+
+/// Key and value of one entry, `None` for a tombstone.
+pub type Entry<K, V> = (K, Option<V>);
+
+/// Location and key range of one data block.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct BlockHandle<K: LogSerial> {
+    pub first_key: K,
+    pub last_key: K,
+    pub offset: u64,
+    pub len: u64,
+    pub crc: u32,
+}
+
+/// Serialized state of a bloom filter.
+#[derive(Encode, Decode, Debug)]
+pub struct FilterBlock {
+    pub bitmap: Vec<u8>,
+    pub bits: u64,
+    pub hashes: u32,
+    pub sip_keys: [(u64, u64); 2],
+}
+
+impl FilterBlock {
+    pub fn from_bloom<K: LogSerial>(bloom: &Bloom<K>) -> FilterBlock {
+        FilterBlock {
+            bitmap: bloom.bitmap(),
+            bits: bloom.number_of_bits(),
+            hashes: bloom.number_of_hash_functions(),
+            sip_keys: bloom.sip_keys(),
+        }
+    }
+
+    pub fn to_bloom<K: LogSerial>(&self) -> Bloom<K> {
+        Bloom::from_existing(&self.bitmap, self.bits, self.hashes, self.sip_keys)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footer {
+    pub filter_offset: u64,
+    pub filter_len: u64,
+    pub index_offset: u64,
+    pub index_len: u64,
+    pub entry_count: u64,
+    pub version: u32,
+}
+
+impl Footer {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_SIZE as usize);
+        buf.extend_from_slice(&self.filter_offset.to_le_bytes());
+        buf.extend_from_slice(&self.filter_len.to_le_bytes());
+        buf.extend_from_slice(&self.index_offset.to_le_bytes());
+        buf.extend_from_slice(&self.index_len.to_le_bytes());
+        buf.extend_from_slice(&self.entry_count.to_le_bytes());
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&MAGIC.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Footer> {
+        let magic = u64::from_le_bytes(buf[44..52].try_into()?);
+        if magic != MAGIC {
+            return Err(Error::Corruption(format!(
+                "bad sstable magic number {:#x}",
+                magic
+            )));
+        }
+
+        let footer = Footer {
+            filter_offset: u64::from_le_bytes(buf[0..8].try_into()?),
+            filter_len: u64::from_le_bytes(buf[8..16].try_into()?),
+            index_offset: u64::from_le_bytes(buf[16..24].try_into()?),
+            index_len: u64::from_le_bytes(buf[24..32].try_into()?),
+            entry_count: u64::from_le_bytes(buf[32..40].try_into()?),
+            version: u32::from_le_bytes(buf[40..44].try_into()?),
+        };
+        if footer.version != FORMAT_VERSION {
+            return Err(Error::Corruption(format!(
+                "unsupported sstable version {}",
+                footer.version
+            )));
+        }
+
+        Ok(footer)
+    }
+}
+
+/// Writes a sorted stream of entries into a new sstable.
+pub struct TableBuilder<K: LogSerial, V: LogSerial> {
+    file: BufWriter<File>,
+    block: Vec<u8>,
+    block_size: usize,
+    filter_fp_rate: f64,
+    first_key: Option<K>,
+    last_key: Option<K>,
+    offset: u64,
+    index: Vec<BlockHandle<K>>,
+    // keys are kept until finish so the filter can be sized from their count
+    keys: Vec<K>,
+    phantom: std::marker::PhantomData<V>,
+}
+
+impl<K: LogSerial, V: LogSerial> TableBuilder<K, V> {
+    pub fn new(path: &Path, options: &Options) -> Result<TableBuilder<K, V>> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(TableBuilder {
+            file: BufWriter::new(file),
+            block: Vec::with_capacity(options.block_size),
+            block_size: options.block_size,
+            filter_fp_rate: options.filter_fp_rate,
+            first_key: None,
+            last_key: None,
+            offset: 0,
+            index: Vec::new(),
+            keys: Vec::new(),
+            phantom: std::marker::PhantomData,
+        })
+    }
+
+    /// Keys must be added in strictly increasing order.
+    pub fn add(&mut self, key: K, value: Option<V>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.clone());
+        }
+        self.last_key = Some(key.clone());
+        self.keys.push(key.clone());
+
+        let entry = SimpleTableEntry { key, value };
+        bincode::encode_into_std_write(&entry, &mut self.block, bincode::config::standard())?;
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let (first_key, last_key) = match (self.first_key.take(), self.last_key.take()) {
+            (Some(first_key), Some(last_key)) => (first_key, last_key),
+            _ => return Ok(()),
+        };
+
+        self.file.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            first_key,
+            last_key,
+            offset: self.offset,
+            len: self.block.len() as u64,
+            crc: crc32fast::hash(&self.block),
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+
+        Ok(())
+    }
+
+    /// Number of bytes written so far, not counting the index and footer.
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+
+        let mut bloom = Bloom::new_for_fp_rate(self.keys.len().max(1), self.filter_fp_rate);
+        for key in self.keys.iter() {
+            bloom.set(key);
+        }
+        let filter = FilterBlock::from_bloom(&bloom);
+        let filter = bincode::encode_to_vec(&filter, bincode::config::standard())?;
+        self.file.write_all(&filter)?;
+
+        let index = bincode::encode_to_vec(&self.index, bincode::config::standard())?;
+        self.file.write_all(&index)?;
+
+        let footer = Footer {
+            filter_offset: self.offset,
+            filter_len: filter.len() as u64,
+            index_offset: self.offset + filter.len() as u64,
+            index_len: index.len() as u64,
+            entry_count: self.keys.len() as u64,
+            version: FORMAT_VERSION,
+        };
+        self.file.write_all(&footer.encode())?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        Ok(())
+    }
+}
+
+/// Writes every entry into a new sstable at `path`.
+pub fn write_table<K: LogSerial, V: LogSerial>(
+    path: &Path,
+    entries: impl IntoIterator<Item = (K, Option<V>)>,
+    options: &Options,
+) -> Result<()> {
+    let mut builder = TableBuilder::new(path, options)?;
+    for (key, value) in entries {
+        builder.add(key, value)?;
+    }
+    builder.finish()
+}
+
+/// Writes the entries of `memtable` into a new sstable at `path`, keeping
+/// the memtable to install the table with.
+pub fn write_memtable<K: LogSerial, V: LogSerial>(
+    path: &Path,
+    memtable: &Memtable<K, V>,
+    options: &Options,
+) -> Result<()> {
+    let entries = memtable
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()));
+    write_table(path, entries, options)
+}
+
+/// Reads the `len` bytes at `offset` of the sstable at `path`, failing with
+/// `Error::Corruption` rather than allocating if they run past `end`.
+fn read_region(file: &File, path: &Path, offset: u64, len: u64, end: u64) -> Result<Vec<u8>> {
+    if offset
+        .checked_add(len)
+        .is_none_or(|region_end| region_end > end)
+    {
+        return Err(Error::Corruption(format!(
+            "{} bytes at {} run past the end of sstable {}",
+            len,
+            offset,
+            path.display()
+        )));
+    }
+
+    let mut buf = vec![0; len as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+/// Read handle for an sstable, holding its index in memory.
+pub struct Table<K: LogSerial, V: LogSerial> {
+    pub path: PathBuf,
+    pub footer: Footer,
+    pub index: Vec<BlockHandle<K>>,
+    file: File,
+    // where the footer starts, nothing the footer or index point at is past it
+    end: u64,
+    phantom: std::marker::PhantomData<V>,
+}
+
+impl<K: LogSerial, V: LogSerial> Table<K, V> {
+    pub fn open(path: &Path) -> Result<Table<K, V>> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_SIZE {
+            return Err(Error::Corruption(format!(
+                "sstable {} is too short",
+                path.display()
+            )));
+        }
+
+        let mut buf = vec![0; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut buf, len - FOOTER_SIZE)?;
+        let footer = Footer::decode(&buf)?;
+
+        let end = len - FOOTER_SIZE;
+        let buf = read_region(&file, path, footer.index_offset, footer.index_len, end)?;
+        let (index, _) = bincode::decode_from_slice(&buf, bincode::config::standard())?;
+
+        Ok(Table {
+            path: path.to_path_buf(),
+            footer,
+            index,
+            file,
+            end,
+            phantom: std::marker::PhantomData,
+        })
+    }
+
+    /// Reads the table's bloom filter block.
+    pub fn read_filter(&self) -> Result<Bloom<K>> {
+        let buf = read_region(
+            &self.file,
+            &self.path,
+            self.footer.filter_offset,
+            self.footer.filter_len,
+            self.end,
+        )?;
+        let (filter, _): (FilterBlock, usize) =
+            bincode::decode_from_slice(&buf, bincode::config::standard())?;
+
+        Ok(filter.to_bloom())
+    }
+
+    pub fn first_key(&self) -> Option<&K> {
+        self.index.first().map(|handle| &handle.first_key)
+    }
+
+    pub fn last_key(&self) -> Option<&K> {
+        self.index.last().map(|handle| &handle.last_key)
+    }
+
+    fn read_block(&self, handle: &BlockHandle<K>) -> Result<VecDeque<(K, Option<V>)>> {
+        let buf = read_region(&self.file, &self.path, handle.offset, handle.len, self.end)?;
+        if crc32fast::hash(&buf) != handle.crc {
+            return Err(Error::Corruption(format!(
+                "checksum mismatch in block at {} of {}",
+                handle.offset,
+                self.path.display()
+            )));
+        }
+
+        let mut entries = VecDeque::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (entry, read): (SimpleTableEntry<K, V>, usize) =
+                bincode::decode_from_slice(&buf[pos..], bincode::config::standard())?;
+            entries.push_back((entry.key, entry.value));
+            pos += read;
+        }
+
+        Ok(entries)
+    }
+
+    /// Looks up `key`, returning `Some(None)` if the table holds a tombstone.
+    pub fn get(&self, key: &K) -> Result<Option<Option<V>>> {
+        let block = self.index.partition_point(|handle| handle.last_key < *key);
+        match self.index.get(block) {
+            Some(handle) if handle.first_key <= *key => {
+                for (entry_key, value) in self.read_block(handle)? {
+                    if entry_key == *key {
+                        return Ok(Some(value));
+                    }
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// First entry with a key at or after `key`.
+    pub fn seek(&self, key: &K) -> Result<Option<(K, Option<V>)>> {
+        let block = self.index.partition_point(|handle| handle.last_key < *key);
+        match self.index.get(block) {
+            Some(handle) => Ok(self
+                .read_block(handle)?
+                .into_iter()
+                .find(|(entry_key, _)| entry_key >= key)),
+            None => Ok(None),
+        }
+    }
+
+    /// `seek` for each of the sorted `keys`, reading every block at most once.
+    pub fn seek_many(&self, keys: &[K]) -> Result<Vec<Option<Entry<K, V>>>> {
+        let mut found = Vec::with_capacity(keys.len());
+        let mut block: Option<(usize, VecDeque<Entry<K, V>>)> = None;
+        for key in keys {
+            let index = self.index.partition_point(|handle| handle.last_key < *key);
+            let Some(handle) = self.index.get(index) else {
+                found.push(None);
+                continue;
+            };
+            // sorted keys never go back to an earlier block
+            if block.as_ref().is_none_or(|(read, _)| *read != index) {
+                block = Some((index, self.read_block(handle)?));
+            }
+            let (_, entries) = block.as_ref().unwrap();
+            found.push(
+                entries
+                    .iter()
+                    .find(|(entry_key, _)| entry_key >= key)
+                    .cloned(),
+            );
+        }
+
+        Ok(found)
+    }
+
+    /// Iterates over the entries between two bounds, reading one block at a time.
+    pub fn iter(self, start: Bound<K>, end: Bound<K>) -> TableIterator<K, V> {
+        Arc::new(self).iter_shared(start, end)
+    }
+
+    /// `iter` over a handle other readers share, such as a cached one.
+    pub fn iter_shared(self: Arc<Self>, start: Bound<K>, end: Bound<K>) -> TableIterator<K, V> {
+        let block = match &start {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .index
+                .partition_point(|handle| handle.last_key < *start),
+            Bound::Unbounded => 0,
+        };
+
+        TableIterator {
+            table: self,
+            block,
+            entries: VecDeque::new(),
+            start,
+            end,
+            done: false,
+        }
+    }
+}
+
+/// Key range and size of a non-empty sstable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableMeta<K: LogSerial> {
+    pub path: PathBuf,
+    pub first_key: K,
+    pub last_key: K,
+    pub size: u64,
+}
+
+impl<K: LogSerial> TableMeta<K> {
+    /// Reads the key range of the sstable at `path`, or `None` if it is empty.
+    pub fn load<V: LogSerial>(path: &Path) -> Result<Option<TableMeta<K>>> {
+        let table = Table::<K, V>::open(path)?;
+        let size = table.file.metadata()?.len();
+
+        Ok(match (table.first_key(), table.last_key()) {
+            (Some(first_key), Some(last_key)) => Some(TableMeta {
+                path: path.to_path_buf(),
+                first_key: first_key.clone(),
+                last_key: last_key.clone(),
+                size,
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn overlaps(&self, first_key: &K, last_key: &K) -> bool {
+        self.first_key <= *last_key && *first_key <= self.last_key
+    }
+}
+
+/// Iterates over the entries of one sstable that fall between two bounds.
+/// A block that cannot be read ends it with the error.
+pub struct TableIterator<K: LogSerial, V: LogSerial> {
+    table: Arc<Table<K, V>>,
+    block: usize,
+    entries: VecDeque<(K, Option<V>)>,
+    start: Bound<K>,
+    end: Bound<K>,
+    done: bool,
+}
+
+impl<K: LogSerial, V: LogSerial> Iterator for TableIterator<K, V> {
+    type Item = Result<(K, Option<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (key, value) = match self.entries.pop_front() {
+                Some(entry) => entry,
+                None => {
+                    match self.table.index.get(self.block) {
+                        Some(handle) => match self.table.read_block(handle) {
+                            Ok(entries) => self.entries = entries,
+                            Err(e) => {
+                                self.done = true;
+                                return Some(Err(e));
+                            }
+                        },
+                        None => break,
+                    }
+                    self.block += 1;
+                    continue;
+                }
+            };
+
+            let before_start = match &self.start {
+                Bound::Included(start) => key < *start,
+                Bound::Excluded(start) => key <= *start,
+                Bound::Unbounded => false,
+            };
+            let after_end = match &self.end {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+
+            if after_end {
+                break;
+            } else if !before_start {
+                return Some(Ok((key, value)));
+            }
+        }
+
+        self.done = true;
+        None
+    }
+}
@@ -0,0 +1,128 @@
+use crate::compaction::{CompactionJob, CompactionStats};
+use crate::error::Result;
+use crate::internal_key::{InternalKey, Memtable};
+use crate::log::Log;
+use crate::lsm_forest::LogSerial;
+use crate::merge_iterator::MergeIterator;
+use crate::options::Options;
+use crate::sstable::{Table, TableMeta};
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::table_manager::{TableFilter, TableManager};
+
+use bloomfilter::Bloom;
+
+/// Skips the sstables of `tm` whose bloom filter rules out the key looked up.
+pub struct BloomLayer<K: LogSerial, V: LogSerial, TM: TableManager<K, V>> {
+    pub(crate) tm: TM,
+    // bloom filter of each sstable, loaded from its filter block on first use
+    pub(crate) filters: RwLock<HashMap<PathBuf, Arc<Bloom<InternalKey<K>>>>>,
+    phantom: std::marker::PhantomData<V>,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>> TableManager<K, V>
+    for BloomLayer<K, V, TM>
+{
+    fn new(p: &Path, options: &Options) -> Result<Self> {
+        Ok(BloomLayer {
+            tm: TM::new(p, options)?,
+            filters: RwLock::new(HashMap::new()),
+            phantom: std::marker::PhantomData,
+        })
+    }
+
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()> {
+        self.tm.install_table(path, memtable)
+    }
+
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>> {
+        let may_contain =
+            |path: &Path, key: &K| Ok(may_contain(path, key)? && self.check(path, key, seq)?);
+        self.tm.read_at_filtered(key, seq, &may_contain)
+    }
+
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        let may_contain =
+            |path: &Path, key: &K| Ok(may_contain(path, key)? && self.check(path, key, seq)?);
+        self.tm.multi_read_at_filtered(keys, seq, &may_contain)
+    }
+
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>> {
+        self.tm.scan(start, end)
+    }
+
+    fn options(&self) -> &Options {
+        self.tm.options()
+    }
+
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool {
+        TM::should_flush(options, wal, memtable_size)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        self.tm.last_sequence()
+    }
+
+    fn new_table_path(&mut self, level: u32) -> PathBuf {
+        self.tm.new_table_path(level)
+    }
+
+    fn pick_compaction(&mut self) -> Result<Option<CompactionJob<K>>> {
+        self.tm.pick_compaction()
+    }
+
+    fn finish_compaction(
+        &mut self,
+        job: CompactionJob<K>,
+        result: Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)>,
+    ) -> Result<()> {
+        let inputs = job.inputs.clone();
+        self.tm.finish_compaction(job, result)?;
+
+        // forget the filters of tables compaction removed
+        self.filters
+            .get_mut()
+            .unwrap()
+            .retain(|path, _| !inputs.contains(path));
+
+        Ok(())
+    }
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>> BloomLayer<K, V, TM> {
+    /// Bloom filter of the sstable at `path`, read from its filter block the
+    /// first time it is needed.
+    pub fn filter(&self, path: &Path) -> Result<Arc<Bloom<InternalKey<K>>>> {
+        if let Some(filter) = self.filters.read().unwrap().get(path) {
+            return Ok(Arc::clone(filter));
+        }
+
+        let filter = Arc::new(Table::<InternalKey<K>, V>::open(path)?.read_filter()?);
+        self.filters
+            .write()
+            .unwrap()
+            .insert(path.to_path_buf(), Arc::clone(&filter));
+        Ok(filter)
+    }
+
+    /// Whether the sstable at `path` may hold a version of `key`.
+    fn check(&self, path: &Path, key: &K, seq: u64) -> Result<bool> {
+        // filters hash only the user key
+        Ok(self
+            .filter(path)?
+            .check(&InternalKey::new(key.clone(), seq)))
+    }
+}
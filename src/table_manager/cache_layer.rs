@@ -0,0 +1,124 @@
+use crate::cache::ShardedCache;
+use crate::compaction::{CompactionJob, CompactionStats};
+use crate::error::Result;
+use crate::internal_key::{InternalKey, Memtable, MAX_SEQ};
+use crate::log::Log;
+use crate::lsm_forest::LogSerial;
+use crate::merge_iterator::MergeIterator;
+use crate::options::Options;
+use crate::sstable::TableMeta;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use crate::table_manager::{TableFilter, TableManager};
+
+/// Keeps the newest value of recently read keys in front of `tm`.
+pub struct CacheLayer<K: LogSerial, V: LogSerial, TM: TableManager<K, V>> {
+    pub(crate) tm: TM,
+    pub(crate) cache: ShardedCache<K, Option<V>>,
+}
+
+impl<K: LogSerial, V: LogSerial, TM: TableManager<K, V>> TableManager<K, V>
+    for CacheLayer<K, V, TM>
+{
+    fn new(p: &Path, options: &Options) -> Result<Self> {
+        Ok(CacheLayer {
+            tm: TM::new(p, options)?,
+            cache: ShardedCache::new(options.cache_capacity),
+        })
+    }
+
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()> {
+        // oldest version first, so the newest one is left in the cache
+        for (key, value) in memtable.iter().rev() {
+            if self.cache.contains(&key.key) {
+                self.cache.put(key.key.clone(), value.clone());
+            }
+        }
+
+        self.tm.install_table(path, memtable)
+    }
+
+    // filters only skip tables that cannot hold the key, so what is found
+    // through them is the same value and can be cached
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>> {
+        // the cache only holds the newest version
+        if seq != MAX_SEQ {
+            return self.tm.read_at_filtered(key, seq, may_contain);
+        }
+
+        match self.cache.get(key) {
+            Some(value) => Ok(value),
+            None => {
+                let value = self.tm.read_at_filtered(key, seq, may_contain)?;
+                self.cache.put(key.clone(), value.clone());
+                Ok(value)
+            }
+        }
+    }
+
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        if seq != MAX_SEQ {
+            return self.tm.multi_read_at_filtered(keys, seq, may_contain);
+        }
+
+        let mut values: Vec<Option<Option<V>>> =
+            keys.iter().map(|key| self.cache.get(key)).collect();
+        let (misses, missing): (Vec<usize>, Vec<K>) = keys
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| values[*i].is_none())
+            .map(|(i, key)| (i, key.clone()))
+            .unzip();
+        let found = self.tm.multi_read_at_filtered(&missing, seq, may_contain)?;
+        for (i, value) in misses.into_iter().zip(found) {
+            self.cache.put(keys[i].clone(), value.clone());
+            values[i] = Some(value);
+        }
+
+        Ok(values.into_iter().map(Option::flatten).collect())
+    }
+
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>> {
+        self.tm.scan(start, end)
+    }
+
+    fn options(&self) -> &Options {
+        self.tm.options()
+    }
+
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool {
+        TM::should_flush(options, wal, memtable_size)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        self.tm.last_sequence()
+    }
+
+    fn new_table_path(&mut self, level: u32) -> PathBuf {
+        self.tm.new_table_path(level)
+    }
+
+    // compaction only drops versions no read can see, so cached values stay
+    fn pick_compaction(&mut self) -> Result<Option<CompactionJob<K>>> {
+        self.tm.pick_compaction()
+    }
+
+    fn finish_compaction(
+        &mut self,
+        job: CompactionJob<K>,
+        result: Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)>,
+    ) -> Result<()> {
+        self.tm.finish_compaction(job, result)
+    }
+}
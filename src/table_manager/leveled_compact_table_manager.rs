@@ -0,0 +1,363 @@
+use crate::compaction::{compact_all, install_compaction, CompactionJob, CompactionStats};
+use crate::error::Result;
+use crate::internal_key::{internal_range, InternalKey, Memtable, MAX_SEQ};
+use crate::log::*;
+use crate::lsm_forest::LogSerial;
+use crate::merge_iterator::{EntryIterator, MergeIterator};
+use crate::options::Options;
+use crate::sstable::TableMeta;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use crate::table_manager::simple_table_manager::*;
+use crate::table_manager::{TableFilter, TableManager};
+
+/// Level 0 holds flushed memtables whose key ranges may overlap. Every level
+/// after it is partitioned by key range, so a key lives in at most one table
+/// per level, and may hold `size_ratio` times more bytes than the one above.
+pub struct LeveledCompactTableManager<K: LogSerial, V: LogSerial> {
+    // level 0
+    pub(crate) tm: SimpleTableManager<K, V>,
+    // levels[0] is level 1, each sorted by first key and non-overlapping
+    // user key ranges
+    pub(crate) levels: Vec<Vec<TableMeta<InternalKey<K>>>>,
+    // last key compacted out of each level, compaction picks the table after it
+    pub(crate) compact_pointers: Vec<Option<InternalKey<K>>>,
+    pub(crate) level0_threshold: usize,
+    pub(crate) level1_max_bytes: u64,
+    pub(crate) size_ratio: u64,
+    pub(crate) target_file_size: u64,
+    // totals over every compaction since open
+    pub(crate) compaction_stats: CompactionStats,
+    // inputs of running compactions
+    pub(crate) compacting: HashSet<PathBuf>,
+}
+
+impl<K: LogSerial, V: LogSerial> TableManager<K, V> for LeveledCompactTableManager<K, V> {
+    fn new(p: &Path, options: &Options) -> Result<Self> {
+        let tm = SimpleTableManager::<K, V>::new(p, options)?;
+        let num_levels = options.num_levels;
+
+        let mut levels = Vec::new();
+        for level in 1..=num_levels {
+            let mut tables: Vec<TableMeta<InternalKey<K>>> = Vec::new();
+            for path in tm.manifest.level(level) {
+                if let Some(meta) = TableMeta::load::<V>(&path)? {
+                    tables.push(meta);
+                }
+            }
+            tables.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+            levels.push(tables);
+        }
+
+        Ok(LeveledCompactTableManager::<K, V> {
+            tm,
+            levels,
+            compact_pointers: vec![None; num_levels as usize],
+            level0_threshold: options.level0_threshold,
+            level1_max_bytes: options.level1_max_bytes,
+            size_ratio: options.size_ratio,
+            target_file_size: options.level_target_file_size,
+            compaction_stats: CompactionStats::default(),
+            compacting: HashSet::new(),
+        })
+    }
+
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()> {
+        self.tm.install_table(path, memtable)
+    }
+
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>> {
+        for path in self.tm.sstables.iter().rev() {
+            if !may_contain(path, key)? {
+                continue;
+            }
+            if let Some(value) = self.tm.read_table(path, key, seq)? {
+                return Ok(value);
+            }
+        }
+
+        // every version of a key in a level is in the same table
+        let lookup = InternalKey::new(key.clone(), seq);
+        for tables in self.levels.iter() {
+            let index = tables.partition_point(|table| table.last_key < lookup);
+            if let Some(table) = tables.get(index) {
+                if table.first_key.key <= *key && may_contain(&table.path, key)? {
+                    if let Some(value) = self.tm.read_table(&table.path, key, seq)? {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        let mut values =
+            self.tm
+                .read_tables_many(self.tm.sstables.iter().rev(), keys, seq, may_contain)?;
+
+        // the keys still missing are grouped by the one table of each level
+        // that can hold them
+        for tables in self.levels.iter() {
+            let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+            for (i, key) in keys.iter().enumerate() {
+                if values[i].is_some() {
+                    continue;
+                }
+                let lookup = InternalKey::new(key.clone(), seq);
+                let index = tables.partition_point(|table| table.last_key < lookup);
+                let Some(table) = tables.get(index) else {
+                    continue;
+                };
+                if table.first_key.key > *key || !may_contain(&table.path, key)? {
+                    continue;
+                }
+                match groups.last_mut() {
+                    Some((last, group)) if *last == index => group.push(i),
+                    _ => groups.push((index, vec![i])),
+                }
+            }
+
+            for (index, group) in groups {
+                let lookups: Vec<K> = group.iter().map(|&i| keys[i].clone()).collect();
+                let found = self
+                    .tm
+                    .read_table_many(&tables[index].path, &lookups, seq)?;
+                for (i, value) in group.into_iter().zip(found) {
+                    values[i] = value;
+                }
+            }
+        }
+
+        Ok(values.into_iter().map(Option::flatten).collect())
+    }
+
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>> {
+        let (start, end) = internal_range(&start, &end);
+        let mut sources = self.tm.table_iters(&self.tm.sstables, &start, &end)?;
+
+        // tables in a level do not overlap, so each level is one sorted source
+        for tables in self.levels.iter() {
+            let mut level = Vec::new();
+            for table in tables.iter().filter(|table| in_range(table, &start, &end)) {
+                level.push(self.tm.open_table(&table.path)?);
+            }
+
+            let (start, end) = (start.clone(), end.clone());
+            let source: EntryIterator<InternalKey<K>, V> = Box::new(
+                level
+                    .into_iter()
+                    .flat_map(move |table| table.iter_shared(start.clone(), end.clone())),
+            );
+            sources.push(source);
+        }
+
+        Ok(MergeIterator::new(sources))
+    }
+
+    fn options(&self) -> &Options {
+        self.tm.options()
+    }
+
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool {
+        SimpleTableManager::<K, V>::should_flush(options, wal, memtable_size)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        self.tm.last_sequence()
+    }
+
+    fn new_table_path(&mut self, level: u32) -> PathBuf {
+        self.tm.new_table_path(level)
+    }
+
+    fn pick_compaction(&mut self) -> Result<Option<CompactionJob<K>>> {
+        let mut levels = Vec::new();
+        if self.tm.sstables.len() >= self.level0_threshold {
+            levels.push(0);
+        }
+        // the last level has nowhere to compact into
+        levels.extend(
+            (1..self.levels.len()).filter(|&level| self.level_bytes(level) > self.max_bytes(level)),
+        );
+
+        for level in levels {
+            if let Some(job) = self.plan(level)? {
+                self.compacting.extend(job.inputs.iter().cloned());
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    fn finish_compaction(
+        &mut self,
+        job: CompactionJob<K>,
+        result: Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)>,
+    ) -> Result<()> {
+        self.compacting.retain(|path| !job.inputs.contains(path));
+        let (outputs, stats) = result?;
+
+        install_compaction(
+            &mut self.tm.manifest,
+            job.output_level,
+            &outputs,
+            &job.inputs,
+        )?;
+        self.compaction_stats.add(&stats);
+        self.tm.evict_tables(&job.inputs);
+
+        let level = job.level;
+        if level == 0 {
+            self.tm.sstables.retain(|path| !job.inputs.contains(path));
+        } else {
+            self.levels[level - 1].retain(|table| !job.inputs.contains(&table.path));
+            self.compact_pointers[level - 1] = job.last_key;
+        }
+        let next = &mut self.levels[level];
+        next.retain(|table| !job.inputs.contains(&table.path));
+        next.extend(outputs);
+        next.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+
+        Ok(())
+    }
+}
+
+fn in_range<K: LogSerial>(
+    table: &TableMeta<InternalKey<K>>,
+    start: &Bound<InternalKey<K>>,
+    end: &Bound<InternalKey<K>>,
+) -> bool {
+    let after_start = match start {
+        Bound::Included(start) | Bound::Excluded(start) => table.last_key >= *start,
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) | Bound::Excluded(end) => table.first_key <= *end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Whether `table` holds any version of the user keys from `first_key` to `last_key`.
+fn overlaps_keys<K: LogSerial>(
+    table: &TableMeta<InternalKey<K>>,
+    first_key: &InternalKey<K>,
+    last_key: &InternalKey<K>,
+) -> bool {
+    table.overlaps(
+        &InternalKey::new(first_key.key.clone(), MAX_SEQ),
+        &InternalKey::new(last_key.key.clone(), 0),
+    )
+}
+
+impl<K: LogSerial, V: LogSerial> LeveledCompactTableManager<K, V> {
+    /// Maximum number of bytes level `level` (1 based) may hold.
+    pub fn max_bytes(&self, level: usize) -> u64 {
+        self.level1_max_bytes * self.size_ratio.pow(level as u32 - 1)
+    }
+
+    pub fn level_bytes(&self, level: usize) -> u64 {
+        self.levels[level - 1].iter().map(|table| table.size).sum()
+    }
+
+    /// Runs every compaction that is due, keeping every version a snapshot
+    /// at or after `smallest_snapshot` reads, see `SnapshotList::smallest`.
+    pub fn compact(&mut self, smallest_snapshot: u64) -> Result<()> {
+        compact_all::<K, V, Self>(self, smallest_snapshot)
+    }
+
+    /// Compaction of tables of `level` with the tables they overlap in the
+    /// level below, or `None` if one of them is already being compacted.
+    fn plan(&self, level: usize) -> Result<Option<CompactionJob<K>>> {
+        let is_free = |path: &PathBuf| !self.compacting.contains(path);
+
+        // upper inputs are newest first
+        let upper: Vec<PathBuf> = if level == 0 {
+            // level 0 tables overlap, so they are compacted together or not at all
+            if !self.tm.sstables.iter().all(is_free) {
+                return Ok(None);
+            }
+            self.tm.sstables.iter().rev().cloned().collect()
+        } else {
+            let tables = &self.levels[level - 1];
+            let index = match &self.compact_pointers[level - 1] {
+                Some(pointer) => tables.partition_point(|table| table.last_key <= *pointer),
+                None => 0,
+            };
+            let table = tables[index..]
+                .iter()
+                .chain(tables[..index].iter())
+                .find(|table| is_free(&table.path));
+            let Some(table) = table else {
+                return Ok(None);
+            };
+            vec![table.path.clone()]
+        };
+
+        let mut upper_metas = Vec::new();
+        for path in upper.iter() {
+            upper_metas.extend(TableMeta::load::<V>(path)?);
+        }
+        let first_key = upper_metas
+            .iter()
+            .map(|meta| &meta.first_key)
+            .min()
+            .cloned();
+        let last_key = upper_metas.iter().map(|meta| &meta.last_key).max().cloned();
+
+        let lower: Vec<TableMeta<InternalKey<K>>> = match (&first_key, &last_key) {
+            (Some(first_key), Some(last_key)) => self.levels[level]
+                .iter()
+                .filter(|table| overlaps_keys(table, first_key, last_key))
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        if !lower.iter().all(|table| is_free(&table.path)) {
+            return Ok(None);
+        }
+
+        // newest first
+        let inputs: Vec<PathBuf> = upper
+            .iter()
+            .cloned()
+            .chain(lower.iter().map(|table| table.path.clone()))
+            .collect();
+
+        // tombstones can only be dropped if no deeper level holds these keys
+        let bottommost = lower
+            .iter()
+            .map(|table| (&table.first_key, &table.last_key))
+            .chain(first_key.iter().zip(last_key.iter()))
+            .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)))
+            .is_none_or(|(first_key, last_key)| {
+                self.levels[level + 1..]
+                    .iter()
+                    .flatten()
+                    .all(|table| !overlaps_keys(table, first_key, last_key))
+            });
+
+        Ok(Some(CompactionJob {
+            inputs,
+            level,
+            output_level: level as u32 + 1,
+            bottommost,
+            target_file_size: self.target_file_size,
+            last_key,
+        }))
+    }
+}
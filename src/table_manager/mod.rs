@@ -1,24 +1,115 @@
+use crate::compaction::{CompactionJob, CompactionStats};
+use crate::error::Result;
+use crate::internal_key::{InternalKey, Memtable, MAX_SEQ};
 use crate::lsm_forest::LogSerial;
+use crate::merge_iterator::MergeIterator;
+use crate::options::Options;
+use crate::sstable::{write_memtable, TableMeta};
 use crate::{log::*, lsm_forest::LSMTree};
-use anyhow::Result;
 use bincode::{Decode, Encode};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
+use std::ops::Bound;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
 pub mod simple_table_manager;
 pub mod simple_compact_table_manager;
-pub mod simple_bloom_table_manager;
-pub mod simple_cache_table_manager;
 pub mod tiered_compact_table_manager;
-pub mod bcat_table_manager;
+pub mod leveled_compact_table_manager;
+pub mod bloom_layer;
+pub mod cache_layer;
+pub use bloom_layer::BloomLayer;
+pub use cache_layer::CacheLayer;
+pub use leveled_compact_table_manager::LeveledCompactTableManager;
+pub use simple_compact_table_manager::SimpleCompactTableManager;
+pub use simple_table_manager::SimpleTableManager;
+pub use tiered_compact_table_manager::TieredCompactTableManager;
 
-pub trait TableManager<K: LogSerial, V: LogSerial> {
-    fn new(p: &Path) -> Self;
-    fn add_table(&mut self, memtable: BTreeMap<K, Option<V>>) -> Result<()>;
-    fn read(&mut self, key: &K) -> Option<V>;
-    fn should_flush(&self, wal: &Log, memtable: &BTreeMap<K, Option<V>>) -> bool;
+pub type SimpleBloomTableManager<K, V> = BloomLayer<K, V, SimpleTableManager<K, V>>;
+pub type SimpleCacheTableManager<K, V> = CacheLayer<K, V, SimpleTableManager<K, V>>;
+/// Bloom filters and a cache over tiered compaction.
+pub type BCATTableManager<K, V> =
+    CacheLayer<K, V, BloomLayer<K, V, TieredCompactTableManager<K, V>>>;
+pub type LeveledBloomCacheTableManager<K, V> =
+    CacheLayer<K, V, BloomLayer<K, V, LeveledCompactTableManager<K, V>>>;
+
+/// Tells whether the sstable at a path may hold a key, so a lookup can skip
+/// it. Layers such as `BloomLayer` pass one down to the manager they wrap.
+pub type TableFilter<'a, K> = dyn Fn(&Path, &K) -> Result<bool> + 'a;
+
+/// Reads go through `&self`, so any number of threads can read at once.
+/// Flushes and compactions write their tables without the table manager and
+/// only take it for themselves to install them.
+pub trait TableManager<K: LogSerial, V: LogSerial>: Send + Sync + 'static {
+    fn new(p: &Path, options: &Options) -> Result<Self>
+    where
+        Self: Sized;
+    fn options(&self) -> &Options;
+    /// Writes `memtable` to a new level 0 sstable and adds it to the tables
+    /// read.
+    fn add_table(&mut self, memtable: Memtable<K, V>) -> Result<()> {
+        let path = self.new_table_path(0);
+        write_memtable(&path, &memtable, self.options())?;
+        self.install_table(path, memtable)
+    }
+    /// Adds the sstable at `path`, written from `memtable` at a path from
+    /// `new_table_path(0)`, to the tables read. Flushes write the table
+    /// without the table manager locked and only lock it for this.
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()>;
+    /// `read_at` that only looks in the tables `may_contain` allows.
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>>;
+    /// Newest value of `key` written at or before sequence number `seq`.
+    fn read_at(&self, key: &K, seq: u64) -> Result<Option<V>> {
+        self.read_at_filtered(key, seq, &|_, _| Ok(true))
+    }
+    fn read(&self, key: &K) -> Result<Option<V>> {
+        self.read_at(key, MAX_SEQ)
+    }
+    /// `multi_read_at` that only looks in the tables `may_contain` allows.
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        keys.iter()
+            .map(|key| self.read_at_filtered(key, seq, may_contain))
+            .collect()
+    }
+    /// `read_at` for each of `keys`, which are sorted and distinct.
+    fn multi_read_at(&self, keys: &[K], seq: u64) -> Result<Vec<Option<V>>> {
+        self.multi_read_at_filtered(keys, seq, &|_, _| Ok(true))
+    }
+    /// Merged iterator over every version of the keys between two bounds.
+    /// Tombstones are kept so the caller can shadow older data.
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>>;
+    // called by writers without locking the table manager, so it cannot
+    // depend on its state. `memtable_size` is the encoded size of the memtable
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool;
+    /// Largest sequence number written to any table.
+    fn last_sequence(&self) -> u64;
+    /// Reserves a path for a new sstable at manifest level `level`.
+    fn new_table_path(&mut self, level: u32) -> PathBuf;
+    /// Next compaction to run, if any. Its inputs are not picked again until
+    /// it is finished.
+    fn pick_compaction(&mut self) -> Result<Option<CompactionJob<K>>> {
+        Ok(None)
+    }
+    /// Installs the outputs of a picked compaction in place of its inputs,
+    /// or only releases the inputs if it failed.
+    fn finish_compaction(
+        &mut self,
+        _job: CompactionJob<K>,
+        result: Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)>,
+    ) -> Result<()> {
+        result.map(|_| ())
+    }
 }
 
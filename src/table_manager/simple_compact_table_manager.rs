@@ -1,71 +1,182 @@
+use crate::compaction::{compact_tables, install_compaction, CompactionJob, CompactionStats};
+use crate::error::{Error, Result};
+use crate::internal_key::{internal_range, InternalKey, Memtable};
 use crate::lsm_forest::LogSerial;
+use crate::merge_iterator::MergeIterator;
+use crate::options::Options;
+use crate::sstable::TableMeta;
 use crate::{log::*, lsm_forest::LSMTree};
-use anyhow::Result;
 use bincode::{Decode, Encode};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
+use std::ops::Bound;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
 use crate::table_manager::simple_table_manager::*;
-use crate::table_manager::TableManager;
+use crate::table_manager::{TableFilter, TableManager};
 
 pub struct SimpleCompactTableManager<K: LogSerial, V: LogSerial> {
-    pub tm: SimpleTableManager<K, V>,
-    pub compact_threshold: usize,
+    pub(crate) tm: SimpleTableManager<K, V>,
+    // output of the last compaction, one sorted run of non-overlapping tables
+    pub(crate) compacted: Vec<PathBuf>,
+    pub(crate) compact_threshold: usize,
+    pub(crate) target_file_size: u64,
+    // totals over every compaction since open
+    pub(crate) compaction_stats: CompactionStats,
+    // every table is an input, so only one compaction runs at a time
+    pub(crate) compacting: bool,
 }
 
 impl<K: LogSerial, V: LogSerial> TableManager<K, V> for SimpleCompactTableManager<K, V> {
-    fn new(p: &Path) -> Self {
-        SimpleCompactTableManager::<K, V> {
-            tm: SimpleTableManager::<K, V>::new(p),
-            compact_threshold: 10, // DEFAULT of 10
-        }
+    fn new(p: &Path, options: &Options) -> Result<Self> {
+        let tm = SimpleTableManager::<K, V>::new(p, options)?;
+        let compacted = tm.manifest.level(1);
+
+        Ok(SimpleCompactTableManager::<K, V> {
+            tm,
+            compacted,
+            compact_threshold: options.compact_threshold,
+            target_file_size: options.target_file_size,
+            compaction_stats: CompactionStats::default(),
+            compacting: false,
+        })
     }
 
-    fn add_table(&mut self, memtable: BTreeMap<K, Option<V>>) -> Result<()> {
-        self.tm.add_table(memtable)?;
-        if self.tm.sstables.len() >= self.compact_threshold {
-            self.compact()?;
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()> {
+        self.tm.install_table(path, memtable)
+    }
+
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>> {
+        for path in self.tm.sstables.iter().rev().chain(self.compacted.iter()) {
+            if !may_contain(path, key)? {
+                continue;
+            }
+            if let Some(value) = self.tm.read_table(path, key, seq)? {
+                return Ok(value);
+            }
         }
+        Ok(None)
+    }
 
-        Ok(())
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        let tables = self.tm.sstables.iter().rev().chain(self.compacted.iter());
+        let values = self.tm.read_tables_many(tables, keys, seq, may_contain)?;
+        Ok(values.into_iter().map(Option::flatten).collect())
     }
 
-    fn read(&mut self, key: &K) -> Option<V> {
-        self.tm.read(key)
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>> {
+        let (start, end) = internal_range(&start, &end);
+
+        let mut sources = self.tm.table_iters(&self.tm.sstables, &start, &end)?;
+        sources.append(&mut self.tm.table_iters(&self.compacted, &start, &end)?);
+
+        Ok(MergeIterator::new(sources))
     }
 
-    fn should_flush(&self, wal: &Log, memtable: &BTreeMap<K, Option<V>>) -> bool {
-        self.tm.should_flush(wal, memtable)
+    fn options(&self) -> &Options {
+        self.tm.options()
     }
-}
 
-impl<K: LogSerial, V: LogSerial> SimpleCompactTableManager<K, V> {
-    fn compact(&mut self) -> Result<()> {
-        self.tm.sstables.sort();
-        let mut compact_table = BTreeMap::new();
-
-        for table in self.tm.sstables.iter() {
-            let f = File::open(table).unwrap();
-            let mut reader = std::io::BufReader::new(&f);
-
-            while let Ok(entry) = bincode::decode_from_reader::<
-                SimpleTableEntry<K, V>,
-                &mut std::io::BufReader<&File>,
-                _,
-            >(&mut reader, bincode::config::standard())
-            {
-                compact_table.insert(entry.key, entry.value);
-            }
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool {
+        SimpleTableManager::<K, V>::should_flush(options, wal, memtable_size)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        self.tm.last_sequence()
+    }
 
-            fs::remove_file(table)?;
+    fn new_table_path(&mut self, level: u32) -> PathBuf {
+        self.tm.new_table_path(level)
+    }
+
+    fn pick_compaction(&mut self) -> Result<Option<CompactionJob<K>>> {
+        if self.compacting || self.tm.sstables.len() < self.compact_threshold {
+            return Ok(None);
         }
+        Ok(Some(self.start_compaction()))
+    }
+
+    fn finish_compaction(
+        &mut self,
+        job: CompactionJob<K>,
+        result: Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)>,
+    ) -> Result<()> {
+        self.compacting = false;
+        let (outputs, stats) = result?;
+
+        install_compaction(
+            &mut self.tm.manifest,
+            job.output_level,
+            &outputs,
+            &job.inputs,
+        )?;
+        self.compaction_stats.add(&stats);
+        self.tm.evict_tables(&job.inputs);
 
-        self.tm.sstables.clear();
-        self.tm.add_table(compact_table)?;
+        // tables flushed while the compaction ran are newer than its output
+        self.tm.sstables.retain(|path| !job.inputs.contains(path));
+        self.compacted = outputs.into_iter().map(|output| output.path).collect();
 
         Ok(())
     }
 }
+
+impl<K: LogSerial, V: LogSerial> SimpleCompactTableManager<K, V> {
+    /// Compacts every table into one sorted run, whether or not it is due,
+    /// keeping every version a snapshot at or after `smallest_snapshot`
+    /// reads, see `SnapshotList::smallest`.
+    pub fn compact(&mut self, smallest_snapshot: u64) -> Result<()> {
+        if self.compacting {
+            return Err(Error::Busy("a compaction is already running".to_string()));
+        }
+
+        let job = self.start_compaction();
+        let options = self.tm.options.clone();
+        let result = compact_tables::<K, V>(
+            &job.inputs,
+            job.target_file_size,
+            job.bottommost,
+            smallest_snapshot,
+            &options,
+            || self.tm.new_table_path(job.output_level),
+        );
+        self.finish_compaction(job, result)
+    }
+
+    fn start_compaction(&mut self) -> CompactionJob<K> {
+        self.tm.sstables.sort();
+
+        // newest first
+        let inputs: Vec<PathBuf> = self
+            .tm
+            .sstables
+            .iter()
+            .rev()
+            .chain(self.compacted.iter())
+            .cloned()
+            .collect();
+
+        self.compacting = true;
+        // every table is an input, so nothing older is left for tombstones to shadow
+        CompactionJob {
+            inputs,
+            level: 0,
+            output_level: 1,
+            bottommost: true,
+            target_file_size: self.target_file_size,
+            last_key: None,
+        }
+    }
+}
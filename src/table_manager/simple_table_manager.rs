@@ -1,20 +1,30 @@
+use crate::error::Result;
+use crate::internal_key::{internal_range, InternalKey, Memtable};
 use crate::lsm_forest::LogSerial;
+use crate::options::Options;
 use crate::{log::*, lsm_forest::LSMTree};
-use anyhow::Result;
 use bincode::{Decode, Encode};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
+use std::ops::Bound;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::table_manager::TableManager;
+use crate::cache::ShardedCache;
+use crate::manifest::{Manifest, VersionEdit};
+use crate::merge_iterator::{EntryIterator, MergeIterator};
+use crate::sstable::Table;
+use crate::table_manager::{TableFilter, TableManager};
 
 pub struct SimpleTableManager<K: LogSerial, V: LogSerial> {
-    // store sstable names with unix timestamp in an array
-    pub sstables: Vec<PathBuf>,
-    pub path: PathBuf,
-    phantom: std::marker::PhantomData<(K, V)>,
+    // live sstables in the order they were written, as listed by the manifest
+    pub(crate) sstables: Vec<PathBuf>,
+    pub(crate) manifest: Manifest,
+    pub(crate) options: Options,
+    // open sstables, shared with the readers still using them after eviction
+    pub(crate) table_cache: ShardedCache<PathBuf, Arc<Table<InternalKey<K>, V>>>,
 }
 
 #[derive(Encode, Decode, Debug)]
@@ -25,77 +35,199 @@ pub struct SimpleTableEntry<K: LogSerial, V: LogSerial> {
 }
 
 impl<K: LogSerial, V: LogSerial> TableManager<K, V> for SimpleTableManager<K, V> {
-    fn new(p: &Path) -> Self {
-        let mut sstables = Vec::new();
-
-        for file in fs::read_dir(p).unwrap() {
-            let file = file.unwrap();
-            let path = file.path();
-
-            match path.extension() {
-                Some(ext) => {
-                    if ext == "sst" {
-                        sstables.push(path);
-                    }
-                }
-                None => {}
+    fn new(p: &Path, options: &Options) -> Result<Self> {
+        options.validate()?;
+        let manifest = Manifest::open(p)?;
+        // table names grow with every table, so this is the order they were written
+        let mut sstables = manifest.level(0);
+        sstables.sort();
+
+        Ok(SimpleTableManager::<K, V> {
+            sstables,
+            manifest,
+            options: options.clone(),
+            table_cache: ShardedCache::new(options.max_open_tables),
+        })
+    }
+
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()> {
+        let last_sequence = memtable.keys().map(|key| key.seq).max().unwrap_or(0);
+
+        let mut edit = VersionEdit::default();
+        edit.add(0, &path);
+        edit.last_sequence = last_sequence;
+        self.manifest.apply(edit)?;
+
+        self.sstables.push(path);
+        Ok(())
+    }
+
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>> {
+        for path in self.sstables.iter().rev() {
+            if !may_contain(path, key)? {
+                continue;
+            }
+            if let Some(value) = self.read_table(path, key, seq)? {
+                return Ok(value);
             }
         }
+        Ok(None)
+    }
 
-        sstables.sort();
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        let values = self.read_tables_many(self.sstables.iter().rev(), keys, seq, may_contain)?;
+        Ok(values.into_iter().map(Option::flatten).collect())
+    }
 
-        SimpleTableManager::<K, V> {
-            sstables,
-            path: p.to_path_buf(),
-            phantom: std::marker::PhantomData,
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>> {
+        let (start, end) = internal_range(&start, &end);
+        let sources = self.table_iters(&self.sstables, &start, &end)?;
+
+        Ok(MergeIterator::new(sources))
+    }
+
+    fn options(&self) -> &Options {
+        &self.options
+    }
+
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool {
+        memtable_size >= options.memtable_max_bytes
+            || wal
+                .file
+                .metadata()
+                .is_ok_and(|metadata| metadata.len() >= options.wal_max_bytes)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        self.manifest.last_sequence
+    }
+
+    fn new_table_path(&mut self, level: u32) -> PathBuf {
+        self.manifest.new_table_path(level)
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> SimpleTableManager<K, V> {
+    /// Open handle of the sstable at `path`, from the table cache if it is there.
+    pub fn open_table(&self, path: &Path) -> Result<Arc<Table<InternalKey<K>, V>>> {
+        let path = path.to_path_buf();
+        if let Some(table) = self.table_cache.get(&path) {
+            return Ok(table);
         }
+        // two readers may both open it, the second one's handle is kept
+        let table = Arc::new(Table::open(&path)?);
+        self.table_cache.put(path, Arc::clone(&table));
+        Ok(table)
     }
 
-    fn add_table(&mut self, memtable: BTreeMap<K, Option<V>>) -> Result<()> {
-        let name = format!("sstable_{:08}.sst", self.sstables.len());
-        let path = self.path.join(&name);
-        self.sstables.push(path.clone());
-        //let mut file = File::create(self.path.join(name))?;
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(path)?;
-
-        // let payload = bincode::encode_to_vec(&entry, bincode::config::standard())?;
-        // self.file.write(&payload)?;
-        // // self.file.flush()?;
-        for (key, value) in memtable {
-            let entry = SimpleTableEntry { key, value };
-            let payload = bincode::encode_to_vec(&entry, bincode::config::standard())?;
-            file.write(&payload)?;
+    /// Closes the cached handles of tables compaction removed.
+    pub fn evict_tables(&self, paths: &[PathBuf]) {
+        for path in paths {
+            self.table_cache.remove(path);
         }
-        file.flush()?;
-        Ok(())
     }
 
-    fn read(&mut self, key: &K) -> Option<V> {
-        self.sstables.sort();
-        for path in self.sstables.iter().rev() {
-            let f = File::open(path).unwrap();
-            let mut reader = std::io::BufReader::new(&f);
-            while let Ok(entry) = bincode::decode_from_reader::<
-                SimpleTableEntry<K, V>,
-                &mut std::io::BufReader<&File>,
-                _,
-            >(&mut reader, bincode::config::standard())
-            {
-                if entry.key == key.clone() {
-                    return entry.value;
-                } else if entry.key > key.clone() {
-                    break;
+    /// Looks up the newest version of `key` at or before `seq` in one
+    /// sstable, returning `Some(None)` for a tombstone.
+    pub fn read_table(&self, path: &Path, key: &K, seq: u64) -> Result<Option<Option<V>>> {
+        let table = self.open_table(path)?;
+        // seeking a key before the table would still read its first block
+        if !table.first_key().is_some_and(|first| first.key <= *key) {
+            return Ok(None);
+        }
+        Ok(table
+            .seek(&InternalKey::new(key.clone(), seq))?
+            .filter(|(found, _)| found.key == *key)
+            .map(|(_, value)| value))
+    }
+
+    /// `read_table` for each of the sorted `keys`, reading the table and each
+    /// of its blocks at most once.
+    pub fn read_table_many(
+        &self,
+        path: &Path,
+        keys: &[K],
+        seq: u64,
+    ) -> Result<Vec<Option<Option<V>>>> {
+        let table = self.open_table(path)?;
+        let skip = match table.first_key() {
+            Some(first) => keys.partition_point(|key| *key < first.key),
+            None => keys.len(),
+        };
+        let lookups: Vec<InternalKey<K>> = keys[skip..]
+            .iter()
+            .map(|key| InternalKey::new(key.clone(), seq))
+            .collect();
+
+        let mut values = vec![None; skip];
+        for (key, found) in keys[skip..].iter().zip(table.seek_many(&lookups)?) {
+            values.push(
+                found
+                    .filter(|(found, _)| found.key == *key)
+                    .map(|(_, value)| value),
+            );
+        }
+        Ok(values)
+    }
+
+    /// Looks up the sorted `keys` in `tables`, newest first, reading each
+    /// table at most once. A key is not looked for in tables older than the
+    /// one it was found in, nor in those `may_contain` rules out.
+    pub fn read_tables_many<'a>(
+        &self,
+        tables: impl IntoIterator<Item = &'a PathBuf>,
+        keys: &[K],
+        seq: u64,
+        may_contain: impl Fn(&Path, &K) -> Result<bool>,
+    ) -> Result<Vec<Option<Option<V>>>> {
+        let mut values: Vec<Option<Option<V>>> = vec![None; keys.len()];
+        for path in tables {
+            let mut pending = Vec::new();
+            for (i, key) in keys.iter().enumerate() {
+                if values[i].is_none() && may_contain(path, key)? {
+                    pending.push(i);
                 }
             }
+            if pending.is_empty() {
+                continue;
+            }
+
+            let lookups: Vec<K> = pending.iter().map(|&i| keys[i].clone()).collect();
+            for (i, value) in pending
+                .into_iter()
+                .zip(self.read_table_many(path, &lookups, seq)?)
+            {
+                values[i] = value;
+            }
         }
-        None
+
+        Ok(values)
     }
 
-    fn should_flush(&self, wal: &Log, memtable: &BTreeMap<K, Option<V>>) -> bool {
-        memtable.len() >= 256 || wal.file.metadata().unwrap().len() >= (4 * 1024)
+    /// Opens an iterator per sstable, newest table first, over the cached
+    /// handles point reads use.
+    pub fn table_iters(
+        &self,
+        tables: &[PathBuf],
+        start: &Bound<InternalKey<K>>,
+        end: &Bound<InternalKey<K>>,
+    ) -> Result<Vec<EntryIterator<InternalKey<K>, V>>> {
+        let mut sources: Vec<EntryIterator<InternalKey<K>, V>> = Vec::new();
+        for path in tables.iter().rev() {
+            let table = self.open_table(path)?;
+            sources.push(Box::new(table.iter_shared(start.clone(), end.clone())));
+        }
+
+        Ok(sources)
     }
 }
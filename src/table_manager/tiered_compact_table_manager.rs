@@ -1,193 +1,202 @@
+use crate::compaction::{install_compaction, CompactionJob, CompactionStats};
+use crate::error::Result;
+use crate::internal_key::{internal_range, InternalKey, Memtable};
 use crate::lsm_forest::LogSerial;
+use crate::merge_iterator::MergeIterator;
+use crate::options::Options;
+use crate::sstable::TableMeta;
 use crate::{log::*, lsm_forest::LSMTree};
-use anyhow::Result;
 use bincode::{Decode, Encode};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
+use std::ops::Bound;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::vec;
 
 use crate::table_manager::simple_table_manager::*;
-use crate::table_manager::TableManager;
+use crate::table_manager::{TableFilter, TableManager};
 
 pub struct TieredCompactTableManager<K: LogSerial, V: LogSerial> {
-    pub tm: SimpleTableManager<K, V>,
-    pub level2: Vec<PathBuf>,
-    pub level3: Option<PathBuf>,
-    pub compact_threshold: usize,
+    pub(crate) tm: SimpleTableManager<K, V>,
+    pub(crate) level2: Vec<PathBuf>,
+    // one sorted run of non-overlapping tables
+    pub(crate) level3: Vec<PathBuf>,
+    pub(crate) compact_threshold: usize,
+    pub(crate) target_file_size: u64,
+    // totals over every compaction since open
+    pub(crate) compaction_stats: CompactionStats,
+    // only one compaction runs at a time
+    pub(crate) compacting: bool,
 }
 
 impl<K: LogSerial, V: LogSerial> TableManager<K, V> for TieredCompactTableManager<K, V> {
-    fn new(p: &Path) -> Self {
-        let mut level2 = Vec::new();
-        let mut level3 = None;
-
-        for file in fs::read_dir(p).unwrap() {
-            let file = file.unwrap();
-            let path = file.path();
-
-            match path.extension() {
-                Some(ext) => {
-                    if ext == "sst2" {
-                        level2.push(path);
-                    } else if ext == "sst3" {
-                        level3 = Some(path);
-                    }
-                }
-                None => {}
-            }
-        }
+    fn new(p: &Path, options: &Options) -> Result<Self> {
+        let tm = SimpleTableManager::<K, V>::new(p, options)?;
+        let mut level2 = tm.manifest.level(1);
         level2.sort();
+        let mut level3 = tm.manifest.level(2);
+        level3.sort();
 
-        TieredCompactTableManager::<K, V> {
-            tm: SimpleTableManager::<K, V>::new(p),
+        Ok(TieredCompactTableManager::<K, V> {
+            tm,
             level2,
             level3,
-            compact_threshold: 5, // DEFAULT of 5
-        }
+            compact_threshold: options.tiered_compact_threshold,
+            target_file_size: options.target_file_size,
+            compaction_stats: CompactionStats::default(),
+            compacting: false,
+        })
+    }
+
+    fn install_table(&mut self, path: PathBuf, memtable: Memtable<K, V>) -> Result<()> {
+        self.tm.install_table(path, memtable)
     }
 
-    fn add_table(&mut self, memtable: BTreeMap<K, Option<V>>) -> Result<()> {
-        self.tm.add_table(memtable)?;
-        if self.tm.sstables.len() >= self.compact_threshold {
-            self.compact()?;
+    fn read_at_filtered(
+        &self,
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<V>> {
+        // newest tier first
+        for files in [&self.tm.sstables, &self.level2, &self.level3] {
+            if let Some(value) = self.search_files(files, key, seq, may_contain)? {
+                return Ok(value);
+            }
         }
-        self.level2.sort();
+        Ok(None)
+    }
 
-        Ok(())
+    fn multi_read_at_filtered(
+        &self,
+        keys: &[K],
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Vec<Option<V>>> {
+        // newest tier first
+        let tables = self
+            .tm
+            .sstables
+            .iter()
+            .rev()
+            .chain(self.level2.iter().rev())
+            .chain(self.level3.iter().rev());
+        let values = self.tm.read_tables_many(tables, keys, seq, may_contain)?;
+        Ok(values.into_iter().map(Option::flatten).collect())
     }
 
-    fn read(&mut self, key: &K) -> Option<V> {
-        let result = self
-            .search_files(self.tm.sstables.clone(), key)
-            .or(self.search_files(self.level2.clone(), key))
-            .or({
-                if let Some(ref level3_path) = self.level3 {
-                    self.search_files(vec![level3_path.clone()], key)
-                } else {
-                    None
-                }
-            });
-
-        match result {
-            Some(value) => value,
-            None => None,
-        }
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<MergeIterator<InternalKey<K>, V>> {
+        let (start, end) = internal_range(&start, &end);
+
+        let mut sources = self.tm.table_iters(&self.tm.sstables, &start, &end)?;
+        sources.append(&mut self.tm.table_iters(&self.level2, &start, &end)?);
+        sources.append(&mut self.tm.table_iters(&self.level3, &start, &end)?);
+
+        Ok(MergeIterator::new(sources))
     }
 
-    fn should_flush(&self, wal: &Log, memtable: &BTreeMap<K, Option<V>>) -> bool {
-        self.tm.should_flush(wal, memtable)
+    fn options(&self) -> &Options {
+        self.tm.options()
     }
-}
 
-impl<K: LogSerial, V: LogSerial> TieredCompactTableManager<K, V> {
-    fn compact(&mut self) -> Result<()> {
-        let mut compact_table = BTreeMap::new();
-        let mut name = format!("sstable_{:08}.sst2", self.level2.len());
-
-        if self.level2.len() >= self.compact_threshold {
-            name = "sstable_00000000.sst3".to_string();
-            match self.level3 {
-                Some(ref level3_path) => {
-                    let f = File::open(&level3_path).unwrap();
-                    let mut reader = std::io::BufReader::new(&f);
-
-                    while let Ok(entry) =
-                        bincode::decode_from_reader::<
-                            SimpleTableEntry<K, V>,
-                            &mut std::io::BufReader<&File>,
-                            _,
-                        >(&mut reader, bincode::config::standard())
-                    {
-                        compact_table.insert(entry.key, entry.value);
-                    }
-
-                    fs::remove_file(&level3_path)?;
-                }
-                None => {}
-            }
+    fn should_flush(options: &Options, wal: &Log, memtable_size: usize) -> bool {
+        SimpleTableManager::<K, V>::should_flush(options, wal, memtable_size)
+    }
 
-            self.level2.sort();
-            for table in self.level2.iter() {
-                let f = File::open(table).unwrap();
-                let mut reader = std::io::BufReader::new(&f);
-
-                while let Ok(entry) = bincode::decode_from_reader::<
-                    SimpleTableEntry<K, V>,
-                    &mut std::io::BufReader<&File>,
-                    _,
-                >(&mut reader, bincode::config::standard())
-                {
-                    compact_table.insert(entry.key, entry.value);
-                }
-
-                fs::remove_file(table)?;
-            }
-            self.level2.clear();
-        }
+    fn last_sequence(&self) -> u64 {
+        self.tm.last_sequence()
+    }
 
-        self.tm.sstables.sort();
-        for table in self.tm.sstables.iter() {
-            let f = File::open(table).unwrap();
-            let mut reader = std::io::BufReader::new(&f);
-
-            while let Ok(entry) = bincode::decode_from_reader::<
-                SimpleTableEntry<K, V>,
-                &mut std::io::BufReader<&File>,
-                _,
-            >(&mut reader, bincode::config::standard())
-            {
-                compact_table.insert(entry.key, entry.value);
-            }
+    fn new_table_path(&mut self, level: u32) -> PathBuf {
+        self.tm.new_table_path(level)
+    }
 
-            fs::remove_file(table)?;
+    fn pick_compaction(&mut self) -> Result<Option<CompactionJob<K>>> {
+        if self.compacting || self.tm.sstables.len() < self.compact_threshold {
+            return Ok(None);
         }
-        self.tm.sstables.clear();
 
-        let path = self.tm.path.join(&name);
-        if name.ends_with("2") {
-            self.level2.push(path.clone());
-            self.level2.sort();
-        } else {
-            self.level3 = Some(path.clone());
+        let to_level3 = self.level2.len() >= self.compact_threshold;
+        let level = if to_level3 { 2 } else { 1 };
+
+        // newest first
+        let mut inputs: Vec<PathBuf> = self.tm.sstables.iter().rev().cloned().collect();
+        if to_level3 {
+            inputs.extend(self.level2.iter().rev().cloned());
+            inputs.extend(self.level3.iter().cloned());
         }
 
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(path)?;
+        self.compacting = true;
+        // compacting into level 3 reads every table, so it is the bottom
+        Ok(Some(CompactionJob {
+            inputs,
+            level: 1,
+            output_level: level,
+            bottommost: to_level3,
+            target_file_size: self.target_file_size,
+            last_key: None,
+        }))
+    }
 
-        for (key, value) in compact_table {
-            let entry = SimpleTableEntry { key, value };
-            let payload = bincode::encode_to_vec(&entry, bincode::config::standard())?;
-            file.write(&payload)?;
+    fn finish_compaction(
+        &mut self,
+        job: CompactionJob<K>,
+        result: Result<(Vec<TableMeta<InternalKey<K>>>, CompactionStats)>,
+    ) -> Result<()> {
+        self.compacting = false;
+        let (outputs, stats) = result?;
+
+        install_compaction(
+            &mut self.tm.manifest,
+            job.output_level,
+            &outputs,
+            &job.inputs,
+        )?;
+        self.compaction_stats.add(&stats);
+        self.tm.evict_tables(&job.inputs);
+
+        let outputs = outputs.into_iter().map(|output| output.path);
+        // tables flushed while the compaction ran stay in level 1
+        self.tm.sstables.retain(|path| !job.inputs.contains(path));
+        if job.bottommost {
+            self.level2.clear();
+            self.level3 = outputs.collect();
+        } else {
+            self.level2.extend(outputs);
+            self.level2.sort();
         }
-        file.flush()?;
 
         Ok(())
     }
+}
+
+impl<K: LogSerial, V: LogSerial> TieredCompactTableManager<K, V> {
+    /// Every table from oldest to newest: level 3, then level 2, then level 1.
+    pub fn tables(&self) -> Vec<PathBuf> {
+        let mut tables = self.level3.clone();
+        tables.extend(self.level2.iter().cloned());
+        tables.extend(self.tm.sstables.iter().cloned());
+        tables
+    }
 
-    fn search_files(&mut self, mut files: Vec<PathBuf>, key: &K) -> Option<Option<V>> {
-        files.sort();
+    /// Searches the tables of one tier, newest first.
+    fn search_files(
+        &self,
+        files: &[PathBuf],
+        key: &K,
+        seq: u64,
+        may_contain: &TableFilter<K>,
+    ) -> Result<Option<Option<V>>> {
         for path in files.iter().rev() {
-            let f = File::open(path).unwrap();
-            let mut reader = std::io::BufReader::new(&f);
-            while let Ok(entry) = bincode::decode_from_reader::<
-                SimpleTableEntry<K, V>,
-                &mut std::io::BufReader<&File>,
-                _,
-            >(&mut reader, bincode::config::standard())
-            {
-                if entry.key == key.clone() {
-                    return Some(entry.value);
-                } else if entry.key > key.clone() {
-                    break;
-                }
+            if !may_contain(path, key)? {
+                continue;
+            }
+            if let Some(value) = self.tm.read_table(path, key, seq)? {
+                return Ok(Some(value));
             }
         }
-        None
+        Ok(None)
     }
 }
@@ -0,0 +1,69 @@
+use crate::column_family::{ColumnFamily, DEFAULT_FAMILY};
+use crate::lsm_forest::LogSerial;
+use crate::memtable::MemTable;
+use crate::table_manager::TableManager;
+
+/// Puts and removes applied to an `LSMTree` together by `LSMTree::write`,
+/// or to the column families of a `DB` together by `DB::write`.
+/// Later operations on the same key win.
+#[derive(Debug, Clone)]
+pub struct WriteBatch<K: LogSerial, V: LogSerial> {
+    // (id of the column family, key, value or `None` to remove it)
+    pub(crate) entries: Vec<(u32, K, Option<V>)>,
+}
+
+impl<K: LogSerial, V: LogSerial> WriteBatch<K, V> {
+    pub fn new() -> WriteBatch<K, V> {
+        WriteBatch {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: K, value: V) -> &mut Self {
+        self.push(DEFAULT_FAMILY, key, Some(value))
+    }
+
+    pub fn remove(&mut self, key: &K) -> &mut Self {
+        self.push(DEFAULT_FAMILY, key.clone(), None)
+    }
+
+    pub fn put_cf<TM: TableManager<K, V>, MT: MemTable<K, V>>(
+        &mut self,
+        family: &ColumnFamily<K, V, TM, MT>,
+        key: K,
+        value: V,
+    ) -> &mut Self {
+        self.push(family.id, key, Some(value))
+    }
+
+    pub fn remove_cf<TM: TableManager<K, V>, MT: MemTable<K, V>>(
+        &mut self,
+        family: &ColumnFamily<K, V, TM, MT>,
+        key: &K,
+    ) -> &mut Self {
+        self.push(family.id, key.clone(), None)
+    }
+
+    fn push(&mut self, family: u32, key: K, value: Option<V>) -> &mut Self {
+        self.entries.push((family, key, value));
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K: LogSerial, V: LogSerial> Default for WriteBatch<K, V> {
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory budget for the memtables of every LSM Tree sharing it, passed to
+/// each of them through `Options::write_buffer_manager`.
+///
+/// Trees report the encoded size of what they insert and flush their
+/// memtable early once the budget is used up.
+#[derive(Debug)]
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    // memtables and immutable memtables not flushed yet
+    memory_usage: AtomicUsize,
+    // memtables still taking writes
+    mutable_usage: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    pub fn new(buffer_size: usize) -> WriteBufferManager {
+        WriteBufferManager {
+            buffer_size,
+            memory_usage: AtomicUsize::new(0),
+            mutable_usage: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::SeqCst)
+    }
+
+    pub fn mutable_usage(&self) -> usize {
+        self.mutable_usage.load(Ordering::SeqCst)
+    }
+
+    /// Counts `bytes` written to a memtable.
+    pub fn reserve(&self, bytes: usize) {
+        self.memory_usage.fetch_add(bytes, Ordering::SeqCst);
+        self.mutable_usage.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Forgets `bytes` a memtable gave back, such as versions it pruned.
+    pub fn release(&self, bytes: usize) {
+        sub(&self.memory_usage, bytes);
+        sub(&self.mutable_usage, bytes);
+    }
+
+    /// A memtable of `bytes` stopped taking writes and waits to be flushed.
+    pub fn schedule_free(&self, bytes: usize) {
+        sub(&self.mutable_usage, bytes);
+    }
+
+    /// An immutable memtable of `bytes` was flushed, or dropped unflushed.
+    pub fn free(&self, bytes: usize) {
+        sub(&self.memory_usage, bytes);
+    }
+
+    /// Whether the tree writing now should flush its memtable.
+    pub fn should_flush(&self) -> bool {
+        // flushing more memtables does not help while most of the budget is
+        // held by ones already waiting to be flushed
+        let mutable = self.mutable_usage();
+        mutable >= self.buffer_size / 8 * 7
+            || (self.memory_usage() >= self.buffer_size && mutable >= self.buffer_size / 2)
+    }
+}
+
+fn sub(counter: &AtomicUsize, bytes: usize) {
+    let _ = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |usage| {
+        Some(usage.saturating_sub(bytes))
+    });
+}